@@ -1,29 +1,106 @@
 //! HTTP API for tickit-sync server
 
 use axum::{
-    Json, Router,
-    extract::State,
-    http::{StatusCode, header},
+    Extension, Json, Router,
+    body::Body,
+    extract::{ConnectInfo, Query, State},
+    http::{HeaderMap, Method, header},
     middleware::{self, Next},
-    response::{IntoResponse, Response},
+    response::{
+        IntoResponse, Response,
+        sse::{Event, KeepAlive, Sse},
+    },
     routing::{get, post},
 };
 use chrono::Utc;
-use std::sync::Arc;
+use http_body_util::{BodyExt, LengthLimitError, Limited};
+use schemars::JsonSchema;
+use serde::Deserialize;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use tokio::sync::broadcast;
+use tokio_stream::{Stream, StreamExt, wrappers::BroadcastStream};
 
-use crate::config::Config;
-use crate::db::Database;
-use crate::models::{SyncRequest, SyncResponse};
+use crate::auth::TokenCache;
+use crate::clock;
+use crate::config::{Config, LimitsConfig, TokenScope};
+use crate::db::{ApplyError, Database, DeviceContact, SyncPoint};
+use crate::error::ApiError;
+use crate::models::{ConflictReason, DevicesResponse, SyncRequest, SyncResponse};
+use crate::openapi;
+use crate::ratelimit::RateLimiter;
+use crate::resources;
+use crate::usage::UsageTally;
+use crate::validation::{self, PayloadError};
 
 /// Application state shared across handlers
 pub struct AppState {
     pub db: Database,
-    pub config: Config,
+    /// Current config, swapped out when the config file is reloaded
+    config: RwLock<Arc<Config>>,
+    /// Recently verified API tokens
+    pub tokens: TokenCache,
+    /// Request rates and authentication failures per client
+    pub limiter: RateLimiter,
+    /// Token uses not yet written to the database
+    pub usage: UsageTally,
+    /// Notifications of committed syncs, for `/api/v1/events` subscribers
+    pub changes: broadcast::Sender<ChangeNotification>,
 }
 
 impl AppState {
     pub fn new(db: Database, config: Config) -> Arc<Self> {
-        Arc::new(Self { db, config })
+        let (changes, _) = broadcast::channel(256);
+        Arc::new(Self {
+            db,
+            config: RwLock::new(Arc::new(config)),
+            tokens: TokenCache::default(),
+            limiter: RateLimiter::default(),
+            usage: UsageTally::default(),
+            changes,
+        })
+    }
+
+    /// Snapshot of the current config
+    pub fn config(&self) -> Arc<Config> {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Replace the config for all subsequent requests
+    pub fn set_config(&self, config: Config) {
+        *self.config.write().unwrap() = Arc::new(config);
+        self.tokens.clear_rejected();
+    }
+}
+
+/// Sent to event subscribers when a device (or the REST API) commits changes
+#[derive(Debug, Clone)]
+pub struct ChangeNotification {
+    pub user_id: String,
+    pub device_id: String,
+    pub cursor: String,
+}
+
+/// Authenticated caller, resolved from the Bearer token by `auth_middleware`
+#[derive(Debug, Clone)]
+pub struct AuthUser {
+    /// Account whose data the request operates on
+    pub user_id: String,
+    /// Name of the token that was presented
+    pub token_name: String,
+    /// What the token may do
+    pub scope: TokenScope,
+    /// Lists the token is limited to (None = all)
+    pub lists: Option<Vec<String>>,
+}
+
+impl AuthUser {
+    /// Whether the token may see records in `list_id`
+    pub fn can_access_list(&self, list_id: &str) -> bool {
+        self.lists
+            .as_ref()
+            .is_none_or(|lists| lists.iter().any(|l| l == list_id))
     }
 }
 
@@ -32,6 +109,11 @@ pub fn create_router(state: Arc<AppState>) -> Router {
     Router::new()
         .route("/health", get(health))
         .route("/api/v1/sync", post(sync))
+        .route("/api/v1/events", get(events))
+        .route("/api/v1/devices", get(devices))
+        .merge(resources::routes(&state))
+        .merge(openapi::routes())
+        .fallback(not_found)
         .layer(middleware::from_fn_with_state(
             state.clone(),
             auth_middleware,
@@ -39,6 +121,11 @@ pub fn create_router(state: Arc<AppState>) -> Router {
         .with_state(state)
 }
 
+/// Response for unknown routes
+async fn not_found() -> ApiError {
+    ApiError::NotFound("No such endpoint".to_string())
+}
+
 /// Health check endpoint (no auth required)
 async fn health() -> impl IntoResponse {
     Json(serde_json::json!({
@@ -51,7 +138,7 @@ async fn health() -> impl IntoResponse {
 /// Auth middleware - validates Bearer token
 async fn auth_middleware(
     State(state): State<Arc<AppState>>,
-    request: axum::http::Request<axum::body::Body>,
+    mut request: axum::http::Request<axum::body::Body>,
     next: Next,
 ) -> Response {
     // Skip auth for health check
@@ -59,6 +146,19 @@ async fn auth_middleware(
         return next.run(request).await;
     }
 
+    // Turn away banned and overly busy clients before doing any work
+    let config = state.config();
+    let ip = match request.extensions().get::<ConnectInfo<SocketAddr>>() {
+        Some(ConnectInfo(addr)) => client_ip(&config, request.headers(), *addr),
+        None => "unknown".to_string(),
+    };
+    if let Err(rejection) = state.limiter.check(&ip, &config.rate_limit) {
+        return ApiError::from(rejection).into_response();
+    }
+    if openapi::is_public(request.uri().path()) {
+        return next.run(request).await;
+    }
+
     // Extract Authorization header
     let auth_header = request
         .headers()
@@ -66,91 +166,1032 @@ async fn auth_middleware(
         .and_then(|h| h.to_str().ok());
 
     let token = match auth_header {
-        Some(h) if h.starts_with("Bearer ") => &h[7..],
+        Some(h) if h.starts_with("Bearer ") => h[7..].to_string(),
         _ => {
-            return (
-                StatusCode::UNAUTHORIZED,
-                Json(serde_json::json!({ "error": "Missing or invalid Authorization header" })),
-            )
+            return ApiError::Unauthorized("Missing or invalid Authorization header".to_string())
                 .into_response();
         }
     };
 
-    // Validate token
-    if !state.config.validate_token(token) {
-        return (
-            StatusCode::UNAUTHORIZED,
-            Json(serde_json::json!({ "error": "Invalid API token" })),
-        )
-            .into_response();
+    // Validate token and resolve the owning user
+    let verified = match state.tokens.get(&token, &config) {
+        Some(t) => Some(t),
+        None if state.tokens.is_rejected(&token) => None,
+        None => {
+            // Argon2 is CPU-heavy; keep it off the async runtime
+            let verified = tokio::task::spawn_blocking({
+                let (token, config) = (token.clone(), config.clone());
+                move || config.validate_token(&token).cloned()
+            })
+            .await
+            .unwrap_or_else(|e| {
+                tracing::error!(error = %e, "Token verification task failed");
+                None
+            });
+            match &verified {
+                Some(t) => state.tokens.insert(&token, t),
+                None => state.tokens.reject(&token),
+            }
+            verified
+        }
+    };
+
+    let user = match verified {
+        Some(t) if t.is_expired() => {
+            return ApiError::Unauthorized("API token has expired".to_string()).into_response();
+        }
+        Some(t) => AuthUser {
+            user_id: t.user_id().to_string(),
+            token_name: t.name.clone(),
+            scope: t.scope,
+            lists: t.lists,
+        },
+        None => {
+            let failures = state.limiter.record_failure(&ip, &config.rate_limit);
+            if failures
+                .banned_until
+                .is_some_and(|until| until > Utc::now())
+            {
+                tracing::warn!(
+                    ip = %ip,
+                    failures = failures.failures,
+                    banned_until = %clock::format(failures.banned_until.unwrap()),
+                    "Banned client after repeated authentication failures"
+                );
+            }
+            if config.rate_limit.persist_bans
+                && let Err(e) = state.db.save_auth_failures(&ip, &failures)
+            {
+                tracing::warn!(error = %e, "Failed to save authentication failures");
+            }
+            return ApiError::Unauthorized("Invalid API token".to_string()).into_response();
+        }
+    };
+
+    if state.limiter.record_success(&ip)
+        && config.rate_limit.persist_bans
+        && let Err(e) = state.db.clear_auth_failures(&ip)
+    {
+        tracing::warn!(error = %e, "Failed to clear authentication failures");
     }
 
+    let required = required_scope(request.method(), request.uri().path());
+    if user.scope < required {
+        return ApiError::forbidden(format!(
+            "Token scope '{}' does not allow this request (needs '{}')",
+            user.scope, required
+        ))
+        .into_response();
+    }
+
+    state
+        .usage
+        .record(&user.token_name, clock::format(Utc::now()));
+
+    request.extensions_mut().insert(user);
     next.run(request).await
 }
 
+/// Read a sync request body, enforcing the configured size limits
+async fn read_sync_request(body: Body, limits: &LimitsConfig) -> Result<SyncRequest, PayloadError> {
+    let bytes = match Limited::new(body, limits.max_body_bytes).collect().await {
+        Ok(collected) => collected.to_bytes(),
+        Err(e) if e.is::<LengthLimitError>() => {
+            return Err(PayloadError::TooLarge(format!(
+                "Request body is larger than {} bytes",
+                limits.max_body_bytes
+            )));
+        }
+        Err(e) => {
+            return Err(PayloadError::Invalid {
+                error: format!("Failed to read request body: {e}"),
+                errors: Vec::new(),
+            });
+        }
+    };
+    validation::parse_sync_request(&bytes, limits)
+}
+
+/// Scope a token needs for a request. Syncs only need read access here;
+/// pushing changes is checked by the sync handler.
+fn required_scope(method: &Method, path: &str) -> TokenScope {
+    if path.starts_with("/api/v1/devices") {
+        TokenScope::Admin
+    } else if method == Method::GET || path == "/api/v1/sync" {
+        TokenScope::Read
+    } else {
+        TokenScope::Write
+    }
+}
+
 /// Main sync endpoint
 async fn sync(
     State(state): State<Arc<AppState>>,
-    Json(request): Json<SyncRequest>,
+    Extension(user): Extension<AuthUser>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    body: Body,
 ) -> Result<Json<SyncResponse>, ApiError> {
+    let config = state.config();
+    let request = read_sync_request(body, &config.limits).await.inspect_err(
+        |e| tracing::warn!(token = %user.token_name, error = ?e, "Rejected sync payload"),
+    )?;
+
     tracing::info!(
+        user = %user.user_id,
+        token = %user.token_name,
         device_id = %request.device_id,
+        cursor = ?request.cursor,
         last_sync = ?request.last_sync,
         changes = request.changes.len(),
         "Sync request received"
     );
 
-    // Apply incoming changes
-    let conflicts = state.db.apply_changes(&request.changes)?;
+    if user.scope < TokenScope::Write && !request.changes.is_empty() {
+        return Err(ApiError::forbidden(
+            "Token is read-only; send syncs without changes",
+        ));
+    }
+
+    // Apply incoming changes (all or nothing)
+    let conflicts = state
+        .db
+        .apply_changes(
+            &user.user_id,
+            &request.changes,
+            &config.sync,
+            user.lists.as_deref(),
+        )
+        .inspect_err(|e| {
+            if !matches!(e, ApplyError::Storage(_)) {
+                tracing::warn!(
+                    device_id = %request.device_id,
+                    token = %user.token_name,
+                    error = %e,
+                    "Sync batch rejected"
+                );
+            }
+        })?;
 
     if !conflicts.is_empty() {
-        tracing::info!(conflicts = ?conflicts, "Sync conflicts detected");
+        let ids: Vec<_> = conflicts.iter().map(|c| c.id.as_str()).collect();
+        tracing::info!(conflicts = ?ids, "Sync conflicts detected");
     }
 
-    // Get changes for the client (since their last sync)
-    let changes = state.db.get_changes_since(request.last_sync.as_deref())?;
+    // Get changes for the client (since their last sync, or the previous page)
+    // Cursors, continuation tokens and `last_sync` were checked and
+    // normalized when the request was parsed
+    let continuation = request
+        .continuation
+        .as_deref()
+        .and_then(decode_continuation);
+    let resuming = continuation.is_some();
+    let cursor = request.cursor.as_deref().and_then(decode_cursor);
+    let mut since = match (continuation, cursor, &request.last_sync) {
+        (Some((after, snapshot)), _, _) => SyncPoint::Sequence {
+            after,
+            until: Some(snapshot),
+        },
+        (None, Some(seq), _) => SyncPoint::Sequence {
+            after: seq,
+            until: None,
+        },
+        (None, None, Some(last_sync)) => SyncPoint::Timestamp(last_sync),
+        (None, None, None) => SyncPoint::Sequence {
+            after: 0,
+            until: None,
+        },
+    };
 
-    let server_time = Utc::now().to_rfc3339();
+    // A device that hasn't synced past purged tombstones may have missed
+    // deletions, so it has to start over from a full sync; so does a device
+    // reset from the CLI. Later pages of a sync were checked on its first.
+    let mut acked = state
+        .db
+        .device_ack(&user.user_id, &request.device_id, since)?;
+    let full_sync = matches!(since, SyncPoint::Sequence { after: 0, .. });
+    let full_resync = !resuming
+        && ((!full_sync && acked < state.db.purge_horizon(&user.user_id)?)
+            || state
+                .db
+                .resync_required(&user.user_id, &request.device_id)?);
+    if full_resync {
+        tracing::warn!(
+            device_id = %request.device_id,
+            "Sending a full resync"
+        );
+        since = SyncPoint::Sequence {
+            after: 0,
+            until: None,
+        };
+        acked = 0;
+    }
+
+    let page_size = request
+        .page_size
+        .map(|size| size.clamp(1, config.sync.max_page_size));
+    let changes =
+        state
+            .db
+            .get_changes_since(&user.user_id, since, page_size, user.lists.as_deref())?;
+
+    // Let other devices know there is something new to pull
+    let rejected = conflicts
+        .iter()
+        .filter(|c| c.reason != ConflictReason::FieldConflict)
+        .count();
+    if request.changes.len() > rejected {
+        // No subscribers is not an error
+        let _ = state.changes.send(ChangeNotification {
+            user_id: user.user_id.clone(),
+            device_id: request.device_id.clone(),
+            cursor: encode_cursor(changes.snapshot),
+        });
+    }
+
+    let server_time = state.db.clock().now();
 
     // Update device sync timestamp
-    state
-        .db
-        .update_device_sync(&request.device_id, &server_time)?;
+    state.db.update_device_sync(
+        &user.user_id,
+        &request.device_id,
+        &server_time,
+        acked,
+        changes.seq,
+        &DeviceContact {
+            token_name: &user.token_name,
+            user_agent: header_str(&headers, header::USER_AGENT.as_str()),
+            client_version: header_str(&headers, "x-client-version"),
+            ip: Some(client_ip(&config, &headers, addr)),
+        },
+    )?;
+    // A reset device has resynced once the last page has been sent
+    if !changes.has_more {
+        state.db.finish_resync(&user.user_id, &request.device_id)?;
+    }
 
     tracing::info!(
         device_id = %request.device_id,
-        outgoing_changes = changes.len(),
+        outgoing_changes = changes.records.len(),
+        has_more = changes.has_more,
         conflicts = conflicts.len(),
         "Sync complete"
     );
 
     Ok(Json(SyncResponse {
         server_time,
-        changes,
-        conflicts,
+        cursor: encode_cursor(changes.seq),
+        has_more: changes.has_more,
+        full_resync,
+        continuation: changes
+            .has_more
+            .then(|| encode_continuation(changes.seq, changes.snapshot)),
+        changes: changes.records,
+        conflicts: conflicts.iter().map(|c| c.id.clone()).collect(),
+        conflict_details: conflicts,
     }))
 }
 
-/// API error type
-#[derive(Debug)]
-pub struct ApiError(anyhow::Error);
+/// Devices registered to the caller's account
+async fn devices(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<AuthUser>,
+) -> Result<Json<DevicesResponse>, ApiError> {
+    let devices = state.db.list_devices(Some(&user.user_id))?;
+    Ok(Json(DevicesResponse { devices }))
+}
 
-impl IntoResponse for ApiError {
-    fn into_response(self) -> Response {
-        tracing::error!(error = %self.0, "API error");
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(serde_json::json!({ "error": self.0.to_string() })),
-        )
-            .into_response()
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct EventsQuery {
+    /// Subscribing device, whose own syncs are not reported back to it
+    pub device_id: Option<String>,
+}
+
+/// Server-Sent Events stream announcing changes committed by other devices
+async fn events(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<AuthUser>,
+    Query(query): Query<EventsQuery>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    tracing::info!(
+        user = %user.user_id,
+        device_id = ?query.device_id,
+        "Event stream opened"
+    );
+
+    let stream = BroadcastStream::new(state.changes.subscribe()).filter_map(move |notification| {
+        match notification {
+            Ok(n) if n.user_id != user.user_id => None,
+            Ok(n) if query.device_id.as_ref() == Some(&n.device_id) => None,
+            Ok(n) => Some(Ok(Event::default().event("changes").data(
+                serde_json::json!({ "device_id": n.device_id, "cursor": n.cursor }).to_string(),
+            ))),
+            // Missed notifications: the client should sync anyway
+            Err(_) => Some(Ok(Event::default().event("changes").data("{}"))),
+        }
+    });
+
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+/// Value of a header, if present and valid text
+fn header_str<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+/// Address the request came from, taken from X-Forwarded-For when the
+/// server sits behind a trusted proxy
+fn client_ip(config: &Config, headers: &HeaderMap, addr: SocketAddr) -> String {
+    if config.server.trust_forwarded_for
+        && let Some(forwarded) = header_str(headers, "x-forwarded-for")
+        && let Some(ip) = forwarded.split(',').next().map(str::trim)
+        && !ip.is_empty()
+    {
+        return ip.to_string();
     }
+    addr.ip().to_string()
 }
 
-impl<E> From<E> for ApiError
-where
-    E: Into<anyhow::Error>,
-{
-    fn from(err: E) -> Self {
-        Self(err.into())
+/// Encode a change sequence number as an opaque sync cursor
+pub fn encode_cursor(seq: i64) -> String {
+    format!("s{seq}")
+}
+
+/// Encode the position of a paginated sync: the last sequence number sent,
+/// and the snapshot the sync is bounded by
+fn encode_continuation(after: i64, snapshot: i64) -> String {
+    format!("p{after}.{snapshot}")
+}
+
+/// Decode a continuation token produced by `encode_continuation`
+pub fn decode_continuation(token: &str) -> Option<(i64, i64)> {
+    let (after, snapshot) = token.strip_prefix('p')?.split_once('.')?;
+    let (after, snapshot) = (after.parse().ok()?, snapshot.parse().ok()?);
+    (0 <= after && after <= snapshot).then_some((after, snapshot))
+}
+
+/// Decode a sync cursor produced by `encode_cursor`
+pub fn decode_cursor(cursor: &str) -> Option<i64> {
+    cursor
+        .strip_prefix('s')?
+        .parse()
+        .ok()
+        .filter(|seq| *seq >= 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TokenConfig;
+    use crate::models::SyncRecord;
+    use axum::http::StatusCode;
+    use serde_json::{Value, json};
+    use std::path::Path;
+
+    fn state() -> Arc<AppState> {
+        let db = Database::open(Path::new(":memory:")).unwrap();
+        AppState::new(db, Config::default())
+    }
+
+    fn user() -> AuthUser {
+        AuthUser {
+            user_id: "alice".to_string(),
+            token_name: "laptop".to_string(),
+            scope: TokenScope::Write,
+            lists: None,
+        }
+    }
+
+    /// A token for the default account, accepted as plain text
+    fn token(name: &str, scope: TokenScope) -> TokenConfig {
+        TokenConfig {
+            name: name.to_string(),
+            token_hash: format!("{name}-secret"),
+            user: None,
+            scope,
+            lists: None,
+            expires_at: None,
+        }
+    }
+
+    fn state_with(tokens: Vec<TokenConfig>) -> Arc<AppState> {
+        let db = Database::open(Path::new(":memory:")).unwrap();
+        AppState::new(
+            db,
+            Config {
+                tokens,
+                ..Config::default()
+            },
+        )
+    }
+
+    /// Send a request through the router, authenticated with a token's secret
+    async fn call(
+        state: &Arc<AppState>,
+        token: &str,
+        method: Method,
+        path: &str,
+        body: Option<Value>,
+    ) -> (StatusCode, Value) {
+        use tower::ServiceExt;
+
+        let mut request = axum::http::Request::builder()
+            .method(method)
+            .uri(path)
+            .header(header::AUTHORIZATION, format!("Bearer {token}-secret"));
+        if body.is_some() {
+            request = request.header(header::CONTENT_TYPE, "application/json");
+        }
+        let mut request = request
+            .body(axum::body::Body::from(
+                body.map(|b| b.to_string()).unwrap_or_default(),
+            ))
+            .unwrap();
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 0))));
+
+        let response = create_router(state.clone()).oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    /// Send a sync, returning the status and JSON body of the response
+    async fn send_raw(state: &Arc<AppState>, request: Value) -> (StatusCode, Value) {
+        let response = sync(
+            State(state.clone()),
+            Extension(user()),
+            ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 0))),
+            HeaderMap::new(),
+            Body::from(request.to_string()),
+        )
+        .await
+        .into_response();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    async fn send(state: &Arc<AppState>, request: Value) -> SyncResponse {
+        let (status, body) = send_raw(state, request).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        serde_json::from_value(body).unwrap()
+    }
+
+    fn task(id: &str) -> Value {
+        json!({
+            "type": "task",
+            "id": id,
+            "title": "Buy milk",
+            "priority": "medium",
+            "completed": false,
+            "list_id": "00000000-0000-4000-8000-000000000000",
+            "created_at": "2024-01-01T00:00:00Z",
+            "updated_at": "2024-01-01T00:00:00Z"
+        })
+    }
+
+    fn deleted(id: &str) -> Value {
+        json!({
+            "type": "deleted",
+            "id": id,
+            "record_type": "task",
+            "deleted_at": "2024-01-02T00:00:00Z"
+        })
+    }
+
+    /// Follow a paginated sync to its end, returning every page
+    async fn all_pages(state: &Arc<AppState>, mut request: Value) -> Vec<SyncResponse> {
+        let mut pages = Vec::new();
+        loop {
+            let page = send(state, request.clone()).await;
+            request["continuation"] = json!(page.continuation);
+            let done = !page.has_more;
+            pages.push(page);
+            if done {
+                return pages;
+            }
+            assert!(pages.len() < 10, "paginated sync never ends");
+        }
+    }
+
+    /// Five tasks, two of them deleted again, with the tombstones purged
+    async fn purged_account(state: &Arc<AppState>) -> (Vec<String>, String) {
+        let ids: Vec<String> = (0..5).map(|_| uuid::Uuid::new_v4().to_string()).collect();
+        let created = send(
+            state,
+            json!({ "device_id": "d1", "changes": ids.iter().map(|id| task(id)).collect::<Vec<_>>() }),
+        )
+        .await;
+        let deleted = send(
+            state,
+            json!({
+                "device_id": "d1",
+                "cursor": created.cursor,
+                "changes": [deleted(&ids[0]), deleted(&ids[1])]
+            }),
+        )
+        .await;
+        // Acknowledge the deletions so they can be purged
+        send(
+            state,
+            json!({ "device_id": "d1", "cursor": deleted.cursor, "changes": [] }),
+        )
+        .await;
+        assert_eq!(state.db.purge_tombstones(None).unwrap(), 2);
+        (ids, created.cursor)
+    }
+
+    fn task_ids(pages: &[SyncResponse]) -> Vec<String> {
+        let mut ids: Vec<String> = pages
+            .iter()
+            .flat_map(|page| &page.changes)
+            .filter_map(|record| match record {
+                SyncRecord::Task(task) => Some(task.id.clone()),
+                _ => None,
+            })
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    #[tokio::test]
+    async fn paginated_full_sync_returns_every_record_once() {
+        let state = state();
+        let mut ids: Vec<String> = (0..5).map(|_| uuid::Uuid::new_v4().to_string()).collect();
+        send(
+            &state,
+            json!({ "device_id": "d1", "changes": ids.iter().map(|id| task(id)).collect::<Vec<_>>() }),
+        )
+        .await;
+        ids.sort();
+
+        let pages = all_pages(
+            &state,
+            json!({ "device_id": "d2", "page_size": 2, "changes": [] }),
+        )
+        .await;
+
+        assert_eq!(pages.len(), 3);
+        assert!(pages[..2].iter().all(|page| page.continuation.is_some()));
+        assert_eq!(task_ids(&pages), ids);
+        // The last page's cursor continues from the end of the snapshot
+        let next = send(
+            &state,
+            json!({ "device_id": "d2", "cursor": pages[2].cursor, "changes": [] }),
+        )
+        .await;
+        assert!(next.changes.is_empty());
+    }
+
+    #[tokio::test]
+    async fn paginated_full_sync_after_a_purge_completes() {
+        let state = state();
+        let (ids, _) = purged_account(&state).await;
+        let mut remaining = ids[2..].to_vec();
+        remaining.sort();
+
+        let pages = all_pages(
+            &state,
+            json!({ "device_id": "d2", "page_size": 2, "changes": [] }),
+        )
+        .await;
+
+        assert_eq!(pages.len(), 2);
+        assert!(pages.iter().all(|page| !page.full_resync));
+        assert_eq!(task_ids(&pages), remaining);
+    }
+
+    #[tokio::test]
+    async fn stale_cursor_pages_through_a_full_resync() {
+        let state = state();
+        let (ids, stale_cursor) = purged_account(&state).await;
+        let mut remaining = ids[2..].to_vec();
+        remaining.sort();
+
+        let pages = all_pages(
+            &state,
+            json!({ "device_id": "d3", "cursor": stale_cursor, "page_size": 2, "changes": [] }),
+        )
+        .await;
+
+        assert!(pages[0].full_resync);
+        assert!(pages[1..].iter().all(|page| !page.full_resync));
+        assert_eq!(task_ids(&pages), remaining);
+    }
+
+    #[tokio::test]
+    async fn malformed_sync_points_are_rejected() {
+        let state = state();
+        for request in [
+            json!({ "device_id": "d1", "continuation": "p9.x", "changes": [] }),
+            json!({ "device_id": "d1", "cursor": "garbage", "changes": [] }),
+            json!({ "device_id": "d1", "last_sync": "garbage", "changes": [] }),
+        ] {
+            let result = sync(
+                State(state.clone()),
+                Extension(user()),
+                ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 0))),
+                HeaderMap::new(),
+                Body::from(request.to_string()),
+            )
+            .await;
+            assert!(
+                matches!(result, Err(ApiError::Validation { .. })),
+                "{request}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn legacy_last_sync_is_compared_as_an_instant() {
+        let state = state();
+        let id = uuid::Uuid::new_v4().to_string();
+        let mut record = task(&id);
+        record["updated_at"] = json!("2024-01-01T12:00:00Z");
+        send(&state, json!({ "device_id": "d1", "changes": [record] })).await;
+
+        // 11:00Z, written with an offset that sorts after the stored value
+        let response = send(
+            &state,
+            json!({ "device_id": "d2", "last_sync": "2024-01-01T13:00:00+02:00", "changes": [] }),
+        )
+        .await;
+        assert_eq!(task_ids(&[response]), vec![id]);
+    }
+
+    #[tokio::test]
+    async fn syncs_with_changes_notify_subscribers() {
+        let state = state();
+        let mut events = state.changes.subscribe();
+
+        let response = send(
+            &state,
+            json!({ "device_id": "d1", "changes": [task(&uuid::Uuid::new_v4().to_string())] }),
+        )
+        .await;
+        let notification = events.try_recv().unwrap();
+        assert_eq!(notification.user_id, "alice");
+        assert_eq!(notification.device_id, "d1");
+        assert_eq!(notification.cursor, response.cursor);
+
+        // Nothing new to pull after a sync that only reads
+        send(&state, json!({ "device_id": "d2", "changes": [] })).await;
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn batches_with_invalid_records_are_rejected_whole() {
+        let state = state();
+        let id = uuid::Uuid::new_v4().to_string();
+        let mut invalid = task(&id);
+        invalid["updated_at"] = json!("yesterday");
+
+        let (status, body) = send_raw(
+            &state,
+            json!({ "device_id": "d1", "changes": [task(&uuid::Uuid::new_v4().to_string()), invalid] }),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["errors"].as_array().unwrap().len(), 1);
+        assert_eq!(body["errors"][0]["index"], 1);
+        assert_eq!(body["errors"][0]["id"], id);
+        let after = send(&state, json!({ "device_id": "d2", "changes": [] })).await;
+        assert!(after.changes.is_empty());
+    }
+
+    #[test]
+    fn cursors_decode_only_when_well_formed() {
+        assert_eq!(decode_cursor(&encode_cursor(42)), Some(42));
+        assert_eq!(decode_cursor("s0"), Some(0));
+        for cursor in ["", "s", "42", "s-1", "sx", "p1.2", "2024-01-01T00:00:00Z"] {
+            assert_eq!(decode_cursor(cursor), None, "{cursor:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn rejected_json_bodies_keep_their_status() {
+        use axum::extract::rejection::JsonRejection;
+        use axum::extract::{FromRequest, Request};
+
+        async fn extract(request: Request) -> ApiError {
+            Json::<Value>::from_request(request, &())
+                .await
+                .map_err(|e: JsonRejection| ApiError::from(e))
+                .unwrap_err()
+        }
+        let json_request = |body: Body| {
+            Request::post("/api/v1/tasks")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(body)
+                .unwrap()
+        };
+
+        // Bodies are limited as `resources::body_limit` does
+        let body = Body::new(Limited::new(Body::from(vec![b' '; 100]), 10));
+        assert!(matches!(
+            extract(json_request(body)).await,
+            ApiError::PayloadTooLarge(_)
+        ));
+        let unlabelled = Request::post("/api/v1/tasks")
+            .body(Body::from("{}"))
+            .unwrap();
+        assert!(matches!(
+            extract(unlabelled).await,
+            ApiError::UnsupportedMediaType(_)
+        ));
+        assert!(matches!(
+            extract(json_request(Body::from("{"))).await,
+            ApiError::Validation { .. }
+        ));
+    }
+
+    #[test]
+    fn continuation_tokens_decode_only_when_well_formed() {
+        assert_eq!(
+            decode_continuation(&encode_continuation(3, 7)),
+            Some((3, 7))
+        );
+        assert_eq!(decode_continuation("p0.0"), Some((0, 0)));
+        for token in [
+            "", "p", "p3", "p3.", "p.7", "s3", "p7.3", "p-1.3", "px.y", "p3.7.1",
+        ] {
+            assert_eq!(decode_continuation(token), None, "{token:?}");
+        }
+    }
+
+    #[test]
+    fn forwarded_addresses_are_trusted_only_when_configured() {
+        let mut config = Config::default();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "203.0.113.9, 10.0.0.1".parse().unwrap());
+        let addr = SocketAddr::from(([10, 0, 0, 1], 4242));
+
+        assert_eq!(client_ip(&config, &headers, addr), "10.0.0.1");
+        config.server.trust_forwarded_for = true;
+        assert_eq!(client_ip(&config, &headers, addr), "203.0.113.9");
+        assert_eq!(client_ip(&config, &HeaderMap::new(), addr), "10.0.0.1");
+    }
+
+    #[tokio::test]
+    async fn reloaded_config_applies_to_the_next_sync() {
+        let state = state();
+        let ids: Vec<String> = (0..3).map(|_| uuid::Uuid::new_v4().to_string()).collect();
+        send(
+            &state,
+            json!({ "device_id": "d1", "changes": ids.iter().map(|id| task(id)).collect::<Vec<_>>() }),
+        )
+        .await;
+
+        let mut config = Config::default();
+        config.sync.max_page_size = 1;
+        state.set_config(config);
+
+        let page = send(
+            &state,
+            json!({ "device_id": "d2", "page_size": 5, "changes": [] }),
+        )
+        .await;
+        assert_eq!(page.changes.len(), 1);
+        assert!(page.has_more);
+    }
+
+    fn list(id: &str) -> Value {
+        json!({
+            "type": "list",
+            "id": id,
+            "name": "Groceries",
+            "created_at": "2024-01-01T00:00:00Z",
+            "updated_at": "2024-01-01T00:00:00Z"
+        })
+    }
+
+    fn task_in(id: &str, list_id: &str) -> Value {
+        let mut task = task(id);
+        task["list_id"] = json!(list_id);
+        task
+    }
+
+    #[tokio::test]
+    async fn expired_tokens_are_rejected() {
+        let mut expired = token("old", TokenScope::Write);
+        expired.expires_at = Some("2000-01-01T00:00:00Z".to_string());
+        let mut current = token("new", TokenScope::Write);
+        current.expires_at = Some("2999-01-01T00:00:00Z".to_string());
+        let state = state_with(vec![expired, current]);
+        let empty = json!({ "device_id": "d1", "changes": [] });
+
+        let (status, body) = call(
+            &state,
+            "old",
+            Method::POST,
+            "/api/v1/sync",
+            Some(empty.clone()),
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["error"], "API token has expired");
+        let (status, _) = call(&state, "new", Method::POST, "/api/v1/sync", Some(empty)).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn scopes_limit_what_a_token_may_do() {
+        let state = state_with(vec![
+            token("reader", TokenScope::Read),
+            token("writer", TokenScope::Write),
+            token("admin", TokenScope::Admin),
+        ]);
+        let id = uuid::Uuid::new_v4().to_string();
+        let push = json!({ "device_id": "d1", "changes": [task(&id)] });
+        let pull = json!({ "device_id": "d1", "changes": [] });
+
+        let (status, _) = call(
+            &state,
+            "reader",
+            Method::POST,
+            "/api/v1/sync",
+            Some(push.clone()),
+        )
+        .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = call(&state, "reader", Method::POST, "/api/v1/sync", Some(pull)).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = call(&state, "writer", Method::POST, "/api/v1/sync", Some(push)).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, _) = call(&state, "writer", Method::GET, "/api/v1/devices", None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, body) = call(&state, "admin", Method::GET, "/api/v1/devices", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["devices"].as_array().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn list_tokens_only_sync_their_lists() {
+        let (shared, private) = (
+            uuid::Uuid::new_v4().to_string(),
+            uuid::Uuid::new_v4().to_string(),
+        );
+        let mut limited = token("limited", TokenScope::Write);
+        limited.lists = Some(vec![shared.clone()]);
+        let state = state_with(vec![token("full", TokenScope::Write), limited]);
+
+        let shared_task = uuid::Uuid::new_v4().to_string();
+        let changes = json!([
+            list(&shared),
+            list(&private),
+            task_in(&shared_task, &shared),
+            task_in(&uuid::Uuid::new_v4().to_string(), &private),
+        ]);
+        let (status, _) = call(
+            &state,
+            "full",
+            Method::POST,
+            "/api/v1/sync",
+            Some(json!({ "device_id": "d1", "changes": changes })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, body) = call(
+            &state,
+            "limited",
+            Method::POST,
+            "/api/v1/sync",
+            Some(json!({ "device_id": "d2", "changes": [] })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let response: SyncResponse = serde_json::from_value(body).unwrap();
+        let mut ids: Vec<&str> = response.changes.iter().map(SyncRecord::id).collect();
+        ids.sort();
+        let mut expected = vec![shared.as_str(), shared_task.as_str()];
+        expected.sort();
+        assert_eq!(ids, expected);
+
+        let (status, body) = call(
+            &state,
+            "limited",
+            Method::POST,
+            "/api/v1/sync",
+            Some(json!({
+                "device_id": "d2",
+                "changes": [task_in(&uuid::Uuid::new_v4().to_string(), &private)]
+            })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body["errors"].as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn device_management_needs_admin_scope() {
+        assert_eq!(
+            required_scope(&Method::GET, "/api/v1/devices"),
+            TokenScope::Admin
+        );
+        assert_eq!(
+            required_scope(&Method::POST, "/api/v1/sync"),
+            TokenScope::Read
+        );
+        assert_eq!(
+            required_scope(&Method::GET, "/api/v1/events"),
+            TokenScope::Read
+        );
+    }
+
+    #[tokio::test]
+    async fn repeated_auth_failures_ban_the_client() {
+        let state = state_with(vec![token("laptop", TokenScope::Write)]);
+        let pull = json!({ "device_id": "d1", "changes": [] });
+        let max_failures = state.config().rate_limit.max_auth_failures;
+
+        for _ in 0..max_failures {
+            let (status, _) = call(
+                &state,
+                "guess",
+                Method::POST,
+                "/api/v1/sync",
+                Some(pull.clone()),
+            )
+            .await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+        }
+
+        // Even the right token is turned away until the ban ends
+        let (status, body) = call(&state, "laptop", Method::POST, "/api/v1/sync", Some(pull)).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(body["error"], "Too many failed authentication attempts");
+    }
+
+    #[tokio::test]
+    async fn oversized_bodies_are_refused() {
+        let mut config = Config::default();
+        config.limits.max_body_bytes = 64;
+        let state = AppState::new(Database::open(Path::new(":memory:")).unwrap(), config);
+        let id = uuid::Uuid::new_v4().to_string();
+
+        let (status, body) =
+            send_raw(&state, json!({ "device_id": "d1", "changes": [task(&id)] })).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(body["error"].as_str().unwrap().contains("64 bytes"));
+    }
+
+    #[tokio::test]
+    async fn failed_tokens_are_verified_again_after_a_reload() {
+        let state = state_with(vec![token("laptop", TokenScope::Write)]);
+        let (status, _) = call(&state, "phone", Method::GET, "/api/v1/tasks", None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert!(state.tokens.is_rejected("phone-secret"));
+
+        state.set_config(Config {
+            tokens: vec![
+                token("laptop", TokenScope::Write),
+                token("phone", TokenScope::Write),
+            ],
+            ..Config::default()
+        });
+        let (status, _) = call(&state, "phone", Method::GET, "/api/v1/tasks", None).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn auth_and_routing_errors_are_typed() {
+        let state = state_with(vec![token("laptop", TokenScope::Write)]);
+
+        let (status, body) = call(&state, "laptop", Method::GET, "/api/v1/nowhere", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["code"], "not_found");
+
+        let (status, body) = call(&state, "guess", Method::GET, "/api/v1/events", None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["code"], "unauthorized");
+    }
+
+    #[tokio::test]
+    async fn reset_device_resyncs_until_the_last_page() {
+        let state = state();
+        let (ids, _) = purged_account(&state).await;
+        state.db.reset_device("d1", None).unwrap();
+
+        let request = json!({ "device_id": "d1", "cursor": "s0", "page_size": 2, "changes": [] });
+        let first = send(&state, request.clone()).await;
+        assert!(first.full_resync);
+        assert!(first.has_more);
+        assert!(state.db.resync_required("alice", "d1").unwrap());
+
+        let mut rest = request;
+        rest["continuation"] = json!(first.continuation);
+        let last = send(&state, rest).await;
+        assert!(!last.has_more);
+        assert!(!state.db.resync_required("alice", "d1").unwrap());
+        assert_eq!(task_ids(&[first, last]).len(), ids.len() - 2);
     }
 }
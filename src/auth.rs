@@ -0,0 +1,173 @@
+//! Cache of verified API tokens
+//!
+//! Argon2 verification is deliberately slow, and a presented token has to be
+//! checked against every configured hash until one matches. Tokens that
+//! verified recently are remembered by a digest of the token (never the token
+//! itself), so repeat requests skip Argon2 entirely. Tokens that failed are
+//! remembered briefly too, so a client retrying a wrong token doesn't cost a
+//! full round of Argon2 each time; they are forgotten when the config changes.
+
+use blake2::{Blake2s256, Digest};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::{Config, TokenConfig};
+
+/// How long a verified token is trusted without verifying it again
+const TTL: Duration = Duration::from_secs(15 * 60);
+
+/// How long a token that failed verification is refused without verifying
+/// it again
+const REJECTED_TTL: Duration = Duration::from_secs(60);
+
+/// Upper bound on cached tokens, so random tokens can't grow the cache
+const MAX_ENTRIES: usize = 1024;
+
+#[derive(Default)]
+pub struct TokenCache {
+    entries: Mutex<HashMap<[u8; 32], CachedToken>>,
+    /// When tokens that matched no configured hash were last tried
+    rejected: Mutex<HashMap<[u8; 32], Instant>>,
+}
+
+struct CachedToken {
+    name: String,
+    /// Configured hash the token was verified against
+    token_hash: String,
+    verified_at: Instant,
+}
+
+impl TokenCache {
+    /// Token entry for a recently verified token, if it is still configured
+    /// unchanged and accepted (revoked or rotated tokens miss the cache)
+    pub fn get(&self, token: &str, config: &Config) -> Option<TokenConfig> {
+        let entries = self.entries.lock().unwrap();
+        let cached = entries.get(&digest(token))?;
+        if cached.verified_at.elapsed() > TTL {
+            return None;
+        }
+
+        config
+            .tokens
+            .iter()
+            .find(|t| t.name == cached.name && t.token_hash == cached.token_hash)
+            .filter(|t| config.accepts(t))
+            .cloned()
+    }
+
+    /// Remember a token that was just verified
+    pub fn insert(&self, token: &str, entry: &TokenConfig) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= MAX_ENTRIES {
+            entries.retain(|_, cached| cached.verified_at.elapsed() <= TTL);
+            if entries.len() >= MAX_ENTRIES {
+                entries.clear();
+            }
+        }
+
+        entries.insert(
+            digest(token),
+            CachedToken {
+                name: entry.name.clone(),
+                token_hash: entry.token_hash.clone(),
+                verified_at: Instant::now(),
+            },
+        );
+    }
+
+    /// Whether a token recently failed verification against the current
+    /// config
+    pub fn is_rejected(&self, token: &str) -> bool {
+        let rejected = self.rejected.lock().unwrap();
+        rejected
+            .get(&digest(token))
+            .is_some_and(|at| at.elapsed() <= REJECTED_TTL)
+    }
+
+    /// Remember a token that just failed verification
+    pub fn reject(&self, token: &str) {
+        let mut rejected = self.rejected.lock().unwrap();
+        if rejected.len() >= MAX_ENTRIES {
+            rejected.retain(|_, at| at.elapsed() <= REJECTED_TTL);
+            if rejected.len() >= MAX_ENTRIES {
+                rejected.clear();
+            }
+        }
+        rejected.insert(digest(token), Instant::now());
+    }
+
+    /// Forget failed tokens, which may be valid under a new config
+    pub fn clear_rejected(&self) {
+        self.rejected.lock().unwrap().clear();
+    }
+}
+
+fn digest(token: &str) -> [u8; 32] {
+    Blake2s256::digest(token.as_bytes()).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TokenScope;
+
+    fn config(token_hash: &str) -> Config {
+        Config {
+            tokens: vec![TokenConfig {
+                name: "laptop".to_string(),
+                token_hash: token_hash.to_string(),
+                user: None,
+                scope: TokenScope::default(),
+                lists: None,
+                expires_at: None,
+            }],
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn verified_tokens_are_remembered() {
+        let cache = TokenCache::default();
+        let config = config("hash");
+        assert!(cache.get("secret", &config).is_none());
+
+        cache.insert("secret", &config.tokens[0]);
+        assert_eq!(cache.get("secret", &config), Some(config.tokens[0].clone()));
+        assert!(cache.get("other", &config).is_none());
+    }
+
+    #[test]
+    fn rotated_or_revoked_tokens_miss_the_cache() {
+        let cache = TokenCache::default();
+        let config = config("hash");
+        cache.insert("secret", &config.tokens[0]);
+
+        assert!(cache.get("secret", &self::config("rotated")).is_none());
+        assert!(cache.get("secret", &Config::default()).is_none());
+    }
+
+    #[test]
+    fn refused_plaintext_tokens_miss_the_cache() {
+        let cache = TokenCache::default();
+        let mut config = config("secret");
+        cache.insert("secret", &config.tokens[0]);
+        assert!(cache.get("secret", &config).is_some());
+
+        config.auth.allow_plaintext_tokens = false;
+        assert!(cache.get("secret", &config).is_none());
+    }
+
+    #[test]
+    fn failed_tokens_are_refused_until_the_config_changes() {
+        let cache = TokenCache::default();
+        assert!(!cache.is_rejected("guess"));
+
+        cache.reject("guess");
+        assert!(cache.is_rejected("guess"));
+        assert!(!cache.is_rejected("other"));
+
+        cache.clear_rejected();
+        assert!(!cache.is_rejected("guess"));
+    }
+}
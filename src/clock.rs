@@ -0,0 +1,76 @@
+//! Hybrid logical clock and timestamp normalization
+//!
+//! Sync timestamps are stored as RFC3339 UTC strings with exactly six
+//! fractional digits, so equal instants always have equal strings. Timestamps
+//! issued by the server are hybrid logical clock values: wall time in
+//! milliseconds, with the last three digits used as a logical counter, so every
+//! issued value is unique and later than any timestamp the server has accepted.
+
+use chrono::{DateTime, Utc};
+use std::cmp::Ordering;
+use std::sync::Mutex;
+
+/// Canonical timestamp format
+const FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.6fZ";
+
+/// Logical counter values per millisecond
+const COUNTER_LIMIT: i64 = 1000;
+
+/// Parse an RFC3339 timestamp with any offset
+pub fn parse(ts: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(ts)
+        .ok()
+        .map(|t| t.with_timezone(&Utc))
+}
+
+/// Rewrite a timestamp in canonical form
+pub fn normalize(ts: &str) -> Option<String> {
+    parse(ts).map(format)
+}
+
+/// Format an instant in canonical form
+pub fn format(t: DateTime<Utc>) -> String {
+    t.format(FORMAT).to_string()
+}
+
+/// Compare two timestamps by the instant they represent
+/// (unparseable values fall back to string order)
+pub fn compare(a: &str, b: &str) -> Ordering {
+    match (parse(a), parse(b)) {
+        (Some(a), Some(b)) => a.cmp(&b),
+        _ => a.cmp(b),
+    }
+}
+
+/// Server hybrid logical clock
+#[derive(Default)]
+pub struct HybridClock {
+    /// Last issued or observed value, in microseconds since the epoch
+    last: Mutex<i64>,
+}
+
+impl HybridClock {
+    /// Issue a timestamp later than every one issued or observed so far
+    pub fn now(&self) -> String {
+        let mut last = self.last.lock().unwrap();
+        let wall = Utc::now().timestamp_millis() * COUNTER_LIMIT;
+
+        // Same or earlier millisecond: bump the logical counter, which rolls
+        // over into the next millisecond when exhausted
+        *last = if wall > *last { wall } else { *last + 1 };
+
+        format_micros(*last)
+    }
+
+    /// Advance the clock past a timestamp accepted from a client
+    pub fn observe(&self, ts: &str) {
+        if let Some(t) = parse(ts) {
+            let mut last = self.last.lock().unwrap();
+            *last = (*last).max(t.timestamp_micros());
+        }
+    }
+}
+
+fn format_micros(micros: i64) -> String {
+    format(DateTime::from_timestamp_micros(micros).unwrap_or_default())
+}
@@ -1,12 +1,16 @@
 //! Configuration for tickit-sync server
 
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, bail};
 use argon2::{
     Argon2,
     password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString, rand_core::OsRng},
 };
+use chrono::Utc;
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
+use subtle::ConstantTimeEq;
+
+use crate::clock;
 
 /// Server configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -14,10 +18,18 @@ pub struct Config {
     pub server: ServerConfig,
     pub database: DatabaseConfig,
     #[serde(default)]
+    pub sync: SyncConfig,
+    #[serde(default)]
+    pub auth: AuthConfig,
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    #[serde(default)]
     pub tokens: Vec<TokenConfig>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ServerConfig {
     /// Bind address
     #[serde(default = "default_bind")]
@@ -26,21 +38,215 @@ pub struct ServerConfig {
     /// Port to listen on
     #[serde(default = "default_port")]
     pub port: u16,
+
+    /// Take client IPs from the X-Forwarded-For header (only enable behind
+    /// a reverse proxy that sets it)
+    #[serde(default)]
+    pub trust_forwarded_for: bool,
+
+    /// TLS certificate chain (PEM); serve HTTPS when set together with tls_key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// TLS private key (PEM)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls_key: Option<PathBuf>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct DatabaseConfig {
     /// Path to SQLite database file
     #[serde(default = "default_db_path")]
     pub path: PathBuf,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SyncConfig {
+    /// How far ahead of the server clock a client timestamp may be (seconds)
+    #[serde(default = "default_max_clock_skew")]
+    pub max_clock_skew_secs: u64,
+
+    /// What to do with records timestamped beyond the allowed skew
+    #[serde(default)]
+    pub future_timestamps: FutureTimestampPolicy,
+
+    /// Largest page a client may request in a paginated sync
+    #[serde(default = "default_max_page_size")]
+    pub max_page_size: usize,
+
+    /// Longest a tombstone is kept waiting for every device to sync past it
+    /// (days, 0 = until acknowledged)
+    #[serde(default = "default_tombstone_retention")]
+    pub tombstone_retention_days: u64,
+
+    /// How often to purge tombstones (seconds)
+    #[serde(default = "default_tombstone_gc_interval")]
+    pub tombstone_gc_interval_secs: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuthConfig {
+    /// Accept tokens whose token_hash is the plain token (legacy configs;
+    /// upgrade them with `tickit-sync token --upgrade`)
+    #[serde(default = "default_allow_plaintext_tokens")]
+    pub allow_plaintext_tokens: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RateLimitConfig {
+    /// Requests a client IP may make per minute (0 = unlimited)
+    #[serde(default = "default_requests_per_minute")]
+    pub requests_per_minute: u32,
+
+    /// Requests the whole server accepts per minute (0 = unlimited)
+    #[serde(default)]
+    pub global_requests_per_minute: u32,
+
+    /// Failed authentications from one IP before it is banned (0 = never ban)
+    #[serde(default = "default_max_auth_failures")]
+    pub max_auth_failures: u32,
+
+    /// How long failed authentications are remembered (seconds)
+    #[serde(default = "default_failure_window")]
+    pub failure_window_secs: u64,
+
+    /// Length of the first ban (seconds); each further failure doubles it
+    #[serde(default = "default_ban")]
+    pub ban_secs: u64,
+
+    /// Longest ban (seconds)
+    #[serde(default = "default_max_ban")]
+    pub max_ban_secs: u64,
+
+    /// Keep failures and bans in the database across restarts
+    #[serde(default)]
+    pub persist_bans: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LimitsConfig {
+    /// Largest request body (bytes)
+    #[serde(default = "default_max_body_bytes")]
+    pub max_body_bytes: usize,
+
+    /// Most records a client may send in one sync
+    #[serde(default = "default_max_records_per_request")]
+    pub max_records_per_request: usize,
+
+    /// Longest task title, list name or tag name (characters)
+    #[serde(default = "default_max_title_length")]
+    pub max_title_length: usize,
+
+    /// Longest task or list description (characters)
+    #[serde(default = "default_max_description_length")]
+    pub max_description_length: usize,
+
+    /// Longest task URL (characters)
+    #[serde(default = "default_max_url_length")]
+    pub max_url_length: usize,
+
+    /// Most tags on one task
+    #[serde(default = "default_max_tags_per_task")]
+    pub max_tags_per_task: usize,
+
+    /// Reject record IDs that aren't UUIDs
+    #[serde(default = "default_require_uuid_ids")]
+    pub require_uuid_ids: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FutureTimestampPolicy {
+    /// Replace the timestamp with the current server time
+    #[default]
+    Clamp,
+    /// Reject the record as a conflict
+    Reject,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct TokenConfig {
     /// Human-readable name for the token
     pub name: String,
     /// The hashed API token (argon2 hash, or plain text for backwards compat)
     pub token_hash: String,
+    /// User account that owns the data accessed with this token
+    /// (tokens without a user share the default account)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// What the token may do
+    #[serde(default, skip_serializing_if = "TokenScope::is_default")]
+    pub scope: TokenScope,
+    /// IDs of the only lists (and their tasks) the token may access
+    /// (None = all of the user's data)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lists: Option<Vec<String>>,
+    /// When the token stops being accepted (RFC3339)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<String>,
+}
+
+/// Access level of a token; each level includes the ones before it
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Serialize,
+    Deserialize,
+    clap::ValueEnum,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenScope {
+    /// Pull changes only
+    Read,
+    /// Pull and push changes
+    #[default]
+    Write,
+    /// Also manage devices
+    Admin,
+}
+
+impl TokenScope {
+    fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl std::fmt::Display for TokenScope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            TokenScope::Read => "read",
+            TokenScope::Write => "write",
+            TokenScope::Admin => "admin",
+        })
+    }
+}
+
+/// Account used for tokens that don't name a user
+pub const DEFAULT_USER: &str = "default";
+
+impl TokenConfig {
+    /// User account this token belongs to
+    pub fn user_id(&self) -> &str {
+        self.user.as_deref().unwrap_or(DEFAULT_USER)
+    }
+
+    /// Whether the token is past its expiry (an unreadable expiry counts)
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .as_deref()
+            .is_some_and(|ts| clock::parse(ts).is_none_or(|t| t <= Utc::now()))
+    }
+
+    /// Whether token_hash is a legacy plain-text token rather than a hash
+    pub fn is_plaintext(&self) -> bool {
+        PasswordHash::new(&self.token_hash).is_err()
+    }
 }
 
 fn default_bind() -> String {
@@ -55,16 +261,139 @@ fn default_db_path() -> PathBuf {
     PathBuf::from("tickit-sync.sqlite")
 }
 
+fn default_max_clock_skew() -> u64 {
+    300
+}
+
+fn default_max_page_size() -> usize {
+    5000
+}
+
+fn default_tombstone_retention() -> u64 {
+    90
+}
+
+fn default_tombstone_gc_interval() -> u64 {
+    3600
+}
+
+fn default_allow_plaintext_tokens() -> bool {
+    true
+}
+
+fn default_requests_per_minute() -> u32 {
+    300
+}
+
+fn default_max_auth_failures() -> u32 {
+    5
+}
+
+fn default_failure_window() -> u64 {
+    900
+}
+
+fn default_ban() -> u64 {
+    60
+}
+
+fn default_max_ban() -> u64 {
+    86400
+}
+
+fn default_max_body_bytes() -> usize {
+    10 * 1024 * 1024
+}
+
+fn default_max_records_per_request() -> usize {
+    5000
+}
+
+fn default_max_title_length() -> usize {
+    1000
+}
+
+fn default_max_description_length() -> usize {
+    20_000
+}
+
+fn default_max_url_length() -> usize {
+    2048
+}
+
+fn default_max_tags_per_task() -> usize {
+    100
+}
+
+fn default_require_uuid_ids() -> bool {
+    true
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            allow_plaintext_tokens: default_allow_plaintext_tokens(),
+        }
+    }
+}
+
+impl Default for SyncConfig {
+    fn default() -> Self {
+        Self {
+            max_clock_skew_secs: default_max_clock_skew(),
+            future_timestamps: FutureTimestampPolicy::default(),
+            max_page_size: default_max_page_size(),
+            tombstone_retention_days: default_tombstone_retention(),
+            tombstone_gc_interval_secs: default_tombstone_gc_interval(),
+        }
+    }
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            requests_per_minute: default_requests_per_minute(),
+            global_requests_per_minute: 0,
+            max_auth_failures: default_max_auth_failures(),
+            failure_window_secs: default_failure_window(),
+            ban_secs: default_ban(),
+            max_ban_secs: default_max_ban(),
+            persist_bans: false,
+        }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_body_bytes: default_max_body_bytes(),
+            max_records_per_request: default_max_records_per_request(),
+            max_title_length: default_max_title_length(),
+            max_description_length: default_max_description_length(),
+            max_url_length: default_max_url_length(),
+            max_tags_per_task: default_max_tags_per_task(),
+            require_uuid_ids: default_require_uuid_ids(),
+        }
+    }
+}
+
 impl Default for Config {
     fn default() -> Self {
         Self {
             server: ServerConfig {
                 bind: default_bind(),
                 port: default_port(),
+                trust_forwarded_for: false,
+                tls_cert: None,
+                tls_key: None,
             },
             database: DatabaseConfig {
                 path: default_db_path(),
             },
+            sync: SyncConfig::default(),
+            auth: AuthConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            limits: LimitsConfig::default(),
             tokens: Vec::new(),
         }
     }
@@ -136,8 +465,78 @@ impl Config {
         Ok(())
     }
 
-    /// Check if a token is valid (supports both hashed and legacy plain tokens)
-    pub fn validate_token(&self, token: &str) -> bool {
+    /// Check settings that parse but can't be used
+    pub fn validate(&self) -> Result<()> {
+        let mut names = std::collections::HashSet::new();
+        for token in &self.tokens {
+            if token.name.is_empty() {
+                bail!("Token with an empty name");
+            }
+            if token.token_hash.is_empty() {
+                bail!("Token '{}' has an empty token_hash", token.name);
+            }
+            if !names.insert(token.name.as_str()) {
+                bail!("Duplicate token name '{}'", token.name);
+            }
+            if let Some(expires_at) = &token.expires_at
+                && clock::parse(expires_at).is_none()
+            {
+                bail!(
+                    "Token '{}' has an invalid expires_at '{}'",
+                    token.name,
+                    expires_at
+                );
+            }
+        }
+        if self.server.tls_cert.is_some() != self.server.tls_key.is_some() {
+            bail!("server.tls_cert and server.tls_key must be set together");
+        }
+        if self.limits.max_body_bytes == 0 || self.limits.max_records_per_request == 0 {
+            bail!("limits.max_body_bytes and limits.max_records_per_request must be at least 1");
+        }
+        if self.sync.max_page_size == 0 {
+            bail!("sync.max_page_size must be at least 1");
+        }
+        Ok(())
+    }
+
+    /// Describe what changed from an older version of the config
+    pub fn changes_from(&self, old: &Config) -> ConfigChanges {
+        let find =
+            |config: &'_ Config, name: &str| config.tokens.iter().find(|t| t.name == name).cloned();
+
+        let mut changes = ConfigChanges::default();
+        for token in &self.tokens {
+            match find(old, &token.name) {
+                None => changes.tokens_added.push(token.name.clone()),
+                Some(previous) if previous != *token => {
+                    changes.tokens_changed.push(token.name.clone())
+                }
+                Some(_) => {}
+            }
+        }
+        for token in &old.tokens {
+            if find(self, &token.name).is_none() {
+                changes.tokens_removed.push(token.name.clone());
+            }
+        }
+
+        changes.sync_changed = self.sync != old.sync;
+        changes.auth_changed = self.auth != old.auth;
+        changes.rate_limit_changed = self.rate_limit != old.rate_limit;
+        changes.limits_changed = self.limits != old.limits;
+        if self.server != old.server {
+            changes.restart_required.push("server");
+        }
+        if self.database != old.database {
+            changes.restart_required.push("database");
+        }
+        changes
+    }
+
+    /// Find the token entry matching a presented token
+    /// (supports both hashed and, unless disabled, legacy plain tokens)
+    pub fn validate_token(&self, token: &str) -> Option<&TokenConfig> {
         let argon2 = Argon2::default();
 
         for t in &self.tokens {
@@ -147,16 +546,69 @@ impl Config {
                     .verify_password(token.as_bytes(), &parsed_hash)
                     .is_ok()
                 {
-                    return true;
+                    return Some(t);
                 }
-            } else {
-                // Fallback: plain text comparison (legacy/backwards compat)
-                if t.token_hash == token {
-                    return true;
+            } else if self.auth.allow_plaintext_tokens {
+                // Fallback: plain text comparison (legacy/backwards compat),
+                // in constant time so timing doesn't reveal matching prefixes
+                if bool::from(t.token_hash.as_bytes().ct_eq(token.as_bytes())) {
+                    return Some(t);
                 }
             }
         }
-        false
+        None
+    }
+
+    /// Whether a configured token may be used under the current settings
+    pub fn accepts(&self, token: &TokenConfig) -> bool {
+        self.auth.allow_plaintext_tokens || !token.is_plaintext()
+    }
+
+    /// Names of tokens still stored as plain text
+    pub fn plaintext_tokens(&self) -> Vec<&str> {
+        self.tokens
+            .iter()
+            .filter(|t| t.is_plaintext())
+            .map(|t| t.name.as_str())
+            .collect()
+    }
+
+    /// Replace plain-text tokens with their Argon2 hashes, returning the
+    /// names of the upgraded tokens
+    pub fn upgrade_plaintext_tokens(&mut self) -> Result<Vec<String>> {
+        let mut upgraded = Vec::new();
+        for token in self.tokens.iter_mut().filter(|t| t.is_plaintext()) {
+            token.token_hash = hash_token(&token.token_hash)?;
+            upgraded.push(token.name.clone());
+        }
+        Ok(upgraded)
+    }
+}
+
+/// Differences between two versions of the config file
+#[derive(Debug, Default)]
+pub struct ConfigChanges {
+    pub tokens_added: Vec<String>,
+    pub tokens_removed: Vec<String>,
+    pub tokens_changed: Vec<String>,
+    pub sync_changed: bool,
+    pub auth_changed: bool,
+    pub rate_limit_changed: bool,
+    pub limits_changed: bool,
+    /// Sections whose changes only take effect after a restart
+    pub restart_required: Vec<&'static str>,
+}
+
+impl ConfigChanges {
+    pub fn is_empty(&self) -> bool {
+        self.tokens_added.is_empty()
+            && self.tokens_removed.is_empty()
+            && self.tokens_changed.is_empty()
+            && !self.sync_changed
+            && !self.auth_changed
+            && !self.rate_limit_changed
+            && !self.limits_changed
+            && self.restart_required.is_empty()
     }
 }
 
@@ -169,3 +621,126 @@ pub fn hash_token(token: &str) -> Result<String> {
         .map_err(|e| anyhow::anyhow!("Failed to hash token: {}", e))?;
     Ok(hash.to_string())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(name: &str, token_hash: &str) -> TokenConfig {
+        TokenConfig {
+            name: name.to_string(),
+            token_hash: token_hash.to_string(),
+            user: None,
+            scope: TokenScope::default(),
+            lists: None,
+            expires_at: None,
+        }
+    }
+
+    fn config(tokens: Vec<TokenConfig>) -> Config {
+        Config {
+            tokens,
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn unusable_configs_fail_validation() {
+        assert!(
+            config(vec![token("laptop", "a"), token("phone", "b")])
+                .validate()
+                .is_ok()
+        );
+        assert!(config(vec![token("", "a")]).validate().is_err());
+        assert!(config(vec![token("laptop", "")]).validate().is_err());
+        assert!(
+            config(vec![token("laptop", "a"), token("laptop", "b")])
+                .validate()
+                .is_err()
+        );
+
+        let mut empty_pages = Config::default();
+        empty_pages.sync.max_page_size = 0;
+        assert!(empty_pages.validate().is_err());
+    }
+
+    #[test]
+    fn changes_list_tokens_by_name() {
+        let old = config(vec![token("laptop", "a"), token("phone", "b")]);
+        let new = config(vec![token("laptop", "a2"), token("tablet", "c")]);
+
+        let changes = new.changes_from(&old);
+        assert_eq!(changes.tokens_added, ["tablet"]);
+        assert_eq!(changes.tokens_removed, ["phone"]);
+        assert_eq!(changes.tokens_changed, ["laptop"]);
+        assert!(!changes.sync_changed);
+        assert!(changes.restart_required.is_empty());
+        assert!(old.changes_from(&old).is_empty());
+    }
+
+    #[test]
+    fn server_and_database_changes_need_a_restart() {
+        let old = Config::default();
+        let mut new = old.clone();
+        new.server.port += 1;
+        new.database.path = PathBuf::from("elsewhere.sqlite");
+        new.sync.max_page_size += 1;
+
+        let changes = new.changes_from(&old);
+        assert!(changes.sync_changed);
+        assert_eq!(changes.restart_required, ["server", "database"]);
+    }
+
+    #[test]
+    fn tokens_expire_at_their_expiry_time() {
+        let mut token = token("laptop", "a");
+        assert!(!token.is_expired());
+        token.expires_at = Some("2000-01-01T00:00:00Z".to_string());
+        assert!(token.is_expired());
+        token.expires_at = Some("2999-01-01T00:00:00+02:00".to_string());
+        assert!(!token.is_expired());
+        token.expires_at = Some("next tuesday".to_string());
+        assert!(token.is_expired());
+        assert!(config(vec![token]).validate().is_err());
+    }
+
+    #[test]
+    fn scopes_include_the_ones_below_them() {
+        assert!(TokenScope::Read < TokenScope::Write);
+        assert!(TokenScope::Write < TokenScope::Admin);
+        assert_eq!(TokenScope::default(), TokenScope::Write);
+    }
+
+    #[test]
+    fn upgraded_tokens_still_verify() {
+        let mut config = config(vec![token("laptop", "secret")]);
+        assert_eq!(config.plaintext_tokens(), ["laptop"]);
+
+        assert_eq!(config.upgrade_plaintext_tokens().unwrap(), ["laptop"]);
+        assert!(config.plaintext_tokens().is_empty());
+        assert_ne!(config.tokens[0].token_hash, "secret");
+        assert!(config.validate_token("secret").is_some());
+        assert!(config.validate_token("wrong").is_none());
+        assert!(config.upgrade_plaintext_tokens().unwrap().is_empty());
+    }
+
+    #[test]
+    fn plaintext_tokens_can_be_refused() {
+        let mut config = config(vec![token("laptop", "secret")]);
+        assert!(config.validate_token("secret").is_some());
+        assert!(config.accepts(&config.tokens[0]));
+
+        config.auth.allow_plaintext_tokens = false;
+        assert!(config.validate_token("secret").is_none());
+        assert!(!config.accepts(&config.tokens[0]));
+    }
+
+    #[test]
+    fn tls_certificate_and_key_come_together() {
+        let mut config = Config::default();
+        config.server.tls_cert = Some(PathBuf::from("cert.pem"));
+        assert!(config.validate().is_err());
+        config.server.tls_key = Some(PathBuf::from("key.pem"));
+        assert!(config.validate().is_ok());
+    }
+}
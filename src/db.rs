@@ -1,21 +1,124 @@
 //! Database module for tickit-sync server
 
 use anyhow::{Context, Result};
-use chrono::Utc;
-use rusqlite::{Connection, params};
+use chrono::{DateTime, Duration, Utc};
+use rusqlite::{Connection, OptionalExtension, params, params_from_iter, types::Value};
+use std::collections::{BTreeMap, HashMap};
 use std::path::Path;
 use std::sync::Mutex;
 
-use crate::models::{List, Priority, RecordType, SyncRecord, Tag, Task, TaskTagLink};
+use crate::clock::{self, HybridClock};
+use crate::config::{FutureTimestampPolicy, SyncConfig};
+use crate::migrations::{self, Migration};
+use crate::models::{
+    ConflictReason, Device, List, Priority, RecordError, RecordType, SearchResult, SyncConflict,
+    SyncRecord, Tag, Task, TaskField, TaskTagLink,
+};
+use crate::query::{self, Highlight, SearchFilter, TaskFilter};
+use crate::ratelimit::AuthFailures;
+
+/// Where a client's previous sync left off
+#[derive(Debug, Clone, Copy)]
+pub enum SyncPoint<'a> {
+    /// Changes after a server sequence number (0 = full sync), optionally
+    /// bounded by the snapshot a paginated sync started from
+    Sequence { after: i64, until: Option<i64> },
+    /// Client-supplied `last_sync` timestamp (legacy clients, never paginated)
+    Timestamp(&'a str),
+}
+
+impl SyncPoint<'_> {
+    /// SQL condition (using parameters from `?2`) selecting rows changed after this point
+    fn condition(&self, time_column: &str) -> (String, Vec<Value>) {
+        match self {
+            SyncPoint::Sequence { after, until } => (
+                "seq > ?2 AND seq <= ?3".to_string(),
+                vec![
+                    Value::Integer(*after),
+                    Value::Integer(until.unwrap_or(i64::MAX)),
+                ],
+            ),
+            SyncPoint::Timestamp(ts) => (
+                format!("{time_column} > ?2"),
+                vec![Value::Text(ts.to_string())],
+            ),
+        }
+    }
+}
+
+/// Changes returned to a client
+#[derive(Debug)]
+pub struct ChangeSet {
+    pub records: Vec<SyncRecord>,
+    /// Sequence number the changes are complete up to
+    pub seq: i64,
+    /// Snapshot the changes were read from (the end of a paginated sync)
+    pub snapshot: i64,
+    /// More changes remain before the snapshot
+    pub has_more: bool,
+}
+
+/// A deletion as stored
+struct Tombstone {
+    id: String,
+    record_type: RecordType,
+    deleted_at: String,
+    /// List the task was in, for task and task-tag tombstones
+    list_id: Option<String>,
+}
+
+/// Why a batch of incoming changes was not applied
+#[derive(Debug, thiserror::Error)]
+pub enum ApplyError {
+    /// Records that failed validation; nothing was written
+    #[error("{} invalid record(s) in sync batch", .0.len())]
+    Invalid(Vec<RecordError>),
+    /// Records the token isn't allowed to change; nothing was written
+    #[error("{} record(s) in sync batch not permitted for this token", .0.len())]
+    Forbidden(Vec<RecordError>),
+    /// Database failure; the transaction was rolled back
+    #[error(transparent)]
+    Storage(#[from] anyhow::Error),
+}
+
+impl From<rusqlite::Error> for ApplyError {
+    fn from(err: rusqlite::Error) -> Self {
+        Self::Storage(err.into())
+    }
+}
+
+/// How a device connected for a sync
+#[derive(Debug, Clone)]
+pub struct DeviceContact<'a> {
+    pub token_name: &'a str,
+    pub user_agent: Option<&'a str>,
+    pub client_version: Option<&'a str>,
+    pub ip: Option<String>,
+}
+
+/// How much a token has been used
+#[derive(Debug, Clone)]
+pub struct TokenUsage {
+    pub last_used: String,
+    pub request_count: i64,
+}
 
 /// Thread-safe database wrapper
 pub struct Database {
     conn: Mutex<Connection>,
+    clock: HybridClock,
 }
 
 impl Database {
-    /// Open or create the database
+    /// Open or create the database, applying any pending migrations
     pub fn open(path: &Path) -> Result<Self> {
+        let db = Self::open_unmigrated(path)?;
+        db.migrate(false)?;
+        Ok(db)
+    }
+
+    /// Open or create the database without touching its schema
+    pub fn open_unmigrated(path: &Path) -> Result<Self> {
         // Ensure parent directory exists
         if let Some(parent) = path.parent()
             && !parent.as_os_str().is_empty()
@@ -25,169 +128,164 @@ impl Database {
 
         let conn = Connection::open(path).context("Failed to open database")?;
 
-        let db = Self {
+        Ok(Self {
             conn: Mutex::new(conn),
-        };
-        db.init()?;
+            clock: HybridClock::default(),
+        })
+    }
 
-        Ok(db)
+    /// Current schema version of the database
+    pub fn schema_version(&self) -> Result<u32> {
+        migrations::current_version(&self.conn.lock().unwrap())
     }
 
-    /// Initialize the database schema
-    fn init(&self) -> Result<()> {
-        let conn = self.conn.lock().unwrap();
-        conn.execute_batch(
-            r#"
-            -- Lists table
-            CREATE TABLE IF NOT EXISTS lists (
-                id TEXT PRIMARY KEY,
-                name TEXT NOT NULL,
-                description TEXT,
-                icon TEXT NOT NULL DEFAULT '📋',
-                color TEXT,
-                is_inbox INTEGER NOT NULL DEFAULT 0,
-                sort_order INTEGER NOT NULL DEFAULT 0,
-                created_at TEXT NOT NULL,
-                updated_at TEXT NOT NULL
-            );
-
-            -- Tags table  
-            CREATE TABLE IF NOT EXISTS tags (
-                id TEXT PRIMARY KEY,
-                name TEXT NOT NULL,
-                color TEXT NOT NULL,
-                created_at TEXT NOT NULL,
-                updated_at TEXT
-            );
-
-            -- Tasks table
-            CREATE TABLE IF NOT EXISTS tasks (
-                id TEXT PRIMARY KEY,
-                title TEXT NOT NULL,
-                description TEXT,
-                url TEXT,
-                priority TEXT NOT NULL DEFAULT 'medium',
-                completed INTEGER NOT NULL DEFAULT 0,
-                list_id TEXT NOT NULL,
-                created_at TEXT NOT NULL,
-                updated_at TEXT NOT NULL,
-                completed_at TEXT,
-                due_date TEXT,
-                FOREIGN KEY (list_id) REFERENCES lists(id)
-            );
-
-            -- Task-Tag junction table
-            CREATE TABLE IF NOT EXISTS task_tags (
-                task_id TEXT NOT NULL,
-                tag_id TEXT NOT NULL,
-                created_at TEXT NOT NULL DEFAULT (datetime('now')),
-                PRIMARY KEY (task_id, tag_id),
-                FOREIGN KEY (task_id) REFERENCES tasks(id) ON DELETE CASCADE,
-                FOREIGN KEY (tag_id) REFERENCES tags(id) ON DELETE CASCADE
-            );
-
-            -- Tombstones for deleted records
-            CREATE TABLE IF NOT EXISTS tombstones (
-                id TEXT PRIMARY KEY,
-                record_type TEXT NOT NULL,
-                deleted_at TEXT NOT NULL
-            );
-
-            -- Device sync state
-            CREATE TABLE IF NOT EXISTS device_sync (
-                device_id TEXT PRIMARY KEY,
-                last_sync TEXT NOT NULL
-            );
-
-            -- Indexes
-            CREATE INDEX IF NOT EXISTS idx_tasks_list ON tasks(list_id);
-            CREATE INDEX IF NOT EXISTS idx_tasks_updated ON tasks(updated_at);
-            CREATE INDEX IF NOT EXISTS idx_lists_updated ON lists(updated_at);
-            CREATE INDEX IF NOT EXISTS idx_tombstones_deleted ON tombstones(deleted_at);
-            "#,
-        )?;
+    /// Migrations that have not been applied yet
+    pub fn pending_migrations(&self) -> Result<&'static [Migration]> {
+        migrations::pending(&self.conn.lock().unwrap())
+    }
 
-        Ok(())
+    /// Apply pending migrations (rolled back again with `dry_run`)
+    pub fn migrate(&self, dry_run: bool) -> Result<&'static [Migration]> {
+        migrations::run(&self.conn.lock().unwrap(), dry_run)
     }
 
-    /// Get all changes since a given timestamp
-    pub fn get_changes_since(&self, since: Option<&str>) -> Result<Vec<SyncRecord>> {
+    /// Get a user's changes since a given sync point, at most `limit`
+    /// records at a time for sequence-based syncs
+    pub fn get_changes_since(
+        &self,
+        owner: &str,
+        since: SyncPoint,
+        limit: Option<usize>,
+        lists: Option<&[String]>,
+    ) -> Result<ChangeSet> {
         let conn = self.conn.lock().unwrap();
         let mut changes = Vec::new();
 
-        // Get lists
-        let lists = if let Some(since) = since {
-            let mut stmt = conn.prepare(
-                "SELECT id, name, description, icon, color, is_inbox, sort_order, created_at, updated_at 
-                 FROM lists WHERE updated_at > ?1"
-            )?;
-            self.collect_lists(&mut stmt, params![since])?
-        } else {
-            let mut stmt = conn.prepare(
-                "SELECT id, name, description, icon, color, is_inbox, sort_order, created_at, updated_at FROM lists"
-            )?;
-            self.collect_lists(&mut stmt, [])?
+        // Read under the same lock as the changes, so the cursor covers
+        // exactly what is returned
+        let current: i64 =
+            conn.query_row("SELECT value FROM sync_sequence WHERE id = 1", [], |row| {
+                row.get(0)
+            })?;
+
+        // Pages never extend past the snapshot the sync started from, and end
+        // at the sequence number of their last record
+        let mut snapshot = current;
+        let mut has_more = false;
+        let since = match since {
+            SyncPoint::Sequence { after, until } => {
+                snapshot = until.unwrap_or(current).min(current);
+                let mut page_end = snapshot;
+                if let Some(limit) = limit {
+                    let next: Vec<i64> = conn
+                        .prepare(
+                            "SELECT seq FROM (
+                                SELECT seq FROM lists WHERE owner = ?1 AND seq > ?2 AND seq <= ?3
+                                UNION ALL SELECT seq FROM tags WHERE owner = ?1 AND seq > ?2 AND seq <= ?3
+                                UNION ALL SELECT seq FROM tasks WHERE owner = ?1 AND seq > ?2 AND seq <= ?3
+                                UNION ALL SELECT seq FROM tombstones WHERE owner = ?1 AND seq > ?2 AND seq <= ?3
+                             ) ORDER BY seq LIMIT 2 OFFSET ?4",
+                        )?
+                        .query_map(
+                            params![owner, after, snapshot, limit.max(1) as i64 - 1],
+                            |row| row.get(0),
+                        )?
+                        .collect::<Result<_, _>>()?;
+                    if let [last, _] = next[..] {
+                        page_end = last;
+                        has_more = true;
+                    }
+                }
+                SyncPoint::Sequence {
+                    after,
+                    until: Some(page_end),
+                }
+            }
+            since @ SyncPoint::Timestamp(_) => since,
         };
 
-        for list in lists {
+        let query_params = |values: Vec<Value>| {
+            params_from_iter(std::iter::once(Value::Text(owner.to_string())).chain(values))
+        };
+
+        // Get lists
+        let (filter, values) = since.condition("updated_at");
+        let mut stmt = conn.prepare(&format!(
+            "SELECT id, name, description, icon, color, is_inbox, sort_order, created_at, updated_at 
+             FROM lists WHERE owner = ?1 AND {filter}"
+        ))?;
+        for list in self.collect_lists(&mut stmt, query_params(values))? {
             changes.push(SyncRecord::List(list));
         }
 
         // Get tags
-        let tags = if let Some(since) = since {
-            let mut stmt = conn.prepare(
-                "SELECT id, name, color, created_at, updated_at FROM tags WHERE created_at > ?1",
-            )?;
-            self.collect_tags(&mut stmt, params![since])?
-        } else {
-            let mut stmt =
-                conn.prepare("SELECT id, name, color, created_at, updated_at FROM tags")?;
-            self.collect_tags(&mut stmt, [])?
-        };
-
-        for tag in tags {
+        let (filter, values) = since.condition("COALESCE(updated_at, created_at)");
+        let mut stmt = conn.prepare(&format!(
+            "SELECT id, name, color, created_at, updated_at FROM tags WHERE owner = ?1 AND {filter}"
+        ))?;
+        for tag in self.collect_tags(&mut stmt, query_params(values))? {
             changes.push(SyncRecord::Tag(tag));
         }
 
         // Get tasks
-        let tasks = if let Some(since) = since {
-            let mut stmt = conn.prepare(
-                "SELECT id, title, description, url, priority, completed, list_id, 
-                 created_at, updated_at, completed_at, due_date FROM tasks WHERE updated_at > ?1",
-            )?;
-            self.collect_tasks(&conn, &mut stmt, params![since])?
-        } else {
-            let mut stmt = conn.prepare(
-                "SELECT id, title, description, url, priority, completed, list_id, 
-                 created_at, updated_at, completed_at, due_date FROM tasks",
-            )?;
-            self.collect_tasks(&conn, &mut stmt, [])?
-        };
-
-        for task in tasks {
+        let (filter, values) = since.condition("updated_at");
+        let mut stmt = conn.prepare(&format!(
+            "SELECT id, title, description, url, priority, completed, list_id, 
+             created_at, updated_at, completed_at, due_date FROM tasks WHERE owner = ?1 AND {filter}"
+        ))?;
+        for task in self.collect_tasks(&conn, owner, &mut stmt, query_params(values))? {
             changes.push(SyncRecord::Task(task));
         }
 
-        // Get tombstones
-        let tombstones = if let Some(since) = since {
-            let mut stmt = conn.prepare(
-                "SELECT id, record_type, deleted_at FROM tombstones WHERE deleted_at > ?1",
-            )?;
-            self.collect_tombstones(&mut stmt, params![since])?
-        } else {
-            let mut stmt = conn.prepare("SELECT id, record_type, deleted_at FROM tombstones")?;
-            self.collect_tombstones(&mut stmt, [])?
-        };
-
-        for (id, record_type, deleted_at) in tombstones {
+        // Get tombstones, with the list deleted tasks were in
+        let (filter, values) = since.condition("deleted_at");
+        let mut stmt = conn.prepare(&format!(
+            "SELECT id, record_type, deleted_at, list_id FROM tombstones WHERE owner = ?1 AND {filter}"
+        ))?;
+        let mut tombstone_lists = HashMap::new();
+        for tombstone in self.collect_tombstones(&mut stmt, query_params(values))? {
+            if let Some(list_id) = tombstone.list_id {
+                tombstone_lists.insert(tombstone.id.clone(), list_id);
+            }
             changes.push(SyncRecord::Deleted {
-                id,
-                record_type,
-                deleted_at,
+                id: tombstone.id,
+                record_type: tombstone.record_type,
+                deleted_at: tombstone.deleted_at,
+            });
+        }
+
+        // Tokens limited to some lists only see those lists and their tasks
+        if let Some(lists) = lists {
+            changes.retain(|record| match record {
+                SyncRecord::List(list) => lists.contains(&list.id),
+                SyncRecord::Task(task) => lists.contains(&task.list_id),
+                SyncRecord::Deleted {
+                    id,
+                    record_type: RecordType::List,
+                    ..
+                } => lists.contains(id),
+                SyncRecord::Deleted {
+                    id,
+                    record_type: RecordType::Task | RecordType::TaskTag,
+                    ..
+                } => tombstone_lists
+                    .get(id)
+                    .is_some_and(|list_id| lists.contains(list_id)),
+                _ => true,
             });
         }
 
-        Ok(changes)
+        let seq = match since {
+            SyncPoint::Sequence { until, .. } => until.unwrap_or(current),
+            SyncPoint::Timestamp(_) => current,
+        };
+
+        Ok(ChangeSet {
+            records: changes,
+            seq,
+            snapshot,
+            has_more,
+        })
     }
 
     fn collect_lists<P: rusqlite::Params>(
@@ -233,6 +331,7 @@ impl Database {
     fn collect_tasks<P: rusqlite::Params>(
         &self,
         conn: &Connection,
+        owner: &str,
         stmt: &mut rusqlite::Statement,
         params: P,
     ) -> Result<Vec<Task>> {
@@ -258,6 +357,7 @@ impl Database {
                 updated_at: row.get(8)?,
                 completed_at: row.get(9)?,
                 due_date: row.get(10)?,
+                changed_fields: None,
             })
         })?;
 
@@ -265,9 +365,10 @@ impl Database {
 
         // Fill in tag_ids
         for task in &mut tasks {
-            let mut tag_stmt = conn.prepare("SELECT tag_id FROM task_tags WHERE task_id = ?1")?;
+            let mut tag_stmt =
+                conn.prepare("SELECT tag_id FROM task_tags WHERE owner = ?1 AND task_id = ?2")?;
             let tag_ids: Vec<String> = tag_stmt
-                .query_map(params![&task.id], |row| row.get(0))?
+                .query_map(params![owner, &task.id], |row| row.get(0))?
                 .collect::<Result<Vec<_>, _>>()?;
             task.tag_ids = tag_ids;
         }
@@ -279,7 +380,7 @@ impl Database {
         &self,
         stmt: &mut rusqlite::Statement,
         params: P,
-    ) -> Result<Vec<(String, RecordType, String)>> {
+    ) -> Result<Vec<Tombstone>> {
         let rows = stmt.query_map(params, |row| {
             let record_type_str: String = row.get(1)?;
             let record_type = match record_type_str.as_str() {
@@ -290,23 +391,326 @@ impl Database {
                 _ => RecordType::Task,
             };
 
-            Ok((row.get(0)?, record_type, row.get(2)?))
+            Ok(Tombstone {
+                id: row.get(0)?,
+                record_type,
+                deleted_at: row.get(2)?,
+                list_id: row.get(3)?,
+            })
         })?;
 
         rows.collect::<Result<Vec<_>, _>>().map_err(Into::into)
     }
 
-    /// Apply incoming changes from a client
-    pub fn apply_changes(&self, changes: &[SyncRecord]) -> Result<Vec<String>> {
+    /// Server clock used to stamp changes
+    pub fn clock(&self) -> &HybridClock {
+        &self.clock
+    }
+
+    /// Sequence number of the latest change
+    pub fn current_seq(&self) -> Result<i64> {
+        let conn = self.conn.lock().unwrap();
+        let seq = conn.query_row("SELECT value FROM sync_sequence WHERE id = 1", [], |row| {
+            row.get(0)
+        })?;
+        Ok(seq)
+    }
+
+    /// A page of a user's tasks matching a query, plus the cursor of the
+    /// next page. `lists` restricts the result to a token's lists.
+    pub fn query_tasks(
+        &self,
+        owner: &str,
+        filter: &TaskFilter,
+        lists: Option<&[String]>,
+    ) -> Result<(Vec<Task>, Option<String>)> {
+        let (conditions, params) = filter.sql(lists);
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT id, title, description, url, priority, completed, list_id, 
+             created_at, updated_at, completed_at, due_date FROM tasks WHERE owner = ?1{conditions}"
+        ))?;
+        let params = std::iter::once(Value::from(owner.to_string())).chain(params);
+        let tasks = self.collect_tasks(&conn, owner, &mut stmt, params_from_iter(params))?;
+        Ok(filter.page(tasks))
+    }
+
+    /// A user's tasks matching a full-text search, best match first. Matched
+    /// words in the title and description snippet are marked by `highlight`.
+    pub fn search_tasks(
+        &self,
+        owner: &str,
+        filter: &SearchFilter,
+        lists: Option<&[String]>,
+        highlight: Highlight,
+    ) -> Result<Vec<SearchResult>> {
+        let (conditions, mut params) = filter.sql(lists);
+        let start = query::param(&mut params, Highlight::MATCH_START.to_string().into());
+        let end = query::param(&mut params, Highlight::MATCH_END.to_string().into());
+        let conn = self.conn.lock().unwrap();
+        // Title matches weigh ten times as much as description matches
+        let mut stmt = conn.prepare(&format!(
+            "SELECT tasks.id, highlight(task_search, 2, {start}, {end}),
+             snippet(task_search, 3, {start}, {end}, '…', 16),
+             bm25(task_search, 0.0, 0.0, 10.0, 1.0) AS rank
+             FROM task_search
+             JOIN tasks ON tasks.owner = task_search.owner AND tasks.id = task_search.id
+             WHERE tasks.owner = ?1{conditions} ORDER BY rank LIMIT {}",
+            filter.limit()
+        ))?;
+        let params = std::iter::once(Value::from(owner.to_string())).chain(params);
+        let hits = stmt
+            .query_map(params_from_iter(params), |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, f64>(3)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut results = Vec::with_capacity(hits.len());
+        for (id, title, snippet, rank) in hits {
+            if let Some(task) = self.fetch_task(&conn, owner, &id)? {
+                results.push(SearchResult {
+                    task,
+                    // bm25 is lower for better matches
+                    score: -rank,
+                    title: highlight.render(&title),
+                    snippet: Some(snippet)
+                        .filter(|s| !s.is_empty())
+                        .map(|s| highlight.render(&s)),
+                });
+            }
+        }
+        Ok(results)
+    }
+
+    /// Number of tasks in one of a user's lists
+    pub fn list_task_count(&self, owner: &str, list_id: &str) -> Result<i64> {
+        let conn = self.conn.lock().unwrap();
+        let count = conn.query_row(
+            "SELECT COUNT(*) FROM tasks WHERE owner = ?1 AND list_id = ?2",
+            params![owner, list_id],
+            |row| row.get(0),
+        )?;
+        Ok(count)
+    }
+
+    /// A user's lists, optionally only some of them
+    pub fn lists(&self, owner: &str, only: Option<&[String]>) -> Result<Vec<List>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT id, name, description, icon, color, is_inbox, sort_order, created_at, updated_at 
+             FROM lists WHERE owner = ?1 ORDER BY sort_order, name",
+        )?;
+        let mut lists = self.collect_lists(&mut stmt, params![owner])?;
+        if let Some(only) = only {
+            lists.retain(|list| only.contains(&list.id));
+        }
+        Ok(lists)
+    }
+
+    /// A user's tags
+    pub fn tags(&self, owner: &str) -> Result<Vec<Tag>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT id, name, color, created_at, updated_at FROM tags WHERE owner = ?1
+             ORDER BY name",
+        )?;
+        self.collect_tags(&mut stmt, params![owner])
+    }
+
+    /// One of a user's tasks
+    pub fn task(&self, owner: &str, id: &str) -> Result<Option<Task>> {
+        self.fetch_task(&self.conn.lock().unwrap(), owner, id)
+    }
+
+    /// One of a user's lists
+    pub fn list(&self, owner: &str, id: &str) -> Result<Option<List>> {
+        self.fetch_list(&self.conn.lock().unwrap(), owner, id)
+    }
+
+    /// One of a user's tags
+    pub fn tag(&self, owner: &str, id: &str) -> Result<Option<Tag>> {
+        self.fetch_tag(&self.conn.lock().unwrap(), owner, id)
+    }
+
+    /// Apply incoming changes from a client to a user's data. The batch is
+    /// validated up front and applied in a single transaction, so either every
+    /// record is applied (or resolved as a conflict) or none are.
+    pub fn apply_changes(
+        &self,
+        owner: &str,
+        changes: &[SyncRecord],
+        sync: &SyncConfig,
+        lists: Option<&[String]>,
+    ) -> Result<Vec<SyncConflict>, ApplyError> {
         let conn = self.conn.lock().unwrap();
         let mut conflicts = Vec::new();
 
+        // Tokens limited to some lists may only change those lists and their tasks
+        if let Some(lists) = lists {
+            let mut denied = Vec::new();
+            for (index, change) in changes.iter().enumerate() {
+                if let Some(message) =
+                    self.list_access_denied(&conn, owner, change, changes, lists)?
+                {
+                    denied.push(RecordError {
+                        index,
+                        id: change.id().to_string(),
+                        record_type: Some(change.record_type()),
+                        message,
+                    });
+                }
+            }
+            if !denied.is_empty() {
+                return Err(ApplyError::Forbidden(denied));
+            }
+        }
+
+        // Normalize timestamps before any of them are compared or stored
+        let mut accepted = Vec::with_capacity(changes.len());
+        let mut errors = Vec::new();
+        for (index, change) in changes.iter().enumerate() {
+            match self.normalize_record(change, sync) {
+                Ok(Some(record)) => accepted.push(record),
+                Ok(None) => conflicts.push(SyncConflict {
+                    id: change.id().to_string(),
+                    record_type: change.record_type(),
+                    reason: ConflictReason::FutureTimestamp,
+                    fields: Vec::new(),
+                    client: change.clone(),
+                    server: self.fetch_record(&conn, owner, change.id(), change.record_type())?,
+                }),
+                Err(err) => errors.push(RecordError {
+                    index,
+                    id: change.id().to_string(),
+                    record_type: Some(change.record_type()),
+                    message: err.to_string(),
+                }),
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(ApplyError::Invalid(errors));
+        }
+
         // Disable foreign key checks during sync to avoid ordering issues
+        // (this has no effect inside a transaction, so it wraps it)
         conn.execute("PRAGMA foreign_keys = OFF", [])?;
+        let applied = self.apply_records(&conn, owner, &accepted);
+        conn.execute("PRAGMA foreign_keys = ON", [])?;
+
+        conflicts.extend(applied?);
+        Ok(conflicts)
+    }
+
+    /// Why a token limited to `lists` may not apply a record (None if it may)
+    fn list_access_denied(
+        &self,
+        conn: &Connection,
+        owner: &str,
+        record: &SyncRecord,
+        batch: &[SyncRecord],
+        lists: &[String],
+    ) -> Result<Option<String>> {
+        let allowed = |list_id: &str| lists.iter().any(|l| l == list_id);
+        let outside = |list_id: &str| format!("List {list_id} is outside the token's lists");
+        let task_list = |task_id: &str| -> Result<Option<String>> {
+            conn.query_row(
+                "SELECT list_id FROM tasks WHERE owner = ?1 AND id = ?2",
+                params![owner, task_id],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(Into::into)
+        };
+
+        Ok(match record {
+            SyncRecord::List(list) => (!allowed(&list.id)).then(|| outside(&list.id)),
+            SyncRecord::Deleted {
+                id,
+                record_type: RecordType::List,
+                ..
+            } => (!allowed(id)).then(|| outside(id)),
+            SyncRecord::Tag(_)
+            | SyncRecord::Deleted {
+                record_type: RecordType::Tag,
+                ..
+            } => Some("Tokens limited to lists can't change tags".to_string()),
+            // Tasks can't be moved into or out of the allowed lists either
+            SyncRecord::Task(task) => match task_list(&task.id)? {
+                _ if !allowed(&task.list_id) => Some(outside(&task.list_id)),
+                Some(current) if !allowed(&current) => Some(outside(&current)),
+                _ => None,
+            },
+            SyncRecord::TaskTag(link) => {
+                // The task may be created by the same batch
+                let list = match task_list(&link.task_id)? {
+                    Some(list) => Some(list),
+                    None => batch.iter().find_map(|r| match r {
+                        SyncRecord::Task(task) if task.id == link.task_id => {
+                            Some(task.list_id.clone())
+                        }
+                        _ => None,
+                    }),
+                };
+                match list {
+                    Some(list) => (!allowed(&list)).then(|| outside(&list)),
+                    None => Some(format!("Unknown task {}", link.task_id)),
+                }
+            }
+            // Deletions of unknown tasks are refused, since their tombstones
+            // would reach every device whatever list the task is in. A task
+            // deleted before keeps its list on the tombstone, so a deletion
+            // can still be synced again.
+            SyncRecord::Deleted {
+                id,
+                record_type: RecordType::Task | RecordType::TaskTag,
+                ..
+            } => {
+                let list = match task_list(id)? {
+                    Some(list) => Some(list),
+                    None => conn
+                        .query_row(
+                            "SELECT list_id FROM tombstones WHERE owner = ?1 AND id = ?2",
+                            params![owner, id],
+                            |row| row.get::<_, Option<String>>(0),
+                        )
+                        .optional()?
+                        .flatten()
+                        .or_else(|| {
+                            batch.iter().find_map(|r| match r {
+                                SyncRecord::Task(task) if &task.id == id => {
+                                    Some(task.list_id.clone())
+                                }
+                                _ => None,
+                            })
+                        }),
+                };
+                match list {
+                    Some(list) => (!allowed(&list)).then(|| outside(&list)),
+                    None => Some(format!("Unknown task {id}")),
+                }
+            }
+        })
+    }
+
+    /// Apply validated records in one transaction, rolling back on any error
+    fn apply_records(
+        &self,
+        conn: &Connection,
+        owner: &str,
+        records: &[SyncRecord],
+    ) -> Result<Vec<SyncConflict>> {
+        let tx = conn.unchecked_transaction()?;
+        let mut conflicts = Vec::new();
 
         // Sort changes: lists first, then tags, then tasks, then deletions last
         // This ensures foreign key constraints are satisfied
-        let mut sorted_changes: Vec<_> = changes.iter().collect();
+        let mut sorted_changes: Vec<_> = records.iter().collect();
         sorted_changes.sort_by_key(|change| match change {
             SyncRecord::List(_) => 0,
             SyncRecord::Tag(_) => 1,
@@ -316,129 +720,320 @@ impl Database {
         });
 
         for change in sorted_changes {
-            match change {
-                SyncRecord::Task(task) => {
-                    if let Some(conflict) = self.upsert_task(&conn, task)? {
-                        conflicts.push(conflict);
-                    }
-                }
-                SyncRecord::List(list) => {
-                    if let Some(conflict) = self.upsert_list(&conn, list)? {
-                        conflicts.push(conflict);
-                    }
-                }
-                SyncRecord::Tag(tag) => {
-                    self.upsert_tag(&conn, tag)?;
-                }
-                SyncRecord::TaskTag(link) => {
-                    self.upsert_task_tag(&conn, link)?;
-                }
+            let conflict = match change {
+                SyncRecord::Task(task) => self.upsert_task(&tx, owner, task),
+                SyncRecord::List(list) => self.upsert_list(&tx, owner, list),
+                SyncRecord::Tag(tag) => self.upsert_tag(&tx, owner, tag),
+                SyncRecord::TaskTag(link) => self.upsert_task_tag(&tx, owner, link),
                 SyncRecord::Deleted {
                     id,
                     record_type,
                     deleted_at,
-                } => {
-                    self.apply_delete(&conn, id, *record_type, deleted_at)?;
+                } => self
+                    .apply_delete(&tx, owner, id, *record_type, deleted_at)
+                    .map(|_| None),
+            }
+            .with_context(|| {
+                format!(
+                    "Failed to apply {} {}",
+                    record_type_str(change.record_type()),
+                    change.id()
+                )
+            })?;
+            conflicts.extend(conflict);
+        }
+
+        tx.commit()?;
+        Ok(conflicts)
+    }
+
+    /// Rewrite a record's timestamps (and timestamp due dates) in canonical
+    /// form and apply the clock skew policy to the timestamp that orders it.
+    /// Returns None if the record is rejected for being too far in the future.
+    fn normalize_record(
+        &self,
+        record: &SyncRecord,
+        sync: &SyncConfig,
+    ) -> Result<Option<SyncRecord>> {
+        let mut record = record.clone();
+        let id = record.id().to_string();
+
+        if let SyncRecord::Task(task) = &mut record {
+            if let Some(completed_at) = &mut task.completed_at {
+                *completed_at = clock::normalize(completed_at)
+                    .with_context(|| format!("Invalid timestamp '{completed_at}'"))?;
+            }
+            // Plain YYYY-MM-DD due dates are kept as they are
+            if let Some(due_date) = &mut task.due_date
+                && let Some(normalized) = clock::normalize(due_date)
+            {
+                *due_date = normalized;
+            }
+        }
+
+        let (other, ordering): (Option<&mut String>, &mut String) = match &mut record {
+            SyncRecord::Task(task) => (Some(&mut task.created_at), &mut task.updated_at),
+            SyncRecord::List(list) => (Some(&mut list.created_at), &mut list.updated_at),
+            SyncRecord::Tag(Tag {
+                created_at,
+                updated_at: Some(updated_at),
+                ..
+            }) => (Some(created_at), updated_at),
+            SyncRecord::Tag(tag) => (None, &mut tag.created_at),
+            SyncRecord::TaskTag(link) => (None, &mut link.created_at),
+            SyncRecord::Deleted { deleted_at, .. } => (None, deleted_at),
+        };
+
+        for ts in other.into_iter().chain(std::iter::once(&mut *ordering)) {
+            *ts = clock::normalize(ts).with_context(|| format!("Invalid timestamp '{ts}'"))?;
+        }
+
+        let limit = Utc::now() + Duration::seconds(sync.max_clock_skew_secs as i64);
+        if clock::parse(ordering).is_some_and(|t| t > limit) {
+            match sync.future_timestamps {
+                FutureTimestampPolicy::Clamp => {
+                    tracing::warn!(id = %id, timestamp = %ordering, "Clamping future-dated record");
+                    *ordering = self.clock.now();
+                }
+                FutureTimestampPolicy::Reject => {
+                    tracing::warn!(id = %id, timestamp = %ordering, "Rejecting future-dated record");
+                    return Ok(None);
                 }
             }
+        } else {
+            self.clock.observe(ordering);
         }
 
-        // Re-enable foreign key checks
-        conn.execute("PRAGMA foreign_keys = ON", [])?;
+        Ok(Some(record))
+    }
 
-        Ok(conflicts)
+    /// Server's current version of a record (the task for task-tag links)
+    fn fetch_record(
+        &self,
+        conn: &Connection,
+        owner: &str,
+        id: &str,
+        record_type: RecordType,
+    ) -> Result<Option<SyncRecord>> {
+        Ok(match record_type {
+            RecordType::Task | RecordType::TaskTag => {
+                self.fetch_task(conn, owner, id)?.map(SyncRecord::Task)
+            }
+            RecordType::List => self.fetch_list(conn, owner, id)?.map(SyncRecord::List),
+            RecordType::Tag => self.fetch_tag(conn, owner, id)?.map(SyncRecord::Tag),
+        })
     }
 
-    fn upsert_task(&self, conn: &Connection, task: &Task) -> Result<Option<String>> {
-        // Check existing
-        let existing: Option<String> = conn
-            .query_row(
-                "SELECT updated_at FROM tasks WHERE id = ?1",
-                params![&task.id],
-                |row| row.get(0),
-            )
-            .ok();
+    /// Merge an incoming task field by field. Each field the client changed
+    /// is taken if it is newer than the server's last change to that field;
+    /// changed fields that lose to a newer server edit are reported as a conflict.
+    fn upsert_task(
+        &self,
+        conn: &Connection,
+        owner: &str,
+        task: &Task,
+    ) -> Result<Option<SyncConflict>> {
+        let Some(current) = self.fetch_task(conn, owner, &task.id)? else {
+            self.insert_task(conn, owner, task)?;
+            return Ok(None);
+        };
 
-        if let Some(existing_updated) = existing {
-            if task.updated_at <= existing_updated {
-                // Conflict: server has newer
-                return Ok(Some(task.id.clone()));
+        // Fields without their own timestamp were last changed with the record
+        let mut field_updated_at = self.fetch_field_updated_at(conn, owner, &task.id)?;
+        for field in TaskField::ALL {
+            field_updated_at
+                .entry(field)
+                .or_insert_with(|| current.updated_at.clone());
+        }
+
+        // Clients that don't report changed fields send their whole record
+        let changed_fields = task.changed_fields.as_deref().unwrap_or(&TaskField::ALL);
+
+        let mut merged = current.clone();
+        let mut applied = Vec::new();
+        let mut lost = Vec::new();
+        for &field in changed_fields {
+            if task_field_eq(field, &current, task) {
+                continue;
+            }
+            if clock::compare(&task.updated_at, &field_updated_at[&field]).is_gt() {
+                copy_task_field(field, task, &mut merged);
+                field_updated_at.insert(field, task.updated_at.clone());
+                applied.push(field);
+            } else {
+                lost.push(field);
+            }
+        }
+
+        if !applied.is_empty() {
+            if clock::compare(&task.updated_at, &merged.updated_at).is_gt() {
+                merged.updated_at = task.updated_at.clone();
             }
 
-            // Update existing
             conn.execute(
                 r#"UPDATE tasks SET title = ?2, description = ?3, url = ?4, priority = ?5,
-                   completed = ?6, list_id = ?7, updated_at = ?8, completed_at = ?9, due_date = ?10
-                   WHERE id = ?1"#,
-                params![
-                    &task.id,
-                    &task.title,
-                    &task.description,
-                    &task.url,
-                    format!("{:?}", task.priority).to_lowercase(),
-                    task.completed as i32,
-                    &task.list_id,
-                    &task.updated_at,
-                    &task.completed_at,
-                    &task.due_date,
-                ],
-            )?;
-        } else {
-            // Insert new
-            conn.execute(
-                r#"INSERT INTO tasks (id, title, description, url, priority, completed, list_id,
-                   created_at, updated_at, completed_at, due_date)
-                   VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)"#,
+                   completed = ?6, list_id = ?7, updated_at = ?8, completed_at = ?9, due_date = ?10,
+                   field_updated_at = ?13, seq = ?12 WHERE owner = ?11 AND id = ?1"#,
                 params![
-                    &task.id,
-                    &task.title,
-                    &task.description,
-                    &task.url,
-                    format!("{:?}", task.priority).to_lowercase(),
-                    task.completed as i32,
-                    &task.list_id,
-                    &task.created_at,
-                    &task.updated_at,
-                    &task.completed_at,
-                    &task.due_date,
+                    &merged.id,
+                    &merged.title,
+                    &merged.description,
+                    &merged.url,
+                    format!("{:?}", merged.priority).to_lowercase(),
+                    merged.completed as i32,
+                    &merged.list_id,
+                    &merged.updated_at,
+                    &merged.completed_at,
+                    &merged.due_date,
+                    owner,
+                    next_seq(conn)?,
+                    serde_json::to_string(&field_updated_at)?,
                 ],
             )?;
+
+            if applied.contains(&TaskField::Title) || applied.contains(&TaskField::Description) {
+                index_task(conn, owner, &merged.id)?;
+            }
+            if applied.contains(&TaskField::TagIds) {
+                self.replace_task_tags(conn, owner, &merged)?;
+            }
+        }
+
+        if lost.is_empty() {
+            return Ok(None);
         }
 
-        // Update tags
+        // Conflict: server has newer values for fields the client changed
+        Ok(Some(SyncConflict {
+            id: task.id.clone(),
+            record_type: RecordType::Task,
+            reason: if applied.is_empty() {
+                ConflictReason::StaleUpdate
+            } else {
+                ConflictReason::FieldConflict
+            },
+            fields: lost,
+            client: SyncRecord::Task(task.clone()),
+            server: self
+                .fetch_task(conn, owner, &task.id)?
+                .map(SyncRecord::Task),
+        }))
+    }
+
+    fn insert_task(&self, conn: &Connection, owner: &str, task: &Task) -> Result<()> {
+        conn.execute(
+            r#"INSERT INTO tasks (id, title, description, url, priority, completed, list_id,
+               created_at, updated_at, completed_at, due_date, owner, seq)
+               VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)"#,
+            params![
+                &task.id,
+                &task.title,
+                &task.description,
+                &task.url,
+                format!("{:?}", task.priority).to_lowercase(),
+                task.completed as i32,
+                &task.list_id,
+                &task.created_at,
+                &task.updated_at,
+                &task.completed_at,
+                &task.due_date,
+                owner,
+                next_seq(conn)?,
+            ],
+        )?;
+        index_task(conn, owner, &task.id)?;
+
+        self.replace_task_tags(conn, owner, task)
+    }
+
+    fn replace_task_tags(&self, conn: &Connection, owner: &str, task: &Task) -> Result<()> {
         conn.execute(
-            "DELETE FROM task_tags WHERE task_id = ?1",
-            params![&task.id],
+            "DELETE FROM task_tags WHERE owner = ?1 AND task_id = ?2",
+            params![owner, &task.id],
         )?;
 
-        let now = Utc::now().to_rfc3339();
+        let now = self.clock.now();
         for tag_id in &task.tag_ids {
             conn.execute(
-                "INSERT OR IGNORE INTO task_tags (task_id, tag_id, created_at) VALUES (?1, ?2, ?3)",
-                params![&task.id, tag_id, &now],
+                "INSERT OR IGNORE INTO task_tags (owner, task_id, tag_id, created_at) VALUES (?1, ?2, ?3, ?4)",
+                params![owner, &task.id, tag_id, &now],
             )?;
         }
 
-        Ok(None)
+        Ok(())
+    }
+
+    fn fetch_task(&self, conn: &Connection, owner: &str, id: &str) -> Result<Option<Task>> {
+        let mut stmt = conn.prepare(
+            "SELECT id, title, description, url, priority, completed, list_id, 
+             created_at, updated_at, completed_at, due_date FROM tasks WHERE owner = ?1 AND id = ?2",
+        )?;
+        let tasks = self.collect_tasks(conn, owner, &mut stmt, params![owner, id])?;
+        Ok(tasks.into_iter().next())
+    }
+
+    fn fetch_list(&self, conn: &Connection, owner: &str, id: &str) -> Result<Option<List>> {
+        let mut stmt = conn.prepare(
+            "SELECT id, name, description, icon, color, is_inbox, sort_order, created_at, updated_at 
+             FROM lists WHERE owner = ?1 AND id = ?2",
+        )?;
+        let lists = self.collect_lists(&mut stmt, params![owner, id])?;
+        Ok(lists.into_iter().next())
+    }
+
+    fn fetch_tag(&self, conn: &Connection, owner: &str, id: &str) -> Result<Option<Tag>> {
+        let mut stmt = conn.prepare(
+            "SELECT id, name, color, created_at, updated_at FROM tags WHERE owner = ?1 AND id = ?2",
+        )?;
+        let tags = self.collect_tags(&mut stmt, params![owner, id])?;
+        Ok(tags.into_iter().next())
+    }
+
+    fn fetch_field_updated_at(
+        &self,
+        conn: &Connection,
+        owner: &str,
+        id: &str,
+    ) -> Result<BTreeMap<TaskField, String>> {
+        let json: String = conn.query_row(
+            "SELECT field_updated_at FROM tasks WHERE owner = ?1 AND id = ?2",
+            params![owner, id],
+            |row| row.get(0),
+        )?;
+        Ok(serde_json::from_str(&json).unwrap_or_default())
     }
 
-    fn upsert_list(&self, conn: &Connection, list: &List) -> Result<Option<String>> {
+    fn upsert_list(
+        &self,
+        conn: &Connection,
+        owner: &str,
+        list: &List,
+    ) -> Result<Option<SyncConflict>> {
         let existing: Option<String> = conn
             .query_row(
-                "SELECT updated_at FROM lists WHERE id = ?1",
-                params![&list.id],
+                "SELECT updated_at FROM lists WHERE owner = ?1 AND id = ?2",
+                params![owner, &list.id],
                 |row| row.get(0),
             )
-            .ok();
+            .optional()?;
 
         if let Some(existing_updated) = existing {
-            if list.updated_at <= existing_updated {
-                return Ok(Some(list.id.clone()));
+            if clock::compare(&list.updated_at, &existing_updated).is_le() {
+                return Ok(Some(SyncConflict {
+                    id: list.id.clone(),
+                    record_type: RecordType::List,
+                    reason: ConflictReason::StaleUpdate,
+                    fields: Vec::new(),
+                    client: SyncRecord::List(list.clone()),
+                    server: self
+                        .fetch_list(conn, owner, &list.id)?
+                        .map(SyncRecord::List),
+                }));
             }
 
             conn.execute(
                 r#"UPDATE lists SET name = ?2, description = ?3, icon = ?4, color = ?5,
-                   sort_order = ?6, updated_at = ?7 WHERE id = ?1"#,
+                   sort_order = ?6, updated_at = ?7, seq = ?9 WHERE owner = ?8 AND id = ?1"#,
                 params![
                     &list.id,
                     &list.name,
@@ -447,12 +1042,14 @@ impl Database {
                     &list.color,
                     list.sort_order,
                     &list.updated_at,
+                    owner,
+                    next_seq(conn)?,
                 ],
             )?;
         } else {
             conn.execute(
-                r#"INSERT INTO lists (id, name, description, icon, color, is_inbox, sort_order, created_at, updated_at)
-                   VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)"#,
+                r#"INSERT INTO lists (id, name, description, icon, color, is_inbox, sort_order, created_at, updated_at, owner, seq)
+                   VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)"#,
                 params![
                     &list.id,
                     &list.name,
@@ -463,6 +1060,8 @@ impl Database {
                     list.sort_order,
                     &list.created_at,
                     &list.updated_at,
+                    owner,
+                    next_seq(conn)?,
                 ],
             )?;
         }
@@ -470,80 +1069,1958 @@ impl Database {
         Ok(None)
     }
 
-    fn upsert_tag(&self, conn: &Connection, tag: &Tag) -> Result<()> {
-        conn.execute(
-            r#"INSERT OR REPLACE INTO tags (id, name, color, created_at, updated_at)
-               VALUES (?1, ?2, ?3, ?4, ?5)"#,
-            params![
-                &tag.id,
-                &tag.name,
-                &tag.color,
-                &tag.created_at,
-                &tag.updated_at
-            ],
-        )?;
-        Ok(())
-    }
-
-    fn upsert_task_tag(&self, conn: &Connection, link: &TaskTagLink) -> Result<()> {
-        conn.execute(
-            "INSERT OR IGNORE INTO task_tags (task_id, tag_id, created_at) VALUES (?1, ?2, ?3)",
-            params![&link.task_id, &link.tag_id, &link.created_at],
-        )?;
-        Ok(())
-    }
-
-    fn apply_delete(
+    fn upsert_tag(
         &self,
         conn: &Connection,
-        id: &str,
-        record_type: RecordType,
-        deleted_at: &str,
-    ) -> Result<()> {
-        let type_str = match record_type {
-            RecordType::Task => "task",
-            RecordType::List => "list",
-            RecordType::Tag => "tag",
-            RecordType::TaskTag => "task_tag",
-        };
+        owner: &str,
+        tag: &Tag,
+    ) -> Result<Option<SyncConflict>> {
+        // Tags that were never edited only carry created_at
+        let existing: Option<String> = conn
+            .query_row(
+                "SELECT COALESCE(updated_at, created_at) FROM tags WHERE owner = ?1 AND id = ?2",
+                params![owner, &tag.id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let incoming_updated = tag.updated_at.as_ref().unwrap_or(&tag.created_at);
 
-        // Record tombstone
-        conn.execute(
-            "INSERT OR REPLACE INTO tombstones (id, record_type, deleted_at) VALUES (?1, ?2, ?3)",
-            params![id, type_str, deleted_at],
-        )?;
+        if let Some(existing_updated) = existing {
+            if clock::compare(incoming_updated, &existing_updated).is_le() {
+                return Ok(Some(SyncConflict {
+                    id: tag.id.clone(),
+                    record_type: RecordType::Tag,
+                    reason: ConflictReason::StaleUpdate,
+                    fields: Vec::new(),
+                    client: SyncRecord::Tag(tag.clone()),
+                    server: self.fetch_tag(conn, owner, &tag.id)?.map(SyncRecord::Tag),
+                }));
+            }
 
-        // Delete the actual record
-        match record_type {
+            conn.execute(
+                r#"UPDATE tags SET name = ?3, color = ?4, updated_at = ?5, seq = ?6
+                   WHERE owner = ?1 AND id = ?2"#,
+                params![
+                    owner,
+                    &tag.id,
+                    &tag.name,
+                    &tag.color,
+                    &tag.updated_at,
+                    next_seq(conn)?,
+                ],
+            )?;
+        } else {
+            conn.execute(
+                r#"INSERT INTO tags (owner, id, name, color, created_at, updated_at, seq)
+                   VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)"#,
+                params![
+                    owner,
+                    &tag.id,
+                    &tag.name,
+                    &tag.color,
+                    &tag.created_at,
+                    &tag.updated_at,
+                    next_seq(conn)?,
+                ],
+            )?;
+        }
+
+        Ok(None)
+    }
+
+    fn upsert_task_tag(
+        &self,
+        conn: &Connection,
+        owner: &str,
+        link: &TaskTagLink,
+    ) -> Result<Option<SyncConflict>> {
+        let conflict = |reason, server: Option<Task>| SyncConflict {
+            id: link.task_id.clone(),
+            record_type: RecordType::TaskTag,
+            reason,
+            fields: Vec::new(),
+            client: SyncRecord::TaskTag(link.clone()),
+            server: server.map(SyncRecord::Task),
+        };
+
+        // Linking to a task or tag that was deleted on the server
+        if has_tombstone(conn, owner, &link.task_id, RecordType::Task)?
+            || has_tombstone(conn, owner, &link.tag_id, RecordType::Tag)?
+        {
+            let task = self.fetch_task(conn, owner, &link.task_id)?;
+            return Ok(Some(conflict(ConflictReason::Deleted, task)));
+        }
+
+        let exists: bool = conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM task_tags WHERE owner = ?1 AND task_id = ?2 AND tag_id = ?3)",
+            params![owner, &link.task_id, &link.tag_id],
+            |row| row.get(0),
+        )?;
+        if exists {
+            return Ok(None);
+        }
+
+        // The task's tags were replaced on the server after this link was
+        // made; tasks whose tags never changed accept any link
+        if let Some(task) = self.fetch_task(conn, owner, &link.task_id)? {
+            let field_updated_at = self.fetch_field_updated_at(conn, owner, &link.task_id)?;
+            if field_updated_at
+                .get(&TaskField::TagIds)
+                .is_some_and(|tags_updated| clock::compare(&link.created_at, tags_updated).is_le())
+            {
+                return Ok(Some(conflict(ConflictReason::StaleUpdate, Some(task))));
+            }
+        }
+
+        conn.execute(
+            "INSERT INTO task_tags (owner, task_id, tag_id, created_at) VALUES (?1, ?2, ?3, ?4)",
+            params![owner, &link.task_id, &link.tag_id, &link.created_at],
+        )?;
+        self.set_tags_updated_at(conn, owner, &link.task_id, &link.created_at)?;
+
+        // Links are sent to clients as part of the task's tag_ids
+        touch_task(conn, owner, &link.task_id)?;
+        Ok(None)
+    }
+
+    /// Record that a task's links changed at `at`, so links made before
+    /// then are stale
+    fn set_tags_updated_at(
+        &self,
+        conn: &Connection,
+        owner: &str,
+        task_id: &str,
+        at: &str,
+    ) -> Result<()> {
+        let exists: bool = conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM tasks WHERE owner = ?1 AND id = ?2)",
+            params![owner, task_id],
+            |row| row.get(0),
+        )?;
+        if !exists {
+            return Ok(());
+        }
+
+        let mut field_updated_at = self.fetch_field_updated_at(conn, owner, task_id)?;
+        if field_updated_at
+            .get(&TaskField::TagIds)
+            .is_some_and(|current| clock::compare(at, current).is_le())
+        {
+            return Ok(());
+        }
+        field_updated_at.insert(TaskField::TagIds, at.to_string());
+        conn.execute(
+            "UPDATE tasks SET field_updated_at = ?3 WHERE owner = ?1 AND id = ?2",
+            params![owner, task_id, serde_json::to_string(&field_updated_at)?],
+        )?;
+        Ok(())
+    }
+
+    fn apply_delete(
+        &self,
+        conn: &Connection,
+        owner: &str,
+        id: &str,
+        record_type: RecordType,
+        deleted_at: &str,
+    ) -> Result<()> {
+        let type_str = record_type_str(record_type);
+
+        // Record tombstone; deleted tasks and task tags keep the task's list
+        // (the task may already be gone when a deletion is synced again)
+        let list_id: Option<String> = match record_type {
+            RecordType::Task | RecordType::TaskTag => conn.query_row(
+                "SELECT COALESCE(
+                    (SELECT list_id FROM tasks WHERE owner = ?1 AND id = ?2),
+                    (SELECT list_id FROM tombstones WHERE owner = ?1 AND id = ?2))",
+                params![owner, id],
+                |row| row.get(0),
+            )?,
+            RecordType::List | RecordType::Tag => None,
+        };
+        conn.execute(
+            "INSERT OR REPLACE INTO tombstones
+                (owner, id, record_type, deleted_at, seq, recorded_at, list_id)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                owner,
+                id,
+                type_str,
+                deleted_at,
+                next_seq(conn)?,
+                self.clock.now(),
+                list_id
+            ],
+        )?;
+
+        // Delete the actual record
+        match record_type {
             RecordType::Task => {
-                conn.execute("DELETE FROM tasks WHERE id = ?1", params![id])?;
+                unindex_task(conn, owner, id)?;
+                // Foreign keys are off while syncing, so links aren't cascaded
+                conn.execute(
+                    "DELETE FROM task_tags WHERE owner = ?1 AND task_id = ?2",
+                    params![owner, id],
+                )?;
+                conn.execute(
+                    "DELETE FROM tasks WHERE owner = ?1 AND id = ?2",
+                    params![owner, id],
+                )?;
             }
             RecordType::List => {
                 // Don't delete inbox
                 conn.execute(
-                    "DELETE FROM lists WHERE id = ?1 AND is_inbox = 0",
-                    params![id],
+                    "DELETE FROM lists WHERE owner = ?1 AND id = ?2 AND is_inbox = 0",
+                    params![owner, id],
                 )?;
             }
             RecordType::Tag => {
-                conn.execute("DELETE FROM tags WHERE id = ?1", params![id])?;
+                // Tasks lose the tag, and are sent to clients again without it
+                let tagged: Vec<String> = conn
+                    .prepare(
+                        "DELETE FROM task_tags WHERE owner = ?1 AND tag_id = ?2 RETURNING task_id",
+                    )?
+                    .query_map(params![owner, id], |row| row.get(0))?
+                    .collect::<Result<_, _>>()?;
+                for task_id in &tagged {
+                    touch_task(conn, owner, task_id)?;
+                }
+                conn.execute(
+                    "DELETE FROM tags WHERE owner = ?1 AND id = ?2",
+                    params![owner, id],
+                )?;
             }
             RecordType::TaskTag => {
                 // id is task_id for task_tag tombstones
-                conn.execute("DELETE FROM task_tags WHERE task_id = ?1", params![id])?;
+                conn.execute(
+                    "DELETE FROM task_tags WHERE owner = ?1 AND task_id = ?2",
+                    params![owner, id],
+                )?;
+                self.set_tags_updated_at(conn, owner, id, deleted_at)?;
+                touch_task(conn, owner, id)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sequence number up to which a device has acknowledged changes by
+    /// resuming its sync from `since`
+    pub fn device_ack(&self, owner: &str, device_id: &str, since: SyncPoint) -> Result<i64> {
+        match since {
+            SyncPoint::Sequence { after, .. } => Ok(after),
+            // Legacy clients send back the server_time of their previous sync
+            // (possibly reformatted), which acknowledges everything that sync
+            // returned
+            SyncPoint::Timestamp(ts) => {
+                let conn = self.conn.lock().unwrap();
+                let device = conn
+                    .query_row(
+                        "SELECT last_sync, sent_seq, acked_seq
+                         FROM device_sync WHERE owner = ?1 AND device_id = ?2",
+                        params![owner, device_id],
+                        |row| {
+                            Ok((
+                                row.get::<_, String>(0)?,
+                                row.get::<_, i64>(1)?,
+                                row.get::<_, i64>(2)?,
+                            ))
+                        },
+                    )
+                    .optional()?;
+                let acks_last_sync = |last_sync: &str| {
+                    clock::parse(ts)
+                        .zip(clock::parse(last_sync))
+                        .is_some_and(|(ts, last_sync)| ts >= last_sync)
+                };
+                Ok(match device {
+                    Some((last_sync, sent, _)) if acks_last_sync(&last_sync) => sent,
+                    Some((_, _, acked)) => acked,
+                    None => 0,
+                })
+            }
+        }
+    }
+
+    /// Highest sequence number of a purged tombstone in a user's data;
+    /// devices that haven't synced past it may have missed deletions
+    pub fn purge_horizon(&self, owner: &str) -> Result<i64> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            "SELECT COALESCE((SELECT purged_seq FROM sync_horizon WHERE owner = ?1), 0)",
+            params![owner],
+            |row| row.get(0),
+        )
+        .map_err(Into::into)
+    }
+
+    /// Whether a device has been flagged to start over with a full resync
+    pub fn resync_required(&self, owner: &str, device_id: &str) -> Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let required = conn
+            .query_row(
+                "SELECT resync_required FROM device_sync WHERE owner = ?1 AND device_id = ?2",
+                params![owner, device_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(required.unwrap_or(false))
+    }
+
+    /// Record a device's sync: the time, the sequence number it acknowledged,
+    /// the sequence number it was sent changes up to, and how it connected
+    pub fn update_device_sync(
+        &self,
+        owner: &str,
+        device_id: &str,
+        timestamp: &str,
+        acked_seq: i64,
+        sent_seq: i64,
+        contact: &DeviceContact,
+    ) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO device_sync (owner, device_id, last_sync, acked_seq, sent_seq,
+                first_seen, token_name, user_agent, client_version, last_ip)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?3, ?6, ?7, ?8, ?9)
+             ON CONFLICT (owner, device_id) DO UPDATE SET
+                last_sync = excluded.last_sync,
+                acked_seq = excluded.acked_seq,
+                sent_seq = excluded.sent_seq,
+                token_name = excluded.token_name,
+                user_agent = excluded.user_agent,
+                client_version = excluded.client_version,
+                last_ip = excluded.last_ip",
+            params![
+                owner,
+                device_id,
+                timestamp,
+                acked_seq,
+                sent_seq,
+                contact.token_name,
+                contact.user_agent,
+                contact.client_version,
+                contact.ip
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Clear a device's pending resync once it has been sent every page
+    pub fn finish_resync(&self, owner: &str, device_id: &str) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE device_sync SET resync_required = 0 WHERE owner = ?1 AND device_id = ?2",
+            params![owner, device_id],
+        )?;
+        Ok(())
+    }
+
+    /// Registered devices, optionally only a single user's
+    pub fn list_devices(&self, owner: Option<&str>) -> Result<Vec<Device>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT device_id, owner, token_name, first_seen, last_sync, last_ip,
+                user_agent, client_version, resync_required
+             FROM device_sync WHERE ?1 IS NULL OR owner = ?1
+             ORDER BY owner, last_sync DESC",
+        )?;
+        let devices = stmt.query_map(params![owner], |row| {
+            Ok(Device {
+                device_id: row.get(0)?,
+                user: row.get(1)?,
+                token_name: row.get(2)?,
+                first_seen: row.get(3)?,
+                last_sync: row.get(4)?,
+                last_ip: row.get(5)?,
+                user_agent: row.get(6)?,
+                client_version: row.get(7)?,
+                resync_pending: row.get(8)?,
+            })
+        })?;
+
+        devices.collect::<Result<Vec<_>, _>>().map_err(Into::into)
+    }
+
+    /// Remove a device from the registry, in one user's account or any.
+    /// Returns how many were removed.
+    pub fn forget_device(&self, device_id: &str, owner: Option<&str>) -> Result<usize> {
+        let conn = self.conn.lock().unwrap();
+        let removed = conn.execute(
+            "DELETE FROM device_sync WHERE device_id = ?1 AND (?2 IS NULL OR owner = ?2)",
+            params![device_id, owner],
+        )?;
+        Ok(removed)
+    }
+
+    /// Flag a device to be sent a full resync on its next sync. Returns how
+    /// many were flagged.
+    pub fn reset_device(&self, device_id: &str, owner: Option<&str>) -> Result<usize> {
+        let conn = self.conn.lock().unwrap();
+        let flagged = conn.execute(
+            "UPDATE device_sync SET resync_required = 1
+             WHERE device_id = ?1 AND (?2 IS NULL OR owner = ?2)",
+            params![device_id, owner],
+        )?;
+        Ok(flagged)
+    }
+
+    /// Add requests made with tokens since the last write, by token name
+    pub fn record_token_usage(&self, usage: &HashMap<String, TokenUsage>) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        let tx = conn.unchecked_transaction()?;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT INTO token_usage (name, last_used, request_count) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (name) DO UPDATE SET
+                    last_used = MAX(last_used, excluded.last_used),
+                    request_count = request_count + excluded.request_count",
+            )?;
+            for (name, used) in usage {
+                stmt.execute(params![name, &used.last_used, used.request_count])?;
             }
         }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Usage of every token that has been used, by token name
+    pub fn token_usage(&self) -> Result<HashMap<String, TokenUsage>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT name, last_used, request_count FROM token_usage")?;
+        let usage = stmt.query_map([], |row| {
+            Ok((
+                row.get(0)?,
+                TokenUsage {
+                    last_used: row.get(1)?,
+                    request_count: row.get(2)?,
+                },
+            ))
+        })?;
 
+        usage.collect::<Result<_, _>>().map_err(Into::into)
+    }
+
+    /// Drop the usage record of a revoked token
+    pub fn forget_token_usage(&self, name: &str) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute("DELETE FROM token_usage WHERE name = ?1", params![name])?;
         Ok(())
     }
 
-    /// Update device sync timestamp
-    pub fn update_device_sync(&self, device_id: &str, timestamp: &str) -> Result<()> {
+    /// Save the authentication failures of a client IP
+    pub fn save_auth_failures(&self, ip: &str, failures: &AuthFailures) -> Result<()> {
         let conn = self.conn.lock().unwrap();
         conn.execute(
-            "INSERT OR REPLACE INTO device_sync (device_id, last_sync) VALUES (?1, ?2)",
-            params![device_id, timestamp],
+            "INSERT OR REPLACE INTO auth_failures (ip, failures, last_failure, banned_until)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![
+                ip,
+                failures.failures,
+                clock::format(failures.last_failure),
+                failures.banned_until.map(clock::format)
+            ],
         )?;
         Ok(())
     }
+
+    /// Drop the saved failures of a client IP
+    pub fn clear_auth_failures(&self, ip: &str) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute("DELETE FROM auth_failures WHERE ip = ?1", params![ip])?;
+        Ok(())
+    }
+
+    /// Saved authentication failures that still matter: IPs that are banned
+    /// or failed since `forget_before`. Older ones are deleted.
+    pub fn load_auth_failures(
+        &self,
+        forget_before: DateTime<Utc>,
+    ) -> Result<Vec<(String, AuthFailures)>> {
+        let conn = self.conn.lock().unwrap();
+        let cutoff = clock::format(forget_before);
+        conn.execute(
+            "DELETE FROM auth_failures
+             WHERE last_failure < ?1 AND (banned_until IS NULL OR banned_until < ?1)",
+            params![cutoff],
+        )?;
+
+        let mut stmt =
+            conn.prepare("SELECT ip, failures, last_failure, banned_until FROM auth_failures")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, u32>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, Option<String>>(3)?,
+            ))
+        })?;
+
+        let mut saved = Vec::new();
+        for row in rows {
+            let (ip, failures, last_failure, banned_until) = row?;
+            let Some(last_failure) = clock::parse(&last_failure) else {
+                continue;
+            };
+            saved.push((
+                ip,
+                AuthFailures {
+                    failures,
+                    last_failure,
+                    banned_until: banned_until.as_deref().and_then(clock::parse),
+                },
+            ));
+        }
+        Ok(saved)
+    }
+
+    /// Delete tombstones that every device of their user has synced past,
+    /// and any recorded longer than `retention` ago. A user with no devices
+    /// has acknowledged nothing. Returns how many were deleted.
+    pub fn purge_tombstones(&self, retention: Option<Duration>) -> Result<usize> {
+        let conn = self.conn.lock().unwrap();
+        let cutoff = retention.map(|r| clock::format(Utc::now() - r));
+
+        let tx = conn.unchecked_transaction()?;
+
+        // Users without any known device may still have one that hasn't
+        // synced yet, so only the retention cutoff applies to them
+        let purged = {
+            let mut stmt = tx.prepare(
+                "DELETE FROM tombstones
+                 WHERE seq <= (SELECT MIN(acked_seq) FROM device_sync d WHERE d.owner = tombstones.owner)
+                    OR recorded_at < ?1
+                 RETURNING owner, seq",
+            )?;
+            stmt.query_map(params![cutoff], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?
+        };
+
+        let mut horizons = BTreeMap::new();
+        for (owner, seq) in &purged {
+            let horizon = horizons.entry(owner.as_str()).or_insert(0);
+            *horizon = (*horizon).max(*seq);
+        }
+        for (owner, seq) in horizons {
+            tx.execute(
+                "INSERT INTO sync_horizon (owner, purged_seq) VALUES (?1, ?2)
+                 ON CONFLICT (owner) DO UPDATE SET purged_seq = MAX(purged_seq, excluded.purged_seq)",
+                params![owner, seq],
+            )?;
+        }
+
+        tx.commit()?;
+        Ok(purged.len())
+    }
+}
+
+/// Whether two versions of a task agree on a field
+fn task_field_eq(field: TaskField, a: &Task, b: &Task) -> bool {
+    match field {
+        TaskField::Title => a.title == b.title,
+        TaskField::Description => a.description == b.description,
+        TaskField::Url => a.url == b.url,
+        TaskField::Priority => a.priority == b.priority,
+        TaskField::Completed => a.completed == b.completed && a.completed_at == b.completed_at,
+        TaskField::DueDate => a.due_date == b.due_date,
+        TaskField::ListId => a.list_id == b.list_id,
+        TaskField::TagIds => {
+            let mut a_tags: Vec<_> = a.tag_ids.iter().collect();
+            let mut b_tags: Vec<_> = b.tag_ids.iter().collect();
+            a_tags.sort();
+            b_tags.sort();
+            a_tags == b_tags
+        }
+    }
+}
+
+/// Copy one field from `from` into `to`
+fn copy_task_field(field: TaskField, from: &Task, to: &mut Task) {
+    match field {
+        TaskField::Title => to.title = from.title.clone(),
+        TaskField::Description => to.description = from.description.clone(),
+        TaskField::Url => to.url = from.url.clone(),
+        TaskField::Priority => to.priority = from.priority,
+        TaskField::Completed => {
+            to.completed = from.completed;
+            to.completed_at = from.completed_at.clone();
+        }
+        TaskField::DueDate => to.due_date = from.due_date.clone(),
+        TaskField::ListId => to.list_id = from.list_id.clone(),
+        TaskField::TagIds => to.tag_ids = from.tag_ids.clone(),
+    }
+}
+
+/// Whether a record was deleted on the server
+fn has_tombstone(
+    conn: &Connection,
+    owner: &str,
+    id: &str,
+    record_type: RecordType,
+) -> Result<bool> {
+    conn.query_row(
+        "SELECT EXISTS(SELECT 1 FROM tombstones WHERE owner = ?1 AND id = ?2 AND record_type = ?3)",
+        params![owner, id, record_type_str(record_type)],
+        |row| row.get(0),
+    )
+    .map_err(Into::into)
+}
+
+/// Database representation of a record type
+fn record_type_str(record_type: RecordType) -> &'static str {
+    match record_type {
+        RecordType::Task => "task",
+        RecordType::List => "list",
+        RecordType::Tag => "tag",
+        RecordType::TaskTag => "task_tag",
+    }
+}
+
+/// Allocate the next server change sequence number
+fn next_seq(conn: &Connection) -> Result<i64> {
+    conn.query_row(
+        "UPDATE sync_sequence SET value = value + 1 WHERE id = 1 RETURNING value",
+        [],
+        |row| row.get(0),
+    )
+    .map_err(Into::into)
+}
+
+/// Replace a task's entry in the full-text search index
+fn index_task(conn: &Connection, owner: &str, task_id: &str) -> Result<()> {
+    unindex_task(conn, owner, task_id)?;
+    conn.execute(
+        "INSERT INTO task_search (owner, id, title, description)
+         SELECT owner, id, title, COALESCE(description, '') FROM tasks WHERE owner = ?1 AND id = ?2",
+        params![owner, task_id],
+    )?;
+    Ok(())
+}
+
+/// Remove a task from the full-text search index
+fn unindex_task(conn: &Connection, owner: &str, task_id: &str) -> Result<()> {
+    conn.execute(
+        "DELETE FROM task_search WHERE owner = ?1 AND id = ?2",
+        params![owner, task_id],
+    )?;
+    Ok(())
+}
+
+/// Give a task a new sequence number so it is sent to clients again
+fn touch_task(conn: &Connection, owner: &str, task_id: &str) -> Result<()> {
+    conn.execute(
+        "UPDATE tasks SET seq = ?3 WHERE owner = ?1 AND id = ?2",
+        params![owner, task_id, next_seq(conn)?],
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::{SearchQuery, TaskQuery};
+
+    const OWNER: &str = "alice";
+
+    fn database() -> Database {
+        Database::open(Path::new(":memory:")).unwrap()
+    }
+
+    fn task(id: &str, updated_at: &str) -> Task {
+        Task {
+            id: id.to_string(),
+            title: "Buy milk".to_string(),
+            description: None,
+            url: None,
+            priority: Priority::Medium,
+            completed: false,
+            list_id: "inbox".to_string(),
+            tag_ids: Vec::new(),
+            created_at: "2024-01-01T00:00:00Z".to_string(),
+            updated_at: updated_at.to_string(),
+            completed_at: None,
+            due_date: None,
+            changed_fields: None,
+        }
+    }
+
+    /// A device's edit of some fields of its copy of a task
+    fn edit(copy: &Task, updated_at: &str, change: impl FnOnce(&mut Task)) -> SyncRecord {
+        let before = copy.clone();
+        let mut task = copy.clone();
+        change(&mut task);
+        task.updated_at = updated_at.to_string();
+        task.changed_fields = Some(
+            TaskField::ALL
+                .into_iter()
+                .filter(|&field| !task_field_eq(field, &before, &task))
+                .collect(),
+        );
+        SyncRecord::Task(task)
+    }
+
+    fn sync(db: &Database, records: &[SyncRecord], config: &SyncConfig) -> Vec<SyncConflict> {
+        db.apply_changes(OWNER, records, config, None).unwrap()
+    }
+
+    fn tag(id: &str, name: &str, updated_at: Option<&str>) -> Tag {
+        Tag {
+            id: id.to_string(),
+            name: name.to_string(),
+            color: "#ff0000".to_string(),
+            created_at: "2024-01-01T00:00:00Z".to_string(),
+            updated_at: updated_at.map(str::to_string),
+        }
+    }
+
+    fn link(task_id: &str, tag_id: &str, created_at: &str) -> SyncRecord {
+        SyncRecord::TaskTag(TaskTagLink {
+            task_id: task_id.to_string(),
+            tag_id: tag_id.to_string(),
+            created_at: created_at.to_string(),
+        })
+    }
+
+    fn stored(db: &Database, id: &str) -> Option<Task> {
+        let conn = db.conn.lock().unwrap();
+        db.fetch_task(&conn, OWNER, id).unwrap()
+    }
+
+    /// IDs of the tasks in a page of changes, sorted
+    fn task_ids(changes: &ChangeSet) -> Vec<&str> {
+        let mut ids: Vec<_> = changes
+            .records
+            .iter()
+            .filter_map(|record| match record {
+                SyncRecord::Task(task) => Some(task.id.as_str()),
+                _ => None,
+            })
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    /// All of a user's changes after a sequence number
+    fn changes_since(db: &Database, owner: &str, after: i64) -> ChangeSet {
+        db.get_changes_since(
+            owner,
+            SyncPoint::Sequence { after, until: None },
+            None,
+            None,
+        )
+        .unwrap()
+    }
+
+    fn page(db: &Database, after: i64, until: Option<i64>, limit: usize) -> ChangeSet {
+        db.get_changes_since(
+            OWNER,
+            SyncPoint::Sequence { after, until },
+            Some(limit),
+            None,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn cursors_return_later_writes_whatever_their_timestamps() {
+        let db = database();
+        let config = SyncConfig::default();
+        sync(
+            &db,
+            &[SyncRecord::Task(task("t1", "2024-01-02T00:00:00Z"))],
+            &config,
+        );
+        let first = changes_since(&db, OWNER, 0);
+        assert_eq!(task_ids(&first), ["t1"]);
+
+        // Written later by a device whose clock is behind
+        sync(
+            &db,
+            &[SyncRecord::Task(task("t2", "2024-01-01T00:00:00Z"))],
+            &config,
+        );
+        let second = changes_since(&db, OWNER, first.seq);
+        assert_eq!(task_ids(&second), ["t2"]);
+        assert!(second.seq > first.seq);
+
+        let nothing = changes_since(&db, OWNER, second.seq);
+        assert!(nothing.records.is_empty());
+        assert_eq!(nothing.seq, second.seq);
+    }
+
+    #[test]
+    fn deletions_are_returned_after_the_cursor() {
+        let db = database();
+        let config = SyncConfig::default();
+        sync(
+            &db,
+            &[SyncRecord::Task(task("t1", "2024-01-01T00:00:00Z"))],
+            &config,
+        );
+        let before = changes_since(&db, OWNER, 0);
+
+        sync(
+            &db,
+            &[SyncRecord::Deleted {
+                id: "t1".into(),
+                record_type: RecordType::Task,
+                deleted_at: "2024-01-02T00:00:00Z".into(),
+            }],
+            &config,
+        );
+        let after = changes_since(&db, OWNER, before.seq);
+
+        assert!(matches!(
+            after.records.as_slice(),
+            [SyncRecord::Deleted { id, .. }] if id == "t1"
+        ));
+        // Other accounts don't see it
+        let other = changes_since(&db, "bob", 0);
+        assert!(other.records.is_empty());
+    }
+
+    #[test]
+    fn concurrent_edits_to_different_fields_merge() {
+        let db = database();
+        let config = SyncConfig::default();
+        let base = task("t1", "2024-01-01T00:00:00Z");
+        sync(&db, &[SyncRecord::Task(base.clone())], &config);
+
+        // The later edit arrives first; both apply, whatever the order
+        let title = edit(&base, "2024-01-01T00:00:02Z", |t| {
+            t.title = "Buy oat milk".into()
+        });
+        let priority = edit(&base, "2024-01-01T00:00:01Z", |t| {
+            t.priority = Priority::High
+        });
+        assert!(sync(&db, &[title], &config).is_empty());
+        assert!(sync(&db, &[priority], &config).is_empty());
+
+        let merged = stored(&db, "t1").unwrap();
+        assert_eq!(merged.title, "Buy oat milk");
+        assert_eq!(merged.priority, Priority::High);
+        assert_eq!(merged.updated_at, "2024-01-01T00:00:02.000000Z");
+    }
+
+    #[test]
+    fn same_field_edits_keep_the_newest() {
+        let db = database();
+        let config = SyncConfig::default();
+        let base = task("t1", "2024-01-01T00:00:00Z");
+        sync(&db, &[SyncRecord::Task(base.clone())], &config);
+
+        let later = edit(&base, "2024-01-01T00:00:02Z", |t| t.title = "Later".into());
+        let earlier = edit(&base, "2024-01-01T00:00:01Z", |t| {
+            t.title = "Earlier".into()
+        });
+        assert!(sync(&db, &[later], &config).is_empty());
+        let conflicts = sync(&db, &[earlier], &config);
+
+        assert_eq!(stored(&db, "t1").unwrap().title, "Later");
+        assert_eq!(conflicts.len(), 1);
+        let conflict = &conflicts[0];
+        assert_eq!(conflict.id, "t1");
+        assert_eq!(conflict.reason, ConflictReason::StaleUpdate);
+        assert_eq!(conflict.fields, [TaskField::Title]);
+        assert!(matches!(&conflict.server, Some(SyncRecord::Task(t)) if t.title == "Later"));
+        assert!(matches!(&conflict.client, SyncRecord::Task(t) if t.title == "Earlier"));
+
+        // A newer edit of the same field wins
+        let newest = edit(&base, "2024-01-01T00:00:03Z", |t| t.title = "Newest".into());
+        assert!(sync(&db, &[newest], &config).is_empty());
+        assert_eq!(stored(&db, "t1").unwrap().title, "Newest");
+    }
+
+    #[test]
+    fn partly_stale_edits_keep_their_newer_fields() {
+        let db = database();
+        let config = SyncConfig::default();
+        let base = task("t1", "2024-01-01T00:00:00Z");
+        sync(&db, &[SyncRecord::Task(base.clone())], &config);
+
+        let title = edit(&base, "2024-01-01T00:00:02Z", |t| t.title = "Server".into());
+        let both = edit(&base, "2024-01-01T00:00:01Z", |t| {
+            t.title = "Client".into();
+            t.completed = true;
+            t.completed_at = Some("2024-01-01T00:00:01Z".into());
+        });
+        sync(&db, &[title], &config);
+        let conflicts = sync(&db, &[both], &config);
+
+        let merged = stored(&db, "t1").unwrap();
+        assert_eq!(merged.title, "Server");
+        assert!(merged.completed);
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].reason, ConflictReason::FieldConflict);
+        assert_eq!(conflicts[0].fields, [TaskField::Title]);
+        assert!(matches!(&conflicts[0].server, Some(SyncRecord::Task(t)) if t.completed));
+    }
+
+    #[test]
+    fn stale_list_updates_return_the_server_version() {
+        let db = database();
+        let config = SyncConfig::default();
+        let list = |name: &str, updated_at: &str| List {
+            id: "l1".to_string(),
+            name: name.to_string(),
+            description: None,
+            icon: "📁".to_string(),
+            color: None,
+            is_inbox: false,
+            created_at: "2024-01-01T00:00:00Z".to_string(),
+            updated_at: updated_at.to_string(),
+            sort_order: 0,
+        };
+        sync(
+            &db,
+            &[SyncRecord::List(list("Server", "2024-01-01T00:00:02Z"))],
+            &config,
+        );
+
+        let conflicts = sync(
+            &db,
+            &[SyncRecord::List(list("Client", "2024-01-01T00:00:01Z"))],
+            &config,
+        );
+
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].record_type, RecordType::List);
+        assert_eq!(conflicts[0].reason, ConflictReason::StaleUpdate);
+        assert!(conflicts[0].fields.is_empty());
+        assert!(matches!(&conflicts[0].client, SyncRecord::List(l) if l.name == "Client"));
+        assert!(matches!(&conflicts[0].server, Some(SyncRecord::List(l)) if l.name == "Server"));
+    }
+
+    #[test]
+    fn stale_tag_updates_return_the_server_version() {
+        let db = database();
+        let config = SyncConfig::default();
+        sync(
+            &db,
+            &[SyncRecord::Tag(tag(
+                "g1",
+                "Server",
+                Some("2024-01-01T00:00:02Z"),
+            ))],
+            &config,
+        );
+
+        let conflicts = sync(
+            &db,
+            &[SyncRecord::Tag(tag(
+                "g1",
+                "Client",
+                Some("2024-01-01T00:00:01Z"),
+            ))],
+            &config,
+        );
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].record_type, RecordType::Tag);
+        assert_eq!(conflicts[0].reason, ConflictReason::StaleUpdate);
+        assert!(matches!(&conflicts[0].server, Some(SyncRecord::Tag(t)) if t.name == "Server"));
+
+        // A tag never edited before is ordered by its creation time
+        sync(&db, &[SyncRecord::Tag(tag("g2", "Old", None))], &config);
+        let renamed = sync(
+            &db,
+            &[SyncRecord::Tag(tag(
+                "g2",
+                "New",
+                Some("2024-01-01T00:00:01Z"),
+            ))],
+            &config,
+        );
+        assert!(renamed.is_empty());
+    }
+
+    #[test]
+    fn links_to_deleted_records_conflict() {
+        let db = database();
+        let config = SyncConfig::default();
+        sync(
+            &db,
+            &[
+                SyncRecord::Task(task("t1", "2024-01-01T00:00:00Z")),
+                SyncRecord::Tag(tag("g1", "Errands", None)),
+                SyncRecord::Deleted {
+                    id: "g1".into(),
+                    record_type: RecordType::Tag,
+                    deleted_at: "2024-01-01T00:00:01Z".into(),
+                },
+            ],
+            &config,
+        );
+
+        let conflicts = sync(&db, &[link("t1", "g1", "2024-01-01T00:00:02Z")], &config);
+
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].id, "t1");
+        assert_eq!(conflicts[0].record_type, RecordType::TaskTag);
+        assert_eq!(conflicts[0].reason, ConflictReason::Deleted);
+        assert!(matches!(&conflicts[0].server, Some(SyncRecord::Task(t)) if t.tag_ids.is_empty()));
+    }
+
+    #[test]
+    fn links_older_than_a_tag_replacement_conflict() {
+        let db = database();
+        let config = SyncConfig::default();
+        let base = task("t1", "2024-01-01T00:00:00Z");
+        sync(
+            &db,
+            &[
+                SyncRecord::Task(base.clone()),
+                SyncRecord::Tag(tag("g1", "Errands", None)),
+                SyncRecord::Tag(tag("g2", "Home", None)),
+            ],
+            &config,
+        );
+        // Another device replaced the task's tags
+        sync(
+            &db,
+            &[edit(&base, "2024-01-01T00:00:02Z", |t| {
+                t.tag_ids = vec!["g2".into()]
+            })],
+            &config,
+        );
+
+        let stale = sync(&db, &[link("t1", "g1", "2024-01-01T00:00:01Z")], &config);
+        assert_eq!(stale.len(), 1);
+        assert_eq!(stale[0].reason, ConflictReason::StaleUpdate);
+        assert_eq!(stored(&db, "t1").unwrap().tag_ids, ["g2"]);
+
+        assert!(sync(&db, &[link("t1", "g1", "2024-01-01T00:00:03Z")], &config).is_empty());
+        let mut tags = stored(&db, "t1").unwrap().tag_ids;
+        tags.sort();
+        assert_eq!(tags, ["g1", "g2"]);
+    }
+
+    #[test]
+    fn links_apply_to_tasks_whose_tags_never_changed() {
+        let db = database();
+        let config = SyncConfig::default();
+        sync(
+            &db,
+            &[
+                // Edited after the link was made, but not its tags
+                SyncRecord::Task(task("t1", "2024-01-01T00:00:05Z")),
+                SyncRecord::Tag(tag("g1", "Errands", None)),
+            ],
+            &config,
+        );
+
+        assert!(sync(&db, &[link("t1", "g1", "2024-01-01T00:00:01Z")], &config).is_empty());
+        assert_eq!(stored(&db, "t1").unwrap().tag_ids, ["g1"]);
+    }
+
+    #[test]
+    fn applied_links_make_older_links_stale() {
+        let db = database();
+        let config = SyncConfig::default();
+        sync(
+            &db,
+            &[
+                SyncRecord::Task(task("t1", "2024-01-01T00:00:00Z")),
+                SyncRecord::Tag(tag("g1", "Errands", None)),
+                SyncRecord::Tag(tag("g2", "Home", None)),
+            ],
+            &config,
+        );
+
+        assert!(sync(&db, &[link("t1", "g1", "2024-01-01T00:00:02Z")], &config).is_empty());
+        let stale = sync(&db, &[link("t1", "g2", "2024-01-01T00:00:01Z")], &config);
+        assert_eq!(stale.len(), 1);
+        assert_eq!(stale[0].reason, ConflictReason::StaleUpdate);
+
+        // Unlinking counts as a change to the task's tags too
+        sync(
+            &db,
+            &[SyncRecord::Deleted {
+                id: "t1".into(),
+                record_type: RecordType::TaskTag,
+                deleted_at: "2024-01-01T00:00:04Z".into(),
+            }],
+            &config,
+        );
+        assert!(stored(&db, "t1").unwrap().tag_ids.is_empty());
+        let stale = sync(&db, &[link("t1", "g2", "2024-01-01T00:00:03Z")], &config);
+        assert_eq!(stale.len(), 1);
+        assert!(sync(&db, &[link("t1", "g2", "2024-01-01T00:00:05Z")], &config).is_empty());
+        assert_eq!(stored(&db, "t1").unwrap().tag_ids, ["g2"]);
+    }
+
+    #[test]
+    fn same_field_edits_are_resolved_by_hlc_order() {
+        let db = database();
+        let config = SyncConfig::default();
+        let base = task("t1", "2024-01-01T00:00:00Z");
+        sync(&db, &[SyncRecord::Task(base.clone())], &config);
+
+        // Same millisecond: the logical counter orders the two edits
+        let later = edit(&base, "2024-01-01T00:00:01.000002Z", |t| {
+            t.title = "Later".into()
+        });
+        let earlier = edit(&base, "2024-01-01T00:00:01.000001Z", |t| {
+            t.title = "Earlier".into()
+        });
+        assert!(sync(&db, &[later], &config).is_empty());
+        let conflicts = sync(&db, &[earlier], &config);
+
+        assert_eq!(stored(&db, "t1").unwrap().title, "Later");
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].reason, ConflictReason::StaleUpdate);
+    }
+
+    #[test]
+    fn timestamps_are_stored_in_canonical_form() {
+        let db = database();
+        let config = SyncConfig::default();
+        let base = task("t1", "2024-01-01T02:00:00+02:00");
+        sync(&db, &[SyncRecord::Task(base.clone())], &config);
+        assert_eq!(
+            stored(&db, "t1").unwrap().updated_at,
+            "2024-01-01T00:00:00.000000Z"
+        );
+
+        // The same instant written differently is not newer
+        let same = edit(&base, "2024-01-01T00:00:00Z", |t| t.title = "Same".into());
+        assert_eq!(sync(&db, &[same], &config).len(), 1);
+        let later = edit(&base, "2024-01-01T01:00:01+01:00", |t| {
+            t.title = "Later".into()
+        });
+        assert!(sync(&db, &[later], &config).is_empty());
+        assert_eq!(stored(&db, "t1").unwrap().title, "Later");
+    }
+
+    #[test]
+    fn future_timestamps_are_clamped_to_the_server_clock() {
+        let db = database();
+        let config = SyncConfig::default();
+        let future = (Utc::now() + Duration::days(1)).to_rfc3339();
+
+        assert!(sync(&db, &[SyncRecord::Task(task("t1", &future))], &config).is_empty());
+
+        let stored = stored(&db, "t1").unwrap();
+        let limit = Utc::now() + Duration::seconds(config.max_clock_skew_secs as i64);
+        assert!(clock::parse(&stored.updated_at).unwrap() <= limit);
+        // The server clock stays ahead of what it stored
+        assert!(clock::compare(&db.clock().now(), &stored.updated_at).is_gt());
+    }
+
+    #[test]
+    fn future_timestamps_can_be_rejected() {
+        let db = database();
+        let config = SyncConfig {
+            future_timestamps: FutureTimestampPolicy::Reject,
+            ..SyncConfig::default()
+        };
+        let future = (Utc::now() + Duration::days(1)).to_rfc3339();
+
+        let conflicts = sync(&db, &[SyncRecord::Task(task("t1", &future))], &config);
+
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].reason, ConflictReason::FutureTimestamp);
+        assert!(conflicts[0].server.is_none());
+        assert!(stored(&db, "t1").is_none());
+    }
+
+    #[test]
+    fn pages_split_changes_on_record_boundaries() {
+        let db = database();
+        let config = SyncConfig::default();
+        let tasks: Vec<_> = ["t1", "t2", "t3", "t4", "t5"]
+            .into_iter()
+            .map(|id| SyncRecord::Task(task(id, "2024-01-01T00:00:00Z")))
+            .collect();
+        sync(&db, &tasks, &config);
+
+        let first = page(&db, 0, None, 2);
+        assert_eq!(task_ids(&first), ["t1", "t2"]);
+        assert!(first.has_more);
+        assert_eq!(first.snapshot, 5);
+        assert_eq!(first.seq, 2);
+
+        let second = page(&db, first.seq, Some(first.snapshot), 2);
+        assert_eq!(task_ids(&second), ["t3", "t4"]);
+        assert!(second.has_more);
+
+        // Exactly a page left: it is the last one
+        let last = page(&db, second.seq, Some(second.snapshot), 2);
+        assert_eq!(task_ids(&last), ["t5"]);
+        assert!(!last.has_more);
+        assert_eq!(last.seq, last.snapshot);
+
+        let exact = page(&db, 0, None, 5);
+        assert_eq!(exact.records.len(), 5);
+        assert!(!exact.has_more);
+    }
+
+    #[test]
+    fn changes_made_during_a_paginated_sync_wait_for_the_next_sync() {
+        let db = database();
+        let config = SyncConfig::default();
+        let tasks: Vec<_> = ["t1", "t2", "t3"]
+            .into_iter()
+            .map(|id| SyncRecord::Task(task(id, "2024-01-01T00:00:00Z")))
+            .collect();
+        sync(&db, &tasks, &config);
+
+        let first = page(&db, 0, None, 2);
+        assert_eq!(task_ids(&first), ["t1", "t2"]);
+
+        // Another device adds a task and edits one not sent yet
+        let t3 = edit(
+            &task("t3", "2024-01-01T00:00:00Z"),
+            "2024-01-01T00:00:01Z",
+            |t| t.title = "Edited".into(),
+        );
+        sync(
+            &db,
+            &[SyncRecord::Task(task("t4", "2024-01-01T00:00:01Z")), t3],
+            &config,
+        );
+
+        let rest = page(&db, first.seq, Some(first.snapshot), 2);
+        assert!(task_ids(&rest).is_empty());
+        assert!(!rest.has_more);
+        assert_eq!(rest.seq, first.snapshot);
+
+        let next = page(&db, rest.seq, None, 10);
+        assert_eq!(task_ids(&next), ["t3", "t4"]);
+        assert!(next.records.iter().any(
+            |record| matches!(record, SyncRecord::Task(t) if t.id == "t3" && t.title == "Edited")
+        ));
+    }
+
+    #[test]
+    fn invalid_records_reject_the_whole_batch() {
+        let db = database();
+        let config = SyncConfig::default();
+        let mut invalid = task("t2", "2024-01-01T00:00:00Z");
+        invalid.created_at = "not a timestamp".into();
+
+        let result = db.apply_changes(
+            OWNER,
+            &[
+                SyncRecord::Task(task("t1", "2024-01-01T00:00:00Z")),
+                SyncRecord::Task(invalid),
+            ],
+            &config,
+            None,
+        );
+
+        let Err(ApplyError::Invalid(errors)) = result else {
+            panic!("batch was not rejected: {result:?}");
+        };
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].index, 1);
+        assert_eq!(errors[0].id, "t2");
+        assert!(stored(&db, "t1").is_none());
+        assert!(changes_since(&db, OWNER, 0).records.is_empty());
+    }
+
+    #[test]
+    fn failed_batches_roll_back_earlier_records() {
+        let db = database();
+        let config = SyncConfig::default();
+        // A database error part way through the batch
+        db.conn
+            .lock()
+            .unwrap()
+            .execute_batch(
+                "CREATE TRIGGER fail_t2 BEFORE INSERT ON tasks WHEN NEW.id = 't2'
+                 BEGIN SELECT RAISE(ABORT, 'disk on fire'); END;",
+            )
+            .unwrap();
+
+        let result = db.apply_changes(
+            OWNER,
+            &[
+                SyncRecord::Task(task("t1", "2024-01-01T00:00:00Z")),
+                SyncRecord::Task(task("t2", "2024-01-01T00:00:00Z")),
+            ],
+            &config,
+            None,
+        );
+
+        assert!(matches!(result, Err(ApplyError::Storage(_))));
+        assert!(stored(&db, "t1").is_none());
+        assert!(changes_since(&db, OWNER, 0).records.is_empty());
+        // The connection is usable again afterwards
+        sync(
+            &db,
+            &[SyncRecord::Task(task("t3", "2024-01-01T00:00:00Z"))],
+            &config,
+        );
+        assert!(stored(&db, "t3").is_some());
+    }
+
+    fn contact() -> DeviceContact<'static> {
+        DeviceContact {
+            token_name: "laptop",
+            user_agent: Some("tickit/1.0"),
+            client_version: Some("1.0"),
+            ip: Some("192.0.2.1".into()),
+        }
+    }
+
+    fn delete(db: &Database, id: &str, config: &SyncConfig) -> i64 {
+        sync(
+            db,
+            &[SyncRecord::Deleted {
+                id: id.into(),
+                record_type: RecordType::Task,
+                deleted_at: "2024-01-02T00:00:00Z".into(),
+            }],
+            config,
+        );
+        changes_since(db, OWNER, 0).seq
+    }
+
+    #[test]
+    fn tombstones_are_purged_once_every_device_acknowledges_them() {
+        let db = database();
+        let config = SyncConfig::default();
+        sync(
+            &db,
+            &[
+                SyncRecord::Task(task("t1", "2024-01-01T00:00:00Z")),
+                SyncRecord::Task(task("t2", "2024-01-01T00:00:00Z")),
+            ],
+            &config,
+        );
+        let created = changes_since(&db, OWNER, 0).seq;
+        let deleted = delete(&db, "t1", &config);
+
+        let now = db.clock().now();
+        db.update_device_sync(OWNER, "d1", &now, deleted, deleted, &contact())
+            .unwrap();
+        db.update_device_sync(OWNER, "d2", &now, created, deleted, &contact())
+            .unwrap();
+        assert_eq!(db.purge_tombstones(None).unwrap(), 0);
+        assert_eq!(db.purge_horizon(OWNER).unwrap(), 0);
+
+        db.update_device_sync(OWNER, "d2", &now, deleted, deleted, &contact())
+            .unwrap();
+        assert_eq!(db.purge_tombstones(None).unwrap(), 1);
+        assert_eq!(db.purge_horizon(OWNER).unwrap(), deleted);
+        assert!(changes_since(&db, OWNER, created).records.is_empty());
+        // Other accounts keep their own horizon
+        assert_eq!(db.purge_horizon("bob").unwrap(), 0);
+    }
+
+    #[test]
+    fn expired_tombstones_are_purged_without_acknowledgement() {
+        let db = database();
+        let config = SyncConfig::default();
+        sync(
+            &db,
+            &[SyncRecord::Task(task("t1", "2024-01-01T00:00:00Z"))],
+            &config,
+        );
+        let deleted = delete(&db, "t1", &config);
+        let now = db.clock().now();
+        db.update_device_sync(OWNER, "d1", &now, 0, deleted, &contact())
+            .unwrap();
+
+        let retention = Some(Duration::days(30));
+        assert_eq!(db.purge_tombstones(retention).unwrap(), 0);
+
+        db.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "UPDATE tombstones SET recorded_at = '2024-01-01T00:00:00.000000Z'",
+                [],
+            )
+            .unwrap();
+        assert_eq!(db.purge_tombstones(retention).unwrap(), 1);
+        assert_eq!(db.purge_horizon(OWNER).unwrap(), deleted);
+    }
+
+    #[test]
+    fn unacknowledged_tombstones_survive_a_purge() {
+        let db = database();
+        let config = SyncConfig::default();
+        sync(
+            &db,
+            &[SyncRecord::Task(task("t1", "2024-01-01T00:00:00Z"))],
+            &config,
+        );
+        let deleted = delete(&db, "t1", &config);
+
+        // No device has synced the account yet
+        assert_eq!(db.purge_tombstones(None).unwrap(), 0);
+        assert_eq!(db.purge_tombstones(Some(Duration::days(30))).unwrap(), 0);
+        assert_eq!(db.purge_horizon(OWNER).unwrap(), 0);
+        assert_eq!(changes_since(&db, OWNER, 0).records.len(), 1);
+
+        let now = db.clock().now();
+        db.update_device_sync(OWNER, "d1", &now, deleted, deleted, &contact())
+            .unwrap();
+        assert_eq!(db.purge_tombstones(None).unwrap(), 1);
+    }
+
+    #[test]
+    fn device_acks_follow_the_sync_point() {
+        let db = database();
+        let sequence = SyncPoint::Sequence {
+            after: 7,
+            until: None,
+        };
+        assert_eq!(db.device_ack(OWNER, "d1", sequence).unwrap(), 7);
+
+        db.update_device_sync(OWNER, "d1", "2024-01-02T00:00:00.000000Z", 3, 9, &contact())
+            .unwrap();
+        // Resuming from the previous sync's server_time acknowledges what it sent
+        let resumed = SyncPoint::Timestamp("2024-01-02T00:00:00.000000Z");
+        assert_eq!(db.device_ack(OWNER, "d1", resumed).unwrap(), 9);
+        let older = SyncPoint::Timestamp("2024-01-01T00:00:00.000000Z");
+        assert_eq!(db.device_ack(OWNER, "d1", older).unwrap(), 3);
+        let unknown = SyncPoint::Timestamp("2024-01-01T00:00:00.000000Z");
+        assert_eq!(db.device_ack(OWNER, "d2", unknown).unwrap(), 0);
+    }
+
+    #[test]
+    fn syncs_register_devices_with_their_contact_details() {
+        let db = database();
+        db.update_device_sync(OWNER, "d1", "2024-01-01T00:00:00.000000Z", 0, 0, &contact())
+            .unwrap();
+        let moved = DeviceContact {
+            ip: Some("198.51.100.7".into()),
+            ..contact()
+        };
+        db.update_device_sync(OWNER, "d1", "2024-01-02T00:00:00.000000Z", 0, 0, &moved)
+            .unwrap();
+        db.update_device_sync("bob", "d2", "2024-01-01T00:00:00.000000Z", 0, 0, &contact())
+            .unwrap();
+
+        let devices = db.list_devices(Some(OWNER)).unwrap();
+        assert_eq!(devices.len(), 1);
+        let device = &devices[0];
+        assert_eq!(device.device_id, "d1");
+        assert_eq!(device.token_name.as_deref(), Some("laptop"));
+        assert_eq!(
+            device.first_seen.as_deref(),
+            Some("2024-01-01T00:00:00.000000Z")
+        );
+        assert_eq!(device.last_sync, "2024-01-02T00:00:00.000000Z");
+        assert_eq!(device.last_ip.as_deref(), Some("198.51.100.7"));
+        assert!(!device.resync_pending);
+        assert_eq!(db.list_devices(None).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn reset_devices_resync_until_they_finish_a_sync() {
+        let db = database();
+        let now = db.clock().now();
+        db.update_device_sync(OWNER, "d1", &now, 0, 0, &contact())
+            .unwrap();
+        db.update_device_sync("bob", "d1", &now, 0, 0, &contact())
+            .unwrap();
+
+        assert_eq!(db.reset_device("d1", Some(OWNER)).unwrap(), 1);
+        assert!(db.resync_required(OWNER, "d1").unwrap());
+        assert!(!db.resync_required("bob", "d1").unwrap());
+        assert_eq!(db.reset_device("unknown", None).unwrap(), 0);
+
+        // Syncing a page isn't enough; the last one clears it
+        db.update_device_sync(OWNER, "d1", &now, 0, 0, &contact())
+            .unwrap();
+        assert!(db.resync_required(OWNER, "d1").unwrap());
+        db.finish_resync(OWNER, "d1").unwrap();
+        assert!(!db.resync_required(OWNER, "d1").unwrap());
+    }
+
+    #[test]
+    fn forgotten_devices_leave_the_registry() {
+        let db = database();
+        let now = db.clock().now();
+        db.update_device_sync(OWNER, "d1", &now, 0, 0, &contact())
+            .unwrap();
+        db.update_device_sync("bob", "d1", &now, 0, 0, &contact())
+            .unwrap();
+
+        assert_eq!(db.forget_device("d1", Some("bob")).unwrap(), 1);
+        assert_eq!(db.list_devices(None).unwrap().len(), 1);
+        assert_eq!(db.forget_device("d1", None).unwrap(), 1);
+        assert!(db.list_devices(None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn token_use_is_counted_per_token() {
+        let db = database();
+        let used = |last_used: &str, request_count| TokenUsage {
+            last_used: last_used.to_string(),
+            request_count,
+        };
+        db.record_token_usage(&HashMap::from([
+            ("laptop".to_string(), used("2024-01-02T00:00:00.000000Z", 2)),
+            ("phone".to_string(), used("2024-01-01T00:00:00.000000Z", 1)),
+        ]))
+        .unwrap();
+        db.record_token_usage(&HashMap::from([(
+            "laptop".to_string(),
+            used("2024-01-03T00:00:00.000000Z", 3),
+        )]))
+        .unwrap();
+
+        let usage = db.token_usage().unwrap();
+        assert_eq!(usage["laptop"].request_count, 5);
+        assert_eq!(usage["laptop"].last_used, "2024-01-03T00:00:00.000000Z");
+        assert_eq!(usage["phone"].request_count, 1);
+
+        db.forget_token_usage("laptop").unwrap();
+        let usage = db.token_usage().unwrap();
+        assert!(!usage.contains_key("laptop"));
+        assert!(usage.contains_key("phone"));
+    }
+
+    /// Indexes of the records a token limited to `lists` was denied
+    fn denied(db: &Database, records: &[SyncRecord], lists: &[&str]) -> Vec<usize> {
+        let lists: Vec<String> = lists.iter().map(|l| l.to_string()).collect();
+        match db.apply_changes(OWNER, records, &SyncConfig::default(), Some(&lists)) {
+            Ok(_) => Vec::new(),
+            Err(ApplyError::Forbidden(errors)) => errors.iter().map(|e| e.index).collect(),
+            Err(e) => panic!("unexpected error: {e}"),
+        }
+    }
+
+    fn in_list(id: &str, list_id: &str) -> Task {
+        Task {
+            list_id: list_id.to_string(),
+            ..task(id, "2024-01-01T00:00:00Z")
+        }
+    }
+
+    #[test]
+    fn list_tokens_only_change_their_lists() {
+        let db = database();
+        let config = SyncConfig::default();
+        sync(
+            &db,
+            &[
+                SyncRecord::Task(in_list("home", "l1")),
+                SyncRecord::Task(in_list("work", "l2")),
+            ],
+            &config,
+        );
+
+        assert!(denied(&db, &[SyncRecord::Task(in_list("allowed", "l1"))], &["l1"]).is_empty());
+        // A task in another list, moved out of or into the allowed list
+        assert_eq!(
+            denied(
+                &db,
+                &[
+                    SyncRecord::Task(in_list("other", "l2")),
+                    SyncRecord::Task(in_list("home", "l2")),
+                    SyncRecord::Task(in_list("work", "l1")),
+                ],
+                &["l1"],
+            ),
+            [0, 1, 2]
+        );
+        assert_eq!(
+            denied(&db, &[SyncRecord::Tag(tag("g1", "Urgent", None))], &["l1"]),
+            [0]
+        );
+        // Links follow their task, which may arrive in the same batch
+        assert_eq!(
+            denied(
+                &db,
+                &[
+                    SyncRecord::Task(in_list("new", "l1")),
+                    link("new", "g1", "2024-01-01T00:00:00Z"),
+                    link("work", "g1", "2024-01-01T00:00:00Z"),
+                ],
+                &["l1"],
+            ),
+            [2]
+        );
+        // Nothing from a denied batch was written
+        assert!(stored(&db, "new").is_none());
+        assert_eq!(stored(&db, "home").unwrap().list_id, "l1");
+    }
+
+    #[test]
+    fn list_tokens_only_see_their_lists() {
+        let db = database();
+        let config = SyncConfig::default();
+        sync(
+            &db,
+            &[
+                SyncRecord::Task(in_list("home", "l1")),
+                SyncRecord::Task(in_list("work", "l2")),
+            ],
+            &config,
+        );
+
+        let lists = ["l1".to_string()];
+        let since = SyncPoint::Sequence {
+            after: 0,
+            until: None,
+        };
+        let changes = db
+            .get_changes_since(OWNER, since, None, Some(&lists))
+            .unwrap();
+        assert_eq!(task_ids(&changes), ["home"]);
+    }
+
+    #[test]
+    fn auth_failures_survive_a_restart_until_they_expire() {
+        let db = database();
+        let now = Utc::now();
+        let failures = |last_failure, banned_until| AuthFailures {
+            failures: 3,
+            last_failure,
+            banned_until,
+        };
+        db.save_auth_failures("192.0.2.1", &failures(now, Some(now + Duration::hours(1))))
+            .unwrap();
+        db.save_auth_failures("192.0.2.2", &failures(now, None))
+            .unwrap();
+        db.save_auth_failures("192.0.2.3", &failures(now - Duration::days(2), None))
+            .unwrap();
+        db.save_auth_failures("192.0.2.4", &failures(now, None))
+            .unwrap();
+        db.clear_auth_failures("192.0.2.4").unwrap();
+
+        let mut saved = db.load_auth_failures(now - Duration::days(1)).unwrap();
+        saved.sort_by(|a, b| a.0.cmp(&b.0));
+        let ips: Vec<&str> = saved.iter().map(|(ip, _)| ip.as_str()).collect();
+        assert_eq!(ips, ["192.0.2.1", "192.0.2.2"]);
+        assert_eq!(saved[0].1.failures, 3);
+        assert!(saved[0].1.banned_until.is_some());
+        assert!(saved[1].1.banned_until.is_none());
+    }
+
+    /// IDs of a page of tasks matching a query, and the next page's cursor
+    fn query(db: &Database, query: TaskQuery) -> (Vec<String>, Option<String>) {
+        let filter = query.parse().unwrap();
+        let (tasks, cursor) = db.query_tasks(OWNER, &filter, None).unwrap();
+        (tasks.into_iter().map(|t| t.id).collect(), cursor)
+    }
+
+    /// Tasks with a spread of priorities, due dates and completion
+    fn query_fixture(db: &Database) {
+        let config = SyncConfig::default();
+        let task = |id: &str, priority, due: Option<&str>, completed: bool| Task {
+            priority,
+            tag_ids: if ["t1", "t4"].contains(&id) {
+                vec!["g1".to_string()]
+            } else {
+                Vec::new()
+            },
+            due_date: due.map(str::to_string),
+            completed,
+            completed_at: completed.then(|| "2024-01-05T00:00:00Z".to_string()),
+            title: format!("Task {id}"),
+            ..task(id, "2024-01-01T00:00:00Z")
+        };
+        sync(
+            db,
+            &[
+                SyncRecord::Task(task("t1", Priority::Low, Some("2024-02-01"), false)),
+                SyncRecord::Task(task("t2", Priority::Urgent, None, false)),
+                SyncRecord::Task(task("t3", Priority::High, Some("2024-01-15"), true)),
+                SyncRecord::Task(task("t4", Priority::Medium, Some("2024-03-01"), false)),
+                SyncRecord::Tag(tag("g1", "Urgent", None)),
+            ],
+            &config,
+        );
+    }
+
+    #[test]
+    fn task_queries_filter_on_every_field() {
+        let db = database();
+        query_fixture(&db);
+        let q = |filter: TaskQuery| query(&db, filter).0;
+
+        assert_eq!(
+            q(TaskQuery {
+                priority: Some("high,urgent".into()),
+                ..TaskQuery::default()
+            }),
+            ["t2", "t3"]
+        );
+        assert_eq!(
+            q(TaskQuery {
+                completed: Some(false),
+                tag_ids: Some("g1".into()),
+                ..TaskQuery::default()
+            }),
+            ["t1", "t4"]
+        );
+        assert_eq!(
+            q(TaskQuery {
+                due_after: Some("2024-01-15".into()),
+                due_before: Some("2024-03-01".into()),
+                ..TaskQuery::default()
+            }),
+            ["t1", "t3"]
+        );
+        assert_eq!(
+            q(TaskQuery {
+                q: Some("task t4".into()),
+                ..TaskQuery::default()
+            }),
+            ["t4"]
+        );
+        assert!(
+            q(TaskQuery {
+                list_id: Some("elsewhere".into()),
+                ..TaskQuery::default()
+            })
+            .is_empty()
+        );
+    }
+
+    #[test]
+    fn task_queries_sort_with_missing_values_last() {
+        let db = database();
+        query_fixture(&db);
+        let sorted = |sort: &str| {
+            query(
+                &db,
+                TaskQuery {
+                    sort: Some(sort.into()),
+                    ..TaskQuery::default()
+                },
+            )
+            .0
+        };
+
+        assert_eq!(sorted("due_date"), ["t3", "t1", "t4", "t2"]);
+        assert_eq!(sorted("-due_date"), ["t4", "t1", "t3", "t2"]);
+        assert_eq!(sorted("-priority"), ["t2", "t3", "t4", "t1"]);
+        assert_eq!(sorted("title"), ["t1", "t2", "t3", "t4"]);
+    }
+
+    #[test]
+    fn task_pages_continue_after_the_last_task_sent() {
+        let db = database();
+        query_fixture(&db);
+        let page = |cursor: Option<String>| {
+            query(
+                &db,
+                TaskQuery {
+                    sort: Some("-priority".into()),
+                    limit: Some(2),
+                    cursor,
+                    ..TaskQuery::default()
+                },
+            )
+        };
+
+        let (first, cursor) = page(None);
+        assert_eq!(first, ["t2", "t3"]);
+        // A task sorting before the cursor doesn't shift the next page
+        sync(
+            &db,
+            &[SyncRecord::Task(Task {
+                priority: Priority::Urgent,
+                ..task("t0", "2024-01-01T00:00:00Z")
+            })],
+            &SyncConfig::default(),
+        );
+        let (second, cursor) = page(cursor);
+        assert_eq!(second, ["t4", "t1"]);
+        assert!(cursor.is_none());
+    }
+
+    /// IDs and marked titles of a search's results, best match first
+    fn search(db: &Database, owner: &str, q: &str) -> Vec<(String, String)> {
+        let filter = SearchQuery {
+            q: Some(q.to_string()),
+            ..SearchQuery::default()
+        }
+        .parse()
+        .unwrap();
+        let marks = Highlight {
+            start: "[",
+            end: "]",
+            html: false,
+        };
+        db.search_tasks(owner, &filter, None, marks)
+            .unwrap()
+            .into_iter()
+            .map(|result| (result.task.id, result.title))
+            .collect()
+    }
+
+    #[test]
+    fn search_ranks_title_matches_first() {
+        let db = database();
+        let config = SyncConfig::default();
+        sync(
+            &db,
+            &[
+                SyncRecord::Task(Task {
+                    title: "Call the bank".into(),
+                    description: Some("Ask about the milk money".into()),
+                    ..task("t1", "2024-01-01T00:00:00Z")
+                }),
+                SyncRecord::Task(Task {
+                    title: "Buy milk".into(),
+                    ..task("t2", "2024-01-01T00:00:00Z")
+                }),
+                SyncRecord::Task(Task {
+                    title: "Walk the dog".into(),
+                    ..task("t3", "2024-01-01T00:00:00Z")
+                }),
+            ],
+            &config,
+        );
+
+        let results = search(&db, OWNER, "mil");
+        let ids: Vec<&str> = results.iter().map(|(id, _)| id.as_str()).collect();
+        assert_eq!(ids, ["t2", "t1"]);
+        assert_eq!(results[0].1, "Buy [milk]");
+        // Operators are searched for as words
+        assert!(search(&db, OWNER, "milk OR dog").is_empty());
+        assert!(search(&db, "bob", "milk").is_empty());
+    }
+
+    #[test]
+    fn search_follows_edits_and_deletions() {
+        let db = database();
+        let config = SyncConfig::default();
+        let base = Task {
+            title: "Buy milk".into(),
+            ..task("t1", "2024-01-01T00:00:00Z")
+        };
+        sync(&db, &[SyncRecord::Task(base.clone())], &config);
+
+        sync(
+            &db,
+            &[edit(&base, "2024-01-02T00:00:00Z", |t| {
+                t.title = "Buy bread".into()
+            })],
+            &config,
+        );
+        assert!(search(&db, OWNER, "milk").is_empty());
+        assert_eq!(search(&db, OWNER, "bread").len(), 1);
+
+        sync(
+            &db,
+            &[SyncRecord::Deleted {
+                id: "t1".into(),
+                record_type: RecordType::Task,
+                deleted_at: "2024-01-03T00:00:00Z".into(),
+            }],
+            &config,
+        );
+        assert!(search(&db, OWNER, "bread").is_empty());
+    }
+
+    #[test]
+    fn completion_filters_compare_instants_whatever_the_offset() {
+        let db = database();
+        let mut done = task("t1", "2024-01-01T00:00:00Z");
+        done.completed = true;
+        // 20:00Z the day before
+        done.completed_at = Some("2026-10-16T01:00:00+05:00".into());
+        done.due_date = Some("2026-10-16T01:00:00+05:00".into());
+        sync(&db, &[SyncRecord::Task(done)], &SyncConfig::default());
+
+        let stored = db.task(OWNER, "t1").unwrap().unwrap();
+        assert_eq!(
+            stored.completed_at.as_deref(),
+            Some("2026-10-15T20:00:00.000000Z")
+        );
+        assert_eq!(
+            stored.due_date.as_deref(),
+            Some("2026-10-15T20:00:00.000000Z")
+        );
+
+        let matching = |query: query::TaskQuery| {
+            let (tasks, _) = db
+                .query_tasks(OWNER, &query.parse().unwrap(), None)
+                .unwrap();
+            tasks.len()
+        };
+        assert_eq!(
+            matching(query::TaskQuery {
+                completed_after: Some("2026-10-15T21:00:00Z".into()),
+                ..Default::default()
+            }),
+            0
+        );
+        assert_eq!(
+            matching(query::TaskQuery {
+                completed_before: Some("2026-10-15T21:00:00Z".into()),
+                ..Default::default()
+            }),
+            1
+        );
+    }
+
+    #[test]
+    fn search_highlights_escape_task_markup() {
+        let db = database();
+        let mut t = task("t1", "2024-01-01T00:00:00Z");
+        t.title = "<img src=x onerror=alert(1)> milk".into();
+        t.description = Some("Buy \"milk\" & <b>bread</b>".into());
+        sync(&db, &[SyncRecord::Task(t)], &SyncConfig::default());
+
+        let filter = query::SearchQuery {
+            q: Some("milk".into()),
+            ..Default::default()
+        }
+        .parse()
+        .unwrap();
+        let results = db
+            .search_tasks(OWNER, &filter, None, Highlight::HTML)
+            .unwrap();
+
+        assert_eq!(results.len(), 1);
+        assert_eq!(
+            results[0].title,
+            "&lt;img src=x onerror=alert(1)&gt; <mark>milk</mark>"
+        );
+        assert_eq!(
+            results[0].snippet.as_deref(),
+            Some("Buy &quot;<mark>milk</mark>&quot; &amp; &lt;b&gt;bread&lt;/b&gt;")
+        );
+    }
+
+    #[test]
+    fn list_tokens_only_delete_tasks_in_their_lists() {
+        let db = database();
+        let config = SyncConfig::default();
+        let mut other = task("t2", "2024-01-01T00:00:00Z");
+        other.list_id = "work".into();
+        sync(
+            &db,
+            &[
+                SyncRecord::Task(task("t1", "2024-01-01T00:00:00Z")),
+                SyncRecord::Task(other),
+            ],
+            &config,
+        );
+
+        let lists = ["inbox".to_string()];
+        let delete = |id: &str| {
+            db.apply_changes(
+                OWNER,
+                &[SyncRecord::Deleted {
+                    id: id.into(),
+                    record_type: RecordType::Task,
+                    deleted_at: "2024-01-02T00:00:00Z".into(),
+                }],
+                &config,
+                Some(&lists),
+            )
+        };
+
+        // Outside the token's lists, or not synced to the server yet
+        for id in ["t2", "t3"] {
+            assert!(matches!(delete(id), Err(ApplyError::Forbidden(_))), "{id}");
+        }
+        // A deletion can be sent again once the task is gone
+        assert!(delete("t1").unwrap().is_empty());
+        assert!(delete("t1").unwrap().is_empty());
+        assert!(db.task(OWNER, "t2").unwrap().is_some());
+    }
 }
@@ -0,0 +1,303 @@
+//! Errors returned by the HTTP API
+//!
+//! Every error response has the same JSON shape: a human-readable `error`, a
+//! stable machine-readable `code`, and for batches the offending records:
+//!
+//! ```json
+//! { "code": "validation", "error": "...", "errors": [{ "index": 0, ... }] }
+//! ```
+
+use axum::{
+    Json,
+    extract::rejection::{JsonRejection, PathRejection, QueryRejection},
+    http::{StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use schemars::JsonSchema;
+use serde::Serialize;
+
+use crate::db::ApplyError;
+use crate::models::RecordError;
+use crate::ratelimit::Rejection;
+use crate::validation::PayloadError;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ApiError {
+    /// The request, or records in it, are malformed (400)
+    #[error("{message}")]
+    Validation {
+        message: String,
+        errors: Vec<RecordError>,
+    },
+
+    /// Missing, invalid or expired credentials (401)
+    #[error("{0}")]
+    Unauthorized(String),
+
+    /// The token may not do this (403)
+    #[error("{message}")]
+    Forbidden {
+        message: String,
+        errors: Vec<RecordError>,
+    },
+
+    /// The requested record doesn't exist (404)
+    #[error("{0}")]
+    NotFound(String),
+
+    /// The request conflicts with the record's current state (409)
+    #[error("{0}")]
+    Conflict(String),
+
+    /// The request exceeds a size limit (413)
+    #[error("{0}")]
+    PayloadTooLarge(String),
+
+    /// The request body isn't JSON (415)
+    #[error("{0}")]
+    UnsupportedMediaType(String),
+
+    /// The client is sending too many requests, or is banned (429)
+    #[error("{message}")]
+    RateLimited {
+        message: String,
+        retry_after_secs: u64,
+    },
+
+    /// The server failed to read or write its data (500); details are logged,
+    /// not sent to the client
+    #[error("Internal server error")]
+    Storage(#[from] anyhow::Error),
+}
+
+impl ApiError {
+    pub fn forbidden(message: impl Into<String>) -> Self {
+        Self::Forbidden {
+            message: message.into(),
+            errors: Vec::new(),
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::Validation { .. } => StatusCode::BAD_REQUEST,
+            Self::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            Self::Forbidden { .. } => StatusCode::FORBIDDEN,
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::Conflict(_) => StatusCode::CONFLICT,
+            Self::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Self::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            Self::Storage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Stable identifier of the kind of error, for clients to match on
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Validation { .. } => "validation",
+            Self::Unauthorized(_) => "unauthorized",
+            Self::Forbidden { .. } => "forbidden",
+            Self::NotFound(_) => "not_found",
+            Self::Conflict(_) => "conflict",
+            Self::PayloadTooLarge(_) => "payload_too_large",
+            Self::UnsupportedMediaType(_) => "unsupported_media_type",
+            Self::RateLimited { .. } => "rate_limited",
+            Self::Storage(_) => "storage",
+        }
+    }
+}
+
+/// JSON body of an error response
+#[derive(Serialize, JsonSchema)]
+#[schemars(rename = "Error")]
+pub struct ErrorBody<'a> {
+    /// Stable identifier of the kind of error
+    #[schemars(extend("enum" = [
+        "validation", "unauthorized", "forbidden", "not_found", "conflict",
+        "payload_too_large", "unsupported_media_type", "rate_limited", "storage"
+    ]))]
+    code: &'static str,
+    /// Human-readable description
+    error: String,
+    /// The offending records of a rejected batch
+    #[serde(skip_serializing_if = "Option::is_none")]
+    errors: Option<&'a [RecordError]>,
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        if let Self::Storage(e) = &self {
+            tracing::error!(error = format!("{e:#}"), "API error");
+        }
+
+        let body = ErrorBody {
+            code: self.code(),
+            error: self.to_string(),
+            errors: match &self {
+                Self::Validation { errors, .. } | Self::Forbidden { errors, .. }
+                    if !errors.is_empty() =>
+                {
+                    Some(errors)
+                }
+                _ => None,
+            },
+        };
+        let mut response = (self.status(), Json(body)).into_response();
+        if let Self::RateLimited {
+            retry_after_secs, ..
+        } = &self
+        {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, (*retry_after_secs).into());
+        }
+        response
+    }
+}
+
+impl From<ApplyError> for ApiError {
+    fn from(err: ApplyError) -> Self {
+        match err {
+            ApplyError::Invalid(errors) => Self::Validation {
+                message: "Sync batch contains invalid records; no changes were applied".to_string(),
+                errors,
+            },
+            ApplyError::Forbidden(errors) => Self::Forbidden {
+                message: "Token may not change some records; no changes were applied".to_string(),
+                errors,
+            },
+            ApplyError::Storage(e) => Self::Storage(e),
+        }
+    }
+}
+
+impl From<PayloadError> for ApiError {
+    fn from(err: PayloadError) -> Self {
+        match err {
+            PayloadError::TooLarge(message) => Self::PayloadTooLarge(message),
+            PayloadError::Invalid { error, errors } => Self::Validation {
+                message: error,
+                errors,
+            },
+        }
+    }
+}
+
+impl From<Rejection> for ApiError {
+    fn from(rejection: Rejection) -> Self {
+        Self::RateLimited {
+            message: rejection.reason.to_string(),
+            retry_after_secs: rejection.retry_after_secs(),
+        }
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        match rejection.status() {
+            StatusCode::PAYLOAD_TOO_LARGE => Self::PayloadTooLarge(rejection.body_text()),
+            StatusCode::UNSUPPORTED_MEDIA_TYPE => Self::UnsupportedMediaType(rejection.body_text()),
+            _ => Self::Validation {
+                message: rejection.body_text(),
+                errors: Vec::new(),
+            },
+        }
+    }
+}
+
+impl From<QueryRejection> for ApiError {
+    fn from(rejection: QueryRejection) -> Self {
+        Self::Validation {
+            message: rejection.body_text(),
+            errors: Vec::new(),
+        }
+    }
+}
+
+impl From<PathRejection> for ApiError {
+    fn from(rejection: PathRejection) -> Self {
+        match rejection.status() {
+            // The route and handler disagree on the path parameters
+            StatusCode::INTERNAL_SERVER_ERROR => {
+                Self::Storage(anyhow::anyhow!(rejection.body_text()))
+            }
+            _ => Self::Validation {
+                message: rejection.body_text(),
+                errors: Vec::new(),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    async fn render(error: ApiError) -> (StatusCode, Option<String>, Value) {
+        let response = error.into_response();
+        let status = response.status();
+        let retry_after = response
+            .headers()
+            .get(header::RETRY_AFTER)
+            .map(|v| v.to_str().unwrap().to_string());
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, retry_after, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn errors_carry_their_status_and_code() {
+        let (status, _, body) = render(ApiError::NotFound("No such task".to_string())).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["code"], "not_found");
+        assert_eq!(body["error"], "No such task");
+        assert!(body.get("errors").is_none());
+
+        let (status, _, body) = render(ApiError::Unauthorized("Invalid API token".into())).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["code"], "unauthorized");
+    }
+
+    #[tokio::test]
+    async fn batch_errors_list_the_offending_records() {
+        let error = ApiError::from(ApplyError::Invalid(vec![RecordError {
+            index: 2,
+            id: "t1".to_string(),
+            record_type: None,
+            message: "bad".to_string(),
+        }]));
+        let (status, _, body) = render(error).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["code"], "validation");
+        assert_eq!(body["errors"][0]["index"], 2);
+
+        let error = ApiError::from(ApplyError::Forbidden(Vec::new()));
+        assert_eq!(error.status(), StatusCode::FORBIDDEN);
+        let error = ApiError::from(PayloadError::TooLarge("big".to_string()));
+        assert_eq!(error.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn rate_limits_tell_the_client_when_to_retry() {
+        let error = ApiError::from(Rejection {
+            reason: "Too many requests",
+            retry_after: std::time::Duration::from_millis(1500),
+        });
+        let (status, retry_after, body) = render(error).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(retry_after.as_deref(), Some("2"));
+        assert_eq!(body["code"], "rate_limited");
+    }
+
+    #[tokio::test]
+    async fn storage_details_stay_on_the_server() {
+        let error = ApiError::from(anyhow::anyhow!("disk I/O error at /var/lib/tickit"));
+        let (status, _, body) = render(error).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(body["code"], "storage");
+        assert_eq!(body["error"], "Internal server error");
+    }
+}
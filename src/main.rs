@@ -5,14 +5,29 @@
 
 use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
+use std::io::IsTerminal;
+use std::net::SocketAddr;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
 mod api;
+mod auth;
+mod clock;
 mod config;
 mod db;
+mod error;
+mod migrations;
 mod models;
+mod openapi;
+mod query;
+mod ratelimit;
+mod resources;
+mod tls;
+mod usage;
+mod validation;
 
-use config::Config;
+use config::{Config, TokenScope};
 
 #[derive(Parser)]
 #[command(name = "tickit-sync")]
@@ -38,6 +53,16 @@ enum Commands {
         /// Bind address (overrides config)
         #[arg(short, long)]
         bind: Option<String>,
+
+        /// Serve HTTPS with a self-signed certificate, generated on first run
+        /// (uses tls_cert/tls_key paths if configured)
+        #[arg(long)]
+        tls_self_signed: bool,
+
+        /// Extra host names or IPs for the self-signed certificate
+        /// (comma-separated)
+        #[arg(long, value_delimiter = ',', requires = "tls_self_signed")]
+        tls_hostname: Vec<String>,
     },
 
     /// Generate a new API token
@@ -46,6 +71,22 @@ enum Commands {
         #[arg(short, long)]
         name: Option<String>,
 
+        /// User account the token belongs to (defaults to the shared account)
+        #[arg(short, long)]
+        user: Option<String>,
+
+        /// Access level: read, write or admin
+        #[arg(long, value_enum, default_value_t = TokenScope::Write)]
+        scope: TokenScope,
+
+        /// Only allow access to these list IDs (comma-separated)
+        #[arg(long, value_delimiter = ',')]
+        lists: Option<Vec<String>>,
+
+        /// Expiry, as an RFC3339 timestamp or a number of days (e.g. "30d")
+        #[arg(long)]
+        expires: Option<String>,
+
         /// List all configured tokens
         #[arg(long)]
         list: bool,
@@ -54,11 +95,71 @@ enum Commands {
         #[arg(long)]
         revoke: Option<String>,
 
+        /// Replace legacy plain-text tokens in the config with Argon2 hashes
+        #[arg(long)]
+        upgrade: bool,
+
         /// Config file path (for list/revoke operations)
         #[arg(short, long)]
         config: Option<PathBuf>,
     },
 
+    /// Manage devices that sync with the server
+    Devices {
+        #[command(subcommand)]
+        command: DeviceCommand,
+
+        /// Config file path
+        #[arg(short, long, global = true)]
+        config: Option<PathBuf>,
+    },
+
+    /// Search task titles and descriptions
+    Search {
+        /// Words to look for
+        #[arg(required = true)]
+        query: Vec<String>,
+
+        /// User account to search (defaults to the shared account)
+        #[arg(short, long)]
+        user: Option<String>,
+
+        /// Only search these list IDs (comma-separated)
+        #[arg(long, value_delimiter = ',')]
+        lists: Vec<String>,
+
+        /// Only show open tasks
+        #[arg(long)]
+        open: bool,
+
+        /// Only show completed tasks
+        #[arg(long, conflicts_with = "open")]
+        completed: bool,
+
+        /// Maximum number of results
+        #[arg(short = 'n', long, default_value_t = 20)]
+        limit: usize,
+
+        /// Config file path
+        #[arg(short, long)]
+        config: Option<PathBuf>,
+    },
+
+    /// Apply pending database schema migrations
+    Migrate {
+        /// Show the schema version and pending migrations without applying them
+        #[arg(long)]
+        status: bool,
+
+        /// Check that pending migrations apply cleanly, then roll them back
+        #[arg(long, conflicts_with = "status")]
+        dry_run: bool,
+
+        /// Config file path
+        #[arg(short, long)]
+        config: Option<PathBuf>,
+    },
+
     /// Initialize a new config file
     Init {
         /// Output path for config file
@@ -67,6 +168,36 @@ enum Commands {
     },
 }
 
+#[derive(Subcommand)]
+enum DeviceCommand {
+    /// List registered devices
+    List {
+        /// Only show devices of this user account
+        #[arg(short, long)]
+        user: Option<String>,
+    },
+
+    /// Remove a device from the registry
+    Forget {
+        /// Device ID
+        id: String,
+
+        /// User account the device belongs to (default: any)
+        #[arg(short, long)]
+        user: Option<String>,
+    },
+
+    /// Make a device do a full sync on its next contact
+    Reset {
+        /// Device ID
+        id: String,
+
+        /// User account the device belongs to (default: any)
+        #[arg(short, long)]
+        user: Option<String>,
+    },
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     // Initialize logging
@@ -80,14 +211,26 @@ async fn main() -> Result<()> {
     let cli = Cli::parse();
 
     match cli.command {
-        Commands::Serve { config, port, bind } => {
-            let mut cfg = if let Some(path) = config {
-                Config::load_from(&path)?
-            } else {
-                Config::load()?
+        Commands::Serve {
+            config,
+            port,
+            bind,
+            tls_self_signed,
+            tls_hostname,
+        } => {
+            // Remember where the config came from so it can be reloaded
+            let config_path = match config {
+                Some(path) => Some(path),
+                None => Some(Config::default_path()?).filter(|path| path.exists()),
+            };
+            let file_cfg = match &config_path {
+                Some(path) => Config::load_from(path)?,
+                None => Config::default(),
             };
+            file_cfg.validate().context("Invalid config")?;
 
             // Override with CLI args
+            let mut cfg = file_cfg.clone();
             if let Some(p) = port {
                 cfg.server.port = p;
             }
@@ -95,13 +238,20 @@ async fn main() -> Result<()> {
                 cfg.server.bind = b;
             }
 
-            run_server(cfg).await
+            let tls = tls::prepare(&cfg, tls_self_signed, &tls_hostname)?;
+
+            run_server(cfg, tls, config_path.map(|path| (path, file_cfg))).await
         }
 
         Commands::Token {
             name,
+            user,
+            scope,
+            lists,
+            expires,
             list,
             revoke,
+            upgrade,
             config,
         } => {
             let config_path = if let Some(path) = config {
@@ -123,6 +273,15 @@ async fn main() -> Result<()> {
                     println!("No tokens configured.");
                     println!("Generate one with: tickit-sync token --name <device-name>");
                 } else {
+                    // Usage is only known once the server has created its database
+                    let (usage, devices) = if cfg.database.path.exists() {
+                        let db = db::Database::open(&cfg.database.path)
+                            .context("Failed to open database")?;
+                        (db.token_usage()?, db.list_devices(None)?)
+                    } else {
+                        Default::default()
+                    };
+
                     println!("Configured tokens:");
                     println!();
                     for token in &cfg.tokens {
@@ -132,7 +291,33 @@ async fn main() -> Result<()> {
                         } else {
                             token.token_hash.clone()
                         };
-                        println!("  {} - {}", token.name, hash_preview);
+                        println!("  {} ({}) - {}", token.name, token.user_id(), hash_preview);
+
+                        let mut access = format!("      Scope: {}", token.scope);
+                        if let Some(lists) = &token.lists {
+                            access.push_str(&format!(", lists: {}", lists.join(", ")));
+                        }
+                        if let Some(expires_at) = &token.expires_at {
+                            let expired = if token.is_expired() { " (expired)" } else { "" };
+                            access.push_str(&format!(", expires: {}{}", expires_at, expired));
+                        }
+                        println!("{}", access);
+
+                        match usage.get(&token.name) {
+                            Some(u) => println!(
+                                "      Last used: {} ({} requests)",
+                                u.last_used, u.request_count
+                            ),
+                            None => println!("      Never used"),
+                        }
+                        let token_devices: Vec<_> = devices
+                            .iter()
+                            .filter(|d| d.token_name.as_deref() == Some(token.name.as_str()))
+                            .map(|d| d.device_id.as_str())
+                            .collect();
+                        if !token_devices.is_empty() {
+                            println!("      Devices: {}", token_devices.join(", "));
+                        }
                     }
                 }
                 return Ok(());
@@ -153,14 +338,43 @@ async fn main() -> Result<()> {
                     println!("Token '{}' not found.", token_name);
                 } else {
                     cfg.save_to(&config_path)?;
+                    if cfg.database.path.exists() {
+                        db::Database::open(&cfg.database.path)
+                            .context("Failed to open database")?
+                            .forget_token_usage(&token_name)?;
+                    }
                     println!("Revoked token '{}'.", token_name);
                 }
                 return Ok(());
             }
 
+            // Hash legacy plain-text tokens in place
+            if upgrade {
+                if !config_path.exists() {
+                    println!("No config file found at {}", config_path.display());
+                    return Ok(());
+                }
+
+                let mut cfg = Config::load_from(&config_path)?;
+                let upgraded = cfg.upgrade_plaintext_tokens()?;
+                if upgraded.is_empty() {
+                    println!("All tokens are already hashed.");
+                } else {
+                    cfg.save_to(&config_path)?;
+                    println!("Hashed {} plain-text token(s):", upgraded.len());
+                    for name in &upgraded {
+                        println!("  {}", name);
+                    }
+                    println!();
+                    println!("Clients keep using the same tokens.");
+                }
+                return Ok(());
+            }
+
             // Generate new token
             let token = generate_token();
             let label = name.unwrap_or_else(|| "default".to_string());
+            let expires_at = expires.as_deref().map(parse_expiry).transpose()?;
 
             // Auto-save to config if it exists
             if config_path.exists() {
@@ -181,6 +395,10 @@ async fn main() -> Result<()> {
                 cfg.tokens.push(config::TokenConfig {
                     name: label.clone(),
                     token_hash,
+                    user: user.clone(),
+                    scope,
+                    lists: lists.clone(),
+                    expires_at: expires_at.clone(),
                 });
                 cfg.save_to(&config_path)?;
 
@@ -212,6 +430,18 @@ async fn main() -> Result<()> {
                 println!("Add this to your server's config.toml:\n");
                 println!("  [[tokens]]");
                 println!("  name = \"{}\"", label);
+                if let Some(user) = &user {
+                    println!("  user = \"{}\"", user);
+                }
+                if scope != TokenScope::Write {
+                    println!("  scope = \"{}\"", scope);
+                }
+                if let Some(lists) = &lists {
+                    println!("  lists = {:?}", lists);
+                }
+                if let Some(expires_at) = &expires_at {
+                    println!("  expires_at = \"{}\"", expires_at);
+                }
                 println!("  token_hash = \"{}\"\n", token_hash);
                 println!("━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━");
                 println!("📱 MOBILE APP (tickit-mobile):");
@@ -230,6 +460,204 @@ async fn main() -> Result<()> {
             Ok(())
         }
 
+        Commands::Devices { command, config } => {
+            let cfg = if let Some(path) = config {
+                Config::load_from(&path)?
+            } else {
+                Config::load()?
+            };
+            let db = db::Database::open(&cfg.database.path).context("Failed to open database")?;
+
+            match command {
+                DeviceCommand::List { user } => {
+                    let devices = db.list_devices(user.as_deref())?;
+                    if devices.is_empty() {
+                        println!("No devices have synced yet.");
+                        return Ok(());
+                    }
+
+                    println!("Registered devices:");
+                    for device in devices {
+                        let unknown = || "unknown".to_string();
+                        println!();
+                        println!("  {} ({})", device.device_id, device.user);
+                        println!(
+                            "    Token:       {}",
+                            device.token_name.unwrap_or_else(unknown)
+                        );
+                        println!(
+                            "    First seen:  {}",
+                            device.first_seen.unwrap_or_else(unknown)
+                        );
+                        println!("    Last sync:   {}", device.last_sync);
+                        println!(
+                            "    Last IP:     {}",
+                            device.last_ip.unwrap_or_else(unknown)
+                        );
+                        println!(
+                            "    User agent:  {}",
+                            device.user_agent.unwrap_or_else(unknown)
+                        );
+                        if let Some(version) = device.client_version {
+                            println!("    Client:      {}", version);
+                        }
+                        if device.resync_pending {
+                            println!("    Full resync pending");
+                        }
+                    }
+                }
+
+                DeviceCommand::Forget { id, user } => {
+                    match db.forget_device(&id, user.as_deref())? {
+                        0 => println!("Device '{}' not found.", id),
+                        _ => println!("Forgot device '{}'.", id),
+                    }
+                }
+
+                DeviceCommand::Reset { id, user } => {
+                    match db.reset_device(&id, user.as_deref())? {
+                        0 => println!("Device '{}' not found.", id),
+                        _ => println!("Device '{}' will do a full sync on its next contact.", id),
+                    }
+                }
+            }
+
+            Ok(())
+        }
+
+        Commands::Search {
+            query,
+            user,
+            lists,
+            open,
+            completed,
+            limit,
+            config,
+        } => {
+            let cfg = if let Some(path) = config {
+                Config::load_from(&path)?
+            } else {
+                Config::load()?
+            };
+            let db = db::Database::open(&cfg.database.path).context("Failed to open database")?;
+
+            let text = query.join(" ");
+            let filter = query::SearchQuery {
+                q: Some(text.clone()),
+                list_id: Some(lists.join(",")),
+                completed: (open || completed).then_some(completed),
+                limit: Some(limit),
+            }
+            .parse()
+            .map_err(|e| anyhow::anyhow!(e))?;
+
+            // Bold matches on a terminal
+            let (start, end) = if std::io::stdout().is_terminal() {
+                ("\x1b[1m", "\x1b[0m")
+            } else {
+                ("", "")
+            };
+            let highlight = query::Highlight {
+                start,
+                end,
+                html: false,
+            };
+            let owner = user.as_deref().unwrap_or(config::DEFAULT_USER);
+            let results = db.search_tasks(owner, &filter, None, highlight)?;
+            if results.is_empty() {
+                println!("No tasks match \"{}\".", text);
+                return Ok(());
+            }
+
+            println!("{} task(s) matching \"{}\":", results.len(), text);
+            for result in results {
+                let task = result.task;
+                let mut details = vec![format!("{:?}", task.priority).to_lowercase()];
+                if task.completed {
+                    details.push("completed".to_string());
+                }
+                if let Some(due) = &task.due_date {
+                    details.push(format!("due {}", due));
+                }
+                println!();
+                println!("  {}  [{}]", result.title, details.join(", "));
+                println!("    ID:    {}", task.id);
+                println!("    List:  {}", task.list_id);
+                if let Some(snippet) = result.snippet {
+                    println!("    {}", snippet);
+                }
+            }
+
+            Ok(())
+        }
+
+        Commands::Migrate {
+            status,
+            dry_run,
+            config,
+        } => {
+            let cfg = if let Some(path) = config {
+                Config::load_from(&path)?
+            } else {
+                Config::load()?
+            };
+
+            let db = db::Database::open_unmigrated(&cfg.database.path)
+                .context("Failed to open database")?;
+            let version = db.schema_version()?;
+            println!("Database: {}", cfg.database.path.display());
+            println!(
+                "Schema version: {} (latest: {})",
+                version,
+                migrations::latest_version()
+            );
+
+            if status {
+                let pending = db.pending_migrations()?;
+                println!();
+                for migration in migrations::MIGRATIONS {
+                    let state = if migration.version <= version {
+                        "applied"
+                    } else {
+                        "pending"
+                    };
+                    println!(
+                        "  {:>3}  {:<8} {}",
+                        migration.version, state, migration.description
+                    );
+                }
+                println!();
+                if pending.is_empty() {
+                    println!("Database is up to date.");
+                } else {
+                    println!("{} migration(s) pending.", pending.len());
+                }
+                return Ok(());
+            }
+
+            let applied = db.migrate(dry_run)?;
+            if applied.is_empty() {
+                println!("Database is up to date.");
+                return Ok(());
+            }
+
+            println!();
+            for migration in applied {
+                println!("  {:>3}  {}", migration.version, migration.description);
+            }
+            println!();
+            if dry_run {
+                println!(
+                    "Dry run: {} migration(s) applied cleanly and were rolled back.",
+                    applied.len()
+                );
+            } else {
+                println!("Applied {} migration(s).", applied.len());
+            }
+
+            Ok(())
+        }
+
         Commands::Init { output } => {
             let path = output.unwrap_or_else(|| PathBuf::from("config.toml"));
             let cfg = Config::default();
@@ -250,22 +678,229 @@ async fn main() -> Result<()> {
     }
 }
 
-async fn run_server(config: Config) -> Result<()> {
+/// Run the server; `config_file` is the file the config was loaded from (and
+/// its contents before CLI overrides), which is watched for changes
+async fn run_server(
+    config: Config,
+    tls: Option<tls::TlsFiles>,
+    config_file: Option<(PathBuf, Config)>,
+) -> Result<()> {
     let db = db::Database::open(&config.database.path).context("Failed to open database")?;
 
+    warn_plaintext_tokens(&config);
+
     let state = api::AppState::new(db, config.clone());
-    let app = api::create_router(state);
+    if config.rate_limit.persist_bans {
+        let window = chrono::Duration::seconds(config.rate_limit.failure_window_secs as i64);
+        let saved = state
+            .db
+            .load_auth_failures(chrono::Utc::now() - window)
+            .context("Failed to load saved authentication failures")?;
+        if !saved.is_empty() {
+            tracing::info!(clients = saved.len(), "Restored authentication failures");
+        }
+        state.limiter.restore(saved);
+    }
+    tokio::spawn(purge_tombstones_periodically(state.clone()));
+    tokio::spawn(write_token_usage_periodically(state.clone()));
+    if let Some((path, loaded)) = config_file {
+        tokio::spawn(watch_config(state.clone(), path, loaded));
+    }
+    let app = api::create_router(state).into_make_service_with_connect_info::<SocketAddr>();
 
     let addr = format!("{}:{}", config.server.bind, config.server.port);
     let listener = tokio::net::TcpListener::bind(&addr).await?;
 
-    tracing::info!("🚀 tickit-sync server listening on http://{}", addr);
+    match tls {
+        Some(files) => {
+            let rustls = files.load().await?;
+            tokio::spawn(tls::watch(rustls.clone(), files));
 
-    axum::serve(listener, app).await?;
+            tracing::info!("🚀 tickit-sync server listening on https://{}", addr);
+            axum_server::from_tcp_rustls(listener.into_std()?, rustls)
+                .serve(app)
+                .await?;
+        }
+        None => {
+            tracing::info!("🚀 tickit-sync server listening on http://{}", addr);
+            axum::serve(listener, app).await?;
+        }
+    }
 
     Ok(())
 }
 
+/// Background job deleting tombstones that are no longer needed
+async fn purge_tombstones_periodically(state: Arc<api::AppState>) {
+    loop {
+        let config = state.config();
+        let retention = (config.sync.tombstone_retention_days > 0)
+            .then(|| chrono::Duration::days(config.sync.tombstone_retention_days as i64));
+
+        match state.db.purge_tombstones(retention) {
+            Ok(0) => {}
+            Ok(purged) => tracing::info!(purged, "Purged tombstones"),
+            Err(e) => tracing::error!(error = %e, "Failed to purge tombstones"),
+        }
+
+        let interval = config.sync.tombstone_gc_interval_secs.max(1);
+        tokio::time::sleep(Duration::from_secs(interval)).await;
+    }
+}
+
+/// Background job writing tallied token uses to the database
+async fn write_token_usage_periodically(state: Arc<api::AppState>) {
+    let mut flush = tokio::time::interval(usage::FLUSH_INTERVAL);
+    loop {
+        flush.tick().await;
+        let usage = state.usage.take();
+        if usage.is_empty() {
+            continue;
+        }
+
+        let db_state = state.clone();
+        match tokio::task::spawn_blocking(move || db_state.db.record_token_usage(&usage)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => tracing::warn!(error = %e, "Failed to record token usage"),
+            Err(e) => tracing::error!(error = %e, "Token usage task failed"),
+        }
+    }
+}
+
+/// Reload the config file whenever it changes on disk, or on SIGHUP
+async fn watch_config(state: Arc<api::AppState>, path: PathBuf, mut loaded: Config) {
+    let (hangup_tx, mut hangup_rx) = tokio::sync::mpsc::channel(1);
+    #[cfg(unix)]
+    tokio::spawn(forward_hangups(hangup_tx));
+    #[cfg(not(unix))]
+    drop(hangup_tx);
+
+    let modified_time = |path: &PathBuf| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let mut modified = modified_time(&path);
+    let mut poll = tokio::time::interval(Duration::from_secs(2));
+
+    loop {
+        tokio::select! {
+            Some(()) = hangup_rx.recv() => {
+                tracing::info!("Received SIGHUP, reloading config");
+            }
+            _ = poll.tick() => {
+                let current = modified_time(&path);
+                if current == modified {
+                    continue;
+                }
+                modified = current;
+                tracing::info!("Config file changed, reloading");
+            }
+        }
+
+        reload_config(&state, &path, &mut loaded);
+    }
+}
+
+#[cfg(unix)]
+async fn forward_hangups(tx: tokio::sync::mpsc::Sender<()>) {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            tracing::warn!(error = %e, "Can't listen for SIGHUP; config reloads on file changes only");
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        // A reload already queued covers this one too
+        let _ = tx.try_send(());
+    }
+}
+
+/// Swap in the reloadable settings from the config file. An invalid file is
+/// rejected and the running config kept.
+fn reload_config(state: &api::AppState, path: &PathBuf, loaded: &mut Config) {
+    let new = match Config::load_from(path).and_then(|cfg| cfg.validate().map(|_| cfg)) {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            tracing::error!(
+                error = format!("{e:#}"),
+                "Invalid config, keeping the running one"
+            );
+            return;
+        }
+    };
+
+    let changes = new.changes_from(loaded);
+    if changes.is_empty() {
+        tracing::info!("Config unchanged");
+        return;
+    }
+
+    // The listener and database stay as they were started
+    let running = state.config();
+    state.set_config(Config {
+        server: running.server.clone(),
+        database: running.database.clone(),
+        ..new.clone()
+    });
+
+    tracing::info!(
+        tokens_added = ?changes.tokens_added,
+        tokens_removed = ?changes.tokens_removed,
+        tokens_changed = ?changes.tokens_changed,
+        sync_changed = changes.sync_changed,
+        auth_changed = changes.auth_changed,
+        rate_limit_changed = changes.rate_limit_changed,
+        limits_changed = changes.limits_changed,
+        "Config reloaded"
+    );
+    if !changes.tokens_added.is_empty()
+        || !changes.tokens_changed.is_empty()
+        || changes.auth_changed
+    {
+        warn_plaintext_tokens(&new);
+    }
+    for section in &changes.restart_required {
+        tracing::warn!("Changes to [{}] take effect after a restart", section);
+    }
+
+    *loaded = new;
+}
+
+/// Point out tokens still stored in plain text in the config
+fn warn_plaintext_tokens(config: &Config) {
+    let plaintext = config.plaintext_tokens();
+    if plaintext.is_empty() {
+        return;
+    }
+
+    if config.auth.allow_plaintext_tokens {
+        tracing::warn!(
+            tokens = ?plaintext,
+            "Tokens stored in plain text; hash them with `tickit-sync token --upgrade`"
+        );
+    } else {
+        tracing::warn!(
+            tokens = ?plaintext,
+            "Tokens stored in plain text are rejected (auth.allow_plaintext_tokens = false); \
+             hash them with `tickit-sync token --upgrade`"
+        );
+    }
+}
+
+/// Parse a token expiry given as "<days>d" or an RFC3339 timestamp
+fn parse_expiry(value: &str) -> Result<String> {
+    if let Some(days) = value.strip_suffix('d')
+        && let Ok(days) = days.parse::<i64>()
+    {
+        return Ok(clock::format(
+            chrono::Utc::now() + chrono::Duration::days(days),
+        ));
+    }
+    clock::normalize(value).with_context(|| {
+        format!("Invalid expiry '{value}': use an RFC3339 timestamp or a number of days like 30d")
+    })
+}
+
 fn generate_token() -> String {
     use rand::Rng;
     let mut rng = rand::rng();
@@ -0,0 +1,674 @@
+//! Versioned schema migrations
+//!
+//! The schema version is stored in SQLite's `PRAGMA user_version`. Each
+//! migration runs in its own transaction together with the version bump, so a
+//! failed migration leaves the database at the previous version.
+//!
+//! Databases created before versioning have version 0. The early migrations
+//! check for the columns they add, so they also bring those databases up to
+//! date whatever state they were left in.
+
+use anyhow::{Context, Result, bail};
+use rusqlite::{Connection, params};
+
+use crate::clock;
+use crate::config::DEFAULT_USER;
+
+/// A single schema change
+pub struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+    apply: fn(&Connection) -> Result<()>,
+}
+
+/// All migrations, in the order they are applied
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "Create tables",
+        apply: create_tables,
+    },
+    Migration {
+        version: 2,
+        description: "Scope records by user account",
+        apply: scope_by_owner,
+    },
+    Migration {
+        version: 3,
+        description: "Add change sequence numbers",
+        apply: add_change_sequence,
+    },
+    Migration {
+        version: 4,
+        description: "Track per-field task edit times",
+        apply: add_field_updated_at,
+    },
+    Migration {
+        version: 5,
+        description: "Track device acknowledgements for tombstone cleanup",
+        apply: add_tombstone_gc,
+    },
+    Migration {
+        version: 6,
+        description: "Add device registry details",
+        apply: add_device_registry,
+    },
+    Migration {
+        version: 7,
+        description: "Track token usage",
+        apply: add_token_usage,
+    },
+    Migration {
+        version: 8,
+        description: "Persist authentication failures",
+        apply: add_auth_failures,
+    },
+    Migration {
+        version: 9,
+        description: "Index task queries",
+        apply: add_task_query_indexes,
+    },
+    Migration {
+        version: 10,
+        description: "Add full-text task search",
+        apply: add_task_search,
+    },
+    Migration {
+        version: 11,
+        description: "Record the list of deleted tasks",
+        apply: add_tombstone_list,
+    },
+    Migration {
+        version: 12,
+        description: "Normalize task completion times and due dates",
+        apply: normalize_task_timestamps,
+    },
+];
+
+/// Schema version this build expects
+pub fn latest_version() -> u32 {
+    MIGRATIONS.last().map_or(0, |m| m.version)
+}
+
+/// Schema version the database is at
+pub fn current_version(conn: &Connection) -> Result<u32> {
+    conn.query_row("PRAGMA user_version", [], |row| row.get(0))
+        .context("Failed to read schema version")
+}
+
+/// Migrations not yet applied to the database. Fails if the database was
+/// created by a newer version of tickit-sync.
+pub fn pending(conn: &Connection) -> Result<&'static [Migration]> {
+    let current = current_version(conn)?;
+    let latest = latest_version();
+    if current > latest {
+        bail!(
+            "Database schema version {current} is newer than this server supports \
+             (version {latest}); upgrade tickit-sync to use this database"
+        );
+    }
+
+    Ok(&MIGRATIONS[MIGRATIONS.partition_point(|m| m.version <= current)..])
+}
+
+/// Apply pending migrations and return them. With `dry_run`, they are all
+/// applied in one transaction that is rolled back afterwards.
+pub fn run(conn: &Connection, dry_run: bool) -> Result<&'static [Migration]> {
+    let pending = pending(conn)?;
+
+    if dry_run {
+        let tx = conn.unchecked_transaction()?;
+        for migration in pending {
+            apply(&tx, migration)?;
+        }
+        tx.rollback()?;
+        return Ok(pending);
+    }
+
+    for migration in pending {
+        tracing::info!(
+            version = migration.version,
+            "Migrating database: {}",
+            migration.description
+        );
+        let tx = conn.unchecked_transaction()?;
+        apply(&tx, migration)?;
+        tx.commit()?;
+    }
+
+    Ok(pending)
+}
+
+fn apply(conn: &Connection, migration: &Migration) -> Result<()> {
+    (migration.apply)(conn).with_context(|| {
+        format!(
+            "Migration {} ({}) failed",
+            migration.version, migration.description
+        )
+    })?;
+    conn.pragma_update(None, "user_version", migration.version)?;
+    Ok(())
+}
+
+/// 1: the original single-user schema
+fn create_tables(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        r#"
+        -- Lists table
+        CREATE TABLE IF NOT EXISTS lists (
+            id TEXT PRIMARY KEY,
+            name TEXT NOT NULL,
+            description TEXT,
+            icon TEXT NOT NULL DEFAULT '📋',
+            color TEXT,
+            is_inbox INTEGER NOT NULL DEFAULT 0,
+            sort_order INTEGER NOT NULL DEFAULT 0,
+            created_at TEXT NOT NULL,
+            updated_at TEXT NOT NULL
+        );
+
+        -- Tags table
+        CREATE TABLE IF NOT EXISTS tags (
+            id TEXT PRIMARY KEY,
+            name TEXT NOT NULL,
+            color TEXT NOT NULL,
+            created_at TEXT NOT NULL,
+            updated_at TEXT
+        );
+
+        -- Tasks table
+        CREATE TABLE IF NOT EXISTS tasks (
+            id TEXT PRIMARY KEY,
+            title TEXT NOT NULL,
+            description TEXT,
+            url TEXT,
+            priority TEXT NOT NULL DEFAULT 'medium',
+            completed INTEGER NOT NULL DEFAULT 0,
+            list_id TEXT NOT NULL,
+            created_at TEXT NOT NULL,
+            updated_at TEXT NOT NULL,
+            completed_at TEXT,
+            due_date TEXT,
+            FOREIGN KEY (list_id) REFERENCES lists(id)
+        );
+
+        -- Task-Tag junction table
+        CREATE TABLE IF NOT EXISTS task_tags (
+            task_id TEXT NOT NULL,
+            tag_id TEXT NOT NULL,
+            created_at TEXT NOT NULL DEFAULT (datetime('now')),
+            PRIMARY KEY (task_id, tag_id),
+            FOREIGN KEY (task_id) REFERENCES tasks(id) ON DELETE CASCADE,
+            FOREIGN KEY (tag_id) REFERENCES tags(id) ON DELETE CASCADE
+        );
+
+        -- Tombstones for deleted records
+        CREATE TABLE IF NOT EXISTS tombstones (
+            id TEXT PRIMARY KEY,
+            record_type TEXT NOT NULL,
+            deleted_at TEXT NOT NULL
+        );
+
+        -- Device sync state
+        CREATE TABLE IF NOT EXISTS device_sync (
+            device_id TEXT PRIMARY KEY,
+            last_sync TEXT NOT NULL
+        );
+
+        -- Indexes
+        CREATE INDEX IF NOT EXISTS idx_tasks_list ON tasks(list_id);
+        CREATE INDEX IF NOT EXISTS idx_tasks_updated ON tasks(updated_at);
+        CREATE INDEX IF NOT EXISTS idx_lists_updated ON lists(updated_at);
+        CREATE INDEX IF NOT EXISTS idx_tombstones_deleted ON tombstones(deleted_at);
+        "#,
+    )?;
+    Ok(())
+}
+
+/// 2: rebuild every table with an owner column, assigning existing rows to
+/// the default account
+fn scope_by_owner(conn: &Connection) -> Result<()> {
+    if has_column(conn, "tasks", "owner")? {
+        return Ok(());
+    }
+
+    // Move the old tables aside first so the new foreign keys resolve
+    // against the rebuilt tables
+    let mut sql = String::new();
+    for table in [
+        "lists",
+        "tags",
+        "tasks",
+        "task_tags",
+        "tombstones",
+        "device_sync",
+    ] {
+        sql.push_str(&format!("ALTER TABLE {table} RENAME TO {table}_old;\n"));
+    }
+    sql.push_str(&format!(
+        r#"
+        CREATE TABLE lists (
+            owner TEXT NOT NULL,
+            id TEXT NOT NULL,
+            name TEXT NOT NULL,
+            description TEXT,
+            icon TEXT NOT NULL DEFAULT '📋',
+            color TEXT,
+            is_inbox INTEGER NOT NULL DEFAULT 0,
+            sort_order INTEGER NOT NULL DEFAULT 0,
+            created_at TEXT NOT NULL,
+            updated_at TEXT NOT NULL,
+            PRIMARY KEY (owner, id)
+        );
+
+        CREATE TABLE tags (
+            owner TEXT NOT NULL,
+            id TEXT NOT NULL,
+            name TEXT NOT NULL,
+            color TEXT NOT NULL,
+            created_at TEXT NOT NULL,
+            updated_at TEXT,
+            PRIMARY KEY (owner, id)
+        );
+
+        CREATE TABLE tasks (
+            owner TEXT NOT NULL,
+            id TEXT NOT NULL,
+            title TEXT NOT NULL,
+            description TEXT,
+            url TEXT,
+            priority TEXT NOT NULL DEFAULT 'medium',
+            completed INTEGER NOT NULL DEFAULT 0,
+            list_id TEXT NOT NULL,
+            created_at TEXT NOT NULL,
+            updated_at TEXT NOT NULL,
+            completed_at TEXT,
+            due_date TEXT,
+            PRIMARY KEY (owner, id),
+            FOREIGN KEY (owner, list_id) REFERENCES lists(owner, id)
+        );
+
+        CREATE TABLE task_tags (
+            owner TEXT NOT NULL,
+            task_id TEXT NOT NULL,
+            tag_id TEXT NOT NULL,
+            created_at TEXT NOT NULL DEFAULT (datetime('now')),
+            PRIMARY KEY (owner, task_id, tag_id),
+            FOREIGN KEY (owner, task_id) REFERENCES tasks(owner, id) ON DELETE CASCADE,
+            FOREIGN KEY (owner, tag_id) REFERENCES tags(owner, id) ON DELETE CASCADE
+        );
+
+        CREATE TABLE tombstones (
+            owner TEXT NOT NULL,
+            id TEXT NOT NULL,
+            record_type TEXT NOT NULL,
+            deleted_at TEXT NOT NULL,
+            PRIMARY KEY (owner, id)
+        );
+
+        CREATE TABLE device_sync (
+            owner TEXT NOT NULL,
+            device_id TEXT NOT NULL,
+            last_sync TEXT NOT NULL,
+            PRIMARY KEY (owner, device_id)
+        );
+
+        INSERT INTO lists (owner, id, name, description, icon, color, is_inbox, sort_order, created_at, updated_at)
+            SELECT '{owner}', id, name, description, icon, color, is_inbox, sort_order, created_at, updated_at FROM lists_old;
+        INSERT INTO tags (owner, id, name, color, created_at, updated_at)
+            SELECT '{owner}', id, name, color, created_at, updated_at FROM tags_old;
+        INSERT INTO tasks (owner, id, title, description, url, priority, completed, list_id, created_at, updated_at, completed_at, due_date)
+            SELECT '{owner}', id, title, description, url, priority, completed, list_id, created_at, updated_at, completed_at, due_date FROM tasks_old;
+        INSERT INTO task_tags (owner, task_id, tag_id, created_at)
+            SELECT '{owner}', task_id, tag_id, created_at FROM task_tags_old;
+        INSERT INTO tombstones (owner, id, record_type, deleted_at)
+            SELECT '{owner}', id, record_type, deleted_at FROM tombstones_old;
+        INSERT INTO device_sync (owner, device_id, last_sync)
+            SELECT '{owner}', device_id, last_sync FROM device_sync_old;
+
+        DROP TABLE task_tags_old;
+        DROP TABLE tasks_old;
+        DROP TABLE tags_old;
+        DROP TABLE lists_old;
+        DROP TABLE tombstones_old;
+        DROP TABLE device_sync_old;
+
+        -- The old indexes were dropped with their tables
+        CREATE INDEX idx_tasks_list ON tasks(owner, list_id);
+        CREATE INDEX idx_tasks_updated ON tasks(owner, updated_at);
+        CREATE INDEX idx_lists_updated ON lists(owner, updated_at);
+        CREATE INDEX idx_tombstones_deleted ON tombstones(owner, deleted_at);
+        "#,
+        owner = DEFAULT_USER,
+    ));
+
+    conn.execute_batch(&sql)?;
+    Ok(())
+}
+
+/// 3: server-assigned change sequence numbers, numbering any existing rows
+fn add_change_sequence(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        r#"
+        -- Server-assigned change sequence (single row)
+        CREATE TABLE IF NOT EXISTS sync_sequence (
+            id INTEGER PRIMARY KEY CHECK (id = 1),
+            value INTEGER NOT NULL
+        );
+        INSERT OR IGNORE INTO sync_sequence (id, value) VALUES (1, 0);
+        "#,
+    )?;
+
+    for table in ["lists", "tags", "tasks", "tombstones"] {
+        add_column_if_missing(conn, table, "seq", "INTEGER NOT NULL DEFAULT 0")?;
+
+        // Rowids are unique per table, so offsetting them by the current
+        // sequence value gives every unnumbered row a distinct number
+        let numbered = conn.execute(
+            &format!(
+                "UPDATE {table} SET seq = rowid + (SELECT value FROM sync_sequence WHERE id = 1)
+                 WHERE seq = 0"
+            ),
+            [],
+        )?;
+        if numbered > 0 {
+            conn.execute(
+                &format!(
+                    "UPDATE sync_sequence SET value = value + (SELECT MAX(rowid) FROM {table})
+                     WHERE id = 1"
+                ),
+                [],
+            )?;
+        }
+
+        conn.execute_batch(&format!(
+            "CREATE INDEX IF NOT EXISTS idx_{table}_seq ON {table}(owner, seq);"
+        ))?;
+    }
+
+    Ok(())
+}
+
+/// 4: per-field edit times for field-level task merging
+fn add_field_updated_at(conn: &Connection) -> Result<()> {
+    add_column_if_missing(
+        conn,
+        "tasks",
+        "field_updated_at",
+        "TEXT NOT NULL DEFAULT '{}'",
+    )
+}
+
+/// 5: what each device has acknowledged, when tombstones were recorded, and
+/// how far each user's tombstones have been purged
+fn add_tombstone_gc(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        r#"
+        ALTER TABLE device_sync ADD COLUMN acked_seq INTEGER NOT NULL DEFAULT 0;
+        ALTER TABLE device_sync ADD COLUMN sent_seq INTEGER NOT NULL DEFAULT 0;
+
+        ALTER TABLE tombstones ADD COLUMN recorded_at TEXT NOT NULL DEFAULT '';
+        UPDATE tombstones SET recorded_at = deleted_at;
+
+        -- Highest purged tombstone sequence number per user
+        CREATE TABLE sync_horizon (
+            owner TEXT PRIMARY KEY,
+            purged_seq INTEGER NOT NULL
+        );
+        "#,
+    )?;
+    Ok(())
+}
+
+/// 6: how and from where each device last synced, and forced resyncs
+fn add_device_registry(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        r#"
+        ALTER TABLE device_sync ADD COLUMN token_name TEXT;
+        ALTER TABLE device_sync ADD COLUMN first_seen TEXT;
+        ALTER TABLE device_sync ADD COLUMN user_agent TEXT;
+        ALTER TABLE device_sync ADD COLUMN client_version TEXT;
+        ALTER TABLE device_sync ADD COLUMN last_ip TEXT;
+        ALTER TABLE device_sync ADD COLUMN resync_required INTEGER NOT NULL DEFAULT 0;
+        "#,
+    )?;
+    Ok(())
+}
+
+/// 7: when each token was last used and how often
+fn add_token_usage(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        r#"
+        CREATE TABLE token_usage (
+            name TEXT PRIMARY KEY,
+            last_used TEXT NOT NULL,
+            request_count INTEGER NOT NULL DEFAULT 0
+        );
+        "#,
+    )?;
+    Ok(())
+}
+
+/// 8: failed logins and bans per client IP, so they survive a restart
+fn add_auth_failures(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        r#"
+        CREATE TABLE auth_failures (
+            ip TEXT PRIMARY KEY,
+            failures INTEGER NOT NULL,
+            last_failure TEXT NOT NULL,
+            banned_until TEXT
+        );
+        "#,
+    )?;
+    Ok(())
+}
+
+/// 9: indexes for filtering and sorting tasks through the API
+fn add_task_query_indexes(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        r#"
+        -- Open/completed tasks of a list
+        DROP INDEX IF EXISTS idx_tasks_list;
+        CREATE INDEX idx_tasks_list ON tasks(owner, list_id, completed);
+
+        CREATE INDEX idx_tasks_created ON tasks(owner, created_at);
+        CREATE INDEX idx_tasks_due ON tasks(owner, due_date);
+        CREATE INDEX idx_tasks_completed_at ON tasks(owner, completed_at);
+        CREATE INDEX idx_tasks_priority ON tasks(owner, priority);
+
+        -- Tasks with a tag (the primary key only covers tags of a task)
+        CREATE INDEX idx_task_tags_tag ON task_tags(owner, tag_id, task_id);
+        "#,
+    )?;
+    Ok(())
+}
+
+/// 10: full-text index of task titles and descriptions. Entries carry the
+/// task's owner and ID (tasks have no stable rowid), and are kept up to date
+/// by the database layer.
+fn add_task_search(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        r#"
+        CREATE VIRTUAL TABLE task_search USING fts5(
+            owner UNINDEXED,
+            id UNINDEXED,
+            title,
+            description,
+            tokenize = 'unicode61 remove_diacritics 2'
+        );
+
+        INSERT INTO task_search (owner, id, title, description)
+            SELECT owner, id, title, COALESCE(description, '') FROM tasks;
+        "#,
+    )?;
+    Ok(())
+}
+
+/// 11: the list a deleted task was in, on task and task-tag tombstones, so
+/// tokens limited to lists only see deletions in them. Older tombstones have
+/// none and are only sent to tokens that see every list.
+fn add_tombstone_list(conn: &Connection) -> Result<()> {
+    add_column_if_missing(conn, "tombstones", "list_id", "TEXT")
+}
+
+/// 12: completion times and timestamp due dates in canonical form, as they
+/// are now stored, so task filters compare them as instants. Values that
+/// don't parse, and plain dates, are left alone.
+fn normalize_task_timestamps(conn: &Connection) -> Result<()> {
+    let mut stmt = conn.prepare(
+        "SELECT owner, id, completed_at, due_date FROM tasks
+         WHERE completed_at IS NOT NULL OR due_date IS NOT NULL",
+    )?;
+    let tasks = stmt
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, Option<String>>(2)?,
+                row.get::<_, Option<String>>(3)?,
+            ))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let normalize = |ts: Option<String>| ts.map(|ts| clock::normalize(&ts).unwrap_or(ts));
+    for (owner, id, completed_at, due_date) in tasks {
+        conn.execute(
+            "UPDATE tasks SET completed_at = ?3, due_date = ?4 WHERE owner = ?1 AND id = ?2",
+            params![owner, id, normalize(completed_at), normalize(due_date)],
+        )?;
+    }
+    Ok(())
+}
+
+/// Add a column to a table created by an older version
+fn add_column_if_missing(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<()> {
+    if !has_column(conn, table, column)? {
+        conn.execute_batch(&format!(
+            "ALTER TABLE {table} ADD COLUMN {column} {definition};"
+        ))?;
+    }
+    Ok(())
+}
+
+/// Check whether a table has a given column
+fn has_column(conn: &Connection, table: &str, column: &str) -> Result<bool> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({table})"))?;
+    let columns = stmt
+        .query_map([], |row| row.get::<_, String>(1))?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(columns.iter().any(|c| c == column))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A database as left by a release from before schema versioning
+    fn baseline() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        create_tables(&conn).unwrap();
+        conn.execute_batch(
+            r#"
+            INSERT INTO lists (id, name, created_at, updated_at)
+                VALUES ('inbox', 'Inbox', '2024-01-01T00:00:00Z', '2024-01-01T00:00:00Z');
+            INSERT INTO tags (id, name, color, created_at)
+                VALUES ('urgent', 'Urgent', 'red', '2024-01-01T00:00:00Z');
+            INSERT INTO tasks (id, title, list_id, created_at, updated_at)
+                VALUES ('t1', 'First', 'inbox', '2024-01-01T00:00:00Z', '2024-01-01T00:00:00Z'),
+                       ('t2', 'Second', 'inbox', '2024-01-01T00:00:00Z', '2024-01-02T00:00:00Z');
+            INSERT INTO task_tags (task_id, tag_id) VALUES ('t1', 'urgent');
+            INSERT INTO tombstones (id, record_type, deleted_at)
+                VALUES ('t0', 'task', '2024-01-01T00:00:00Z');
+            "#,
+        )
+        .unwrap();
+        conn
+    }
+
+    fn count(conn: &Connection, sql: &str) -> i64 {
+        conn.query_row(sql, [], |row| row.get(0)).unwrap()
+    }
+
+    #[test]
+    fn baseline_database_is_migrated_to_the_latest_version() {
+        let conn = baseline();
+        assert_eq!(current_version(&conn).unwrap(), 0);
+
+        let applied = run(&conn, false).unwrap();
+        assert_eq!(applied.len(), MIGRATIONS.len());
+        assert_eq!(current_version(&conn).unwrap(), latest_version());
+        assert!(pending(&conn).unwrap().is_empty());
+
+        // Existing rows belong to the default account
+        for table in ["lists", "tags", "tasks", "task_tags", "tombstones"] {
+            let others = count(
+                &conn,
+                &format!("SELECT COUNT(*) FROM {table} WHERE owner != '{DEFAULT_USER}'"),
+            );
+            assert_eq!(others, 0, "{table}");
+        }
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM tasks"), 2);
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM task_tags"), 1);
+
+        // Every existing change is numbered, with distinct numbers
+        let numbered = count(
+            &conn,
+            "SELECT COUNT(DISTINCT seq) FROM (
+                 SELECT seq FROM lists UNION ALL SELECT seq FROM tags
+                 UNION ALL SELECT seq FROM tasks UNION ALL SELECT seq FROM tombstones
+             ) WHERE seq > 0",
+        );
+        assert_eq!(numbered, 5);
+        let sequence = count(&conn, "SELECT value FROM sync_sequence WHERE id = 1");
+        let highest = count(
+            &conn,
+            "SELECT MAX(seq) FROM (
+                 SELECT seq FROM lists UNION ALL SELECT seq FROM tags
+                 UNION ALL SELECT seq FROM tasks UNION ALL SELECT seq FROM tombstones
+             )",
+        );
+        assert!(sequence >= highest);
+
+        let fields: String = conn
+            .query_row(
+                "SELECT field_updated_at FROM tasks WHERE id = 't1'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(fields, "{}");
+    }
+
+    #[test]
+    fn migrating_again_is_a_no_op() {
+        let conn = baseline();
+        run(&conn, false).unwrap();
+        assert!(run(&conn, false).unwrap().is_empty());
+        assert_eq!(current_version(&conn).unwrap(), latest_version());
+    }
+
+    #[test]
+    fn dry_run_leaves_the_database_untouched() {
+        let conn = baseline();
+        let applied = run(&conn, true).unwrap();
+        assert_eq!(applied.len(), MIGRATIONS.len());
+        assert_eq!(current_version(&conn).unwrap(), 0);
+        assert!(!has_column(&conn, "tasks", "owner").unwrap());
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM tasks"), 2);
+    }
+
+    #[test]
+    fn newer_schema_is_refused() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.pragma_update(None, "user_version", latest_version() + 1)
+            .unwrap();
+        assert!(pending(&conn).is_err());
+        assert!(run(&conn, false).is_err());
+    }
+}
@@ -2,10 +2,13 @@
 //!
 //! Uses String for IDs and timestamps for maximum compatibility with clients.
 
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
 /// Priority level for tasks
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Serialize, Deserialize)]
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Serialize, Deserialize, JsonSchema,
+)]
 #[serde(rename_all = "lowercase")]
 pub enum Priority {
     Low,
@@ -15,8 +18,38 @@ pub enum Priority {
     Urgent,
 }
 
+/// Task fields that are merged individually during sync
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, JsonSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskField {
+    Title,
+    Description,
+    Url,
+    Priority,
+    /// `completed` together with `completed_at`
+    Completed,
+    DueDate,
+    ListId,
+    TagIds,
+}
+
+impl TaskField {
+    pub const ALL: [TaskField; 8] = [
+        TaskField::Title,
+        TaskField::Description,
+        TaskField::Url,
+        TaskField::Priority,
+        TaskField::Completed,
+        TaskField::DueDate,
+        TaskField::ListId,
+        TaskField::TagIds,
+    ];
+}
+
 /// A task/todo item
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct Task {
     pub id: String,
     pub title: String,
@@ -35,10 +68,13 @@ pub struct Task {
     pub completed_at: Option<String>,
     #[serde(default)]
     pub due_date: Option<String>,
+    /// Fields the client changed since its last sync (None = all fields)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub changed_fields: Option<Vec<TaskField>>,
 }
 
 /// A list/project that contains tasks
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct List {
     pub id: String,
     pub name: String,
@@ -61,7 +97,7 @@ fn default_icon() -> String {
 }
 
 /// A tag that can be attached to tasks
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct Tag {
     pub id: String,
     pub name: String,
@@ -72,7 +108,7 @@ pub struct Tag {
 }
 
 /// Link between task and tag
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct TaskTagLink {
     pub task_id: String,
     pub tag_id: String,
@@ -80,7 +116,7 @@ pub struct TaskTagLink {
 }
 
 /// Type of record (for tombstones)
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum RecordType {
     Task,
@@ -90,7 +126,7 @@ pub enum RecordType {
 }
 
 /// A record that can be synced
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum SyncRecord {
     Task(Task),
@@ -104,24 +140,280 @@ pub enum SyncRecord {
     },
 }
 
+impl SyncRecord {
+    /// ID of the record (the task ID for task-tag links)
+    pub fn id(&self) -> &str {
+        match self {
+            SyncRecord::Task(task) => &task.id,
+            SyncRecord::List(list) => &list.id,
+            SyncRecord::Tag(tag) => &tag.id,
+            SyncRecord::TaskTag(link) => &link.task_id,
+            SyncRecord::Deleted { id, .. } => id,
+        }
+    }
+
+    /// Type of the record (the deleted record's type for tombstones)
+    pub fn record_type(&self) -> RecordType {
+        match self {
+            SyncRecord::Task(_) => RecordType::Task,
+            SyncRecord::List(_) => RecordType::List,
+            SyncRecord::Tag(_) => RecordType::Tag,
+            SyncRecord::TaskTag(_) => RecordType::TaskTag,
+            SyncRecord::Deleted { record_type, .. } => *record_type,
+        }
+    }
+}
+
 /// Request to sync changes with server
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct SyncRequest {
     /// Device identifier
     pub device_id: String,
-    /// Timestamp of last successful sync (None = full sync)
+    /// Cursor returned by the last successful sync (preferred over `last_sync`)
+    #[serde(default)]
+    pub cursor: Option<String>,
+    /// Timestamp of last successful sync, for clients without a cursor
+    /// (None for both = full sync)
     pub last_sync: Option<String>,
+    /// Maximum number of changes to return (None = all at once)
+    #[serde(default)]
+    pub page_size: Option<usize>,
+    /// Continuation token from the previous page of a paginated sync
+    #[serde(default)]
+    pub continuation: Option<String>,
     /// Changes from this client since last sync
     pub changes: Vec<SyncRecord>,
 }
 
 /// Response from sync server
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct SyncResponse {
     /// Server timestamp for this sync
     pub server_time: String,
+    /// Opaque cursor to send with the next sync
+    pub cursor: String,
+    /// More changes are waiting; request them with `continuation`
+    #[serde(default)]
+    pub has_more: bool,
+    /// The client's sync state is too old; discard local synced records and
+    /// rebuild them from this (full) sync
+    #[serde(default)]
+    pub full_resync: bool,
+    /// Token for fetching the next page (set when `has_more` is true)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub continuation: Option<String>,
     /// Changes from other devices to apply locally
     pub changes: Vec<SyncRecord>,
     /// IDs of records that had conflicts (server won)
     pub conflicts: Vec<String>,
+    /// Details for each entry in `conflicts`
+    #[serde(default)]
+    pub conflict_details: Vec<SyncConflict>,
+}
+
+/// Why an incoming record was not applied as sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictReason {
+    /// The server's copy was changed more recently; nothing was applied
+    StaleUpdate,
+    /// Some changed fields were merged, others lost to newer server edits
+    FieldConflict,
+    /// The record, or one it refers to, was deleted on the server
+    Deleted,
+    /// The record's timestamp was too far ahead of the server clock
+    FutureTimestamp,
+}
+
+/// A record from the client that conflicted with the server's copy
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct SyncConflict {
+    pub id: String,
+    pub record_type: RecordType,
+    pub reason: ConflictReason,
+    /// Task fields that kept the server's value
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<TaskField>,
+    /// The version the client sent
+    pub client: SyncRecord,
+    /// The server's authoritative version after applying the sync
+    pub server: Option<SyncRecord>,
+}
+
+/// A record in a sync batch that could not be accepted
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct RecordError {
+    /// Position of the record in the request's `changes`
+    pub index: usize,
+    pub id: String,
+    /// Type of the record (None if it couldn't be parsed far enough to tell)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub record_type: Option<RecordType>,
+    pub message: String,
+}
+
+/// A device that has synced with the server
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Device {
+    pub device_id: String,
+    /// Account the device syncs
+    pub user: String,
+    /// Name of the token used for the last sync
+    pub token_name: Option<String>,
+    /// First sync seen from the device (unknown for devices registered
+    /// before the server recorded it)
+    pub first_seen: Option<String>,
+    pub last_sync: String,
+    pub last_ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub client_version: Option<String>,
+    /// The device will be sent a full resync on its next sync
+    pub resync_pending: bool,
+}
+
+/// New task created through the REST API
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct NewTask {
+    /// Client-chosen ID (a UUID is generated if omitted)
+    #[serde(default)]
+    pub id: Option<String>,
+    pub title: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub url: Option<String>,
+    #[serde(default)]
+    pub priority: Priority,
+    #[serde(default)]
+    pub completed: bool,
+    pub list_id: String,
+    #[serde(default)]
+    pub tag_ids: Vec<String>,
+    #[serde(default)]
+    pub due_date: Option<String>,
+}
+
+/// Changes to a task through the REST API; omitted fields are left as they
+/// are, and `null` clears an optional field
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct TaskPatch {
+    pub title: Option<String>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub description: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub url: Option<Option<String>>,
+    pub priority: Option<Priority>,
+    pub completed: Option<bool>,
+    pub list_id: Option<String>,
+    pub tag_ids: Option<Vec<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub due_date: Option<Option<String>>,
+}
+
+/// New list created through the REST API
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct NewList {
+    /// Client-chosen ID (a UUID is generated if omitted)
+    #[serde(default)]
+    pub id: Option<String>,
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default = "default_icon")]
+    pub icon: String,
+    #[serde(default)]
+    pub color: Option<String>,
+    #[serde(default)]
+    pub sort_order: i32,
+}
+
+/// Changes to a list through the REST API
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ListPatch {
+    pub name: Option<String>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub description: Option<Option<String>>,
+    pub icon: Option<String>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub color: Option<Option<String>>,
+    pub sort_order: Option<i32>,
+}
+
+/// New tag created through the REST API
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct NewTag {
+    /// Client-chosen ID (a UUID is generated if omitted)
+    #[serde(default)]
+    pub id: Option<String>,
+    pub name: String,
+    pub color: String,
+}
+
+/// Changes to a tag through the REST API
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct TagPatch {
+    pub name: Option<String>,
+    pub color: Option<String>,
+}
+
+/// Tell a field that is `null` (Some(None)) apart from one that is missing (None)
+fn nullable<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+/// Response listing a page of a user's tasks
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct TasksResponse {
+    pub tasks: Vec<Task>,
+    /// Pass as `cursor` to get the next page; absent on the last page
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+}
+
+/// A task found by a search, with the matched words marked
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct SearchResult {
+    pub task: Task,
+    /// Relevance; higher is a better match
+    pub score: f64,
+    /// Title with matches marked (HTML-escaped through the API)
+    pub title: String,
+    /// Excerpt of the description with matches marked (HTML-escaped through
+    /// the API)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub snippet: Option<String>,
+}
+
+/// Response to a task search, best match first
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct SearchResponse {
+    pub results: Vec<SearchResult>,
+}
+
+/// Response listing a user's lists
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ListsResponse {
+    pub lists: Vec<List>,
+}
+
+/// Response listing a user's tags
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct TagsResponse {
+    pub tags: Vec<Tag>,
+}
+
+/// Response listing a user's devices
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct DevicesResponse {
+    pub devices: Vec<Device>,
 }
@@ -0,0 +1,560 @@
+//! OpenAPI description of the HTTP API
+//!
+//! The document is generated from the wire types in `models.rs`, so it always
+//! matches what the server accepts and returns. Each sync record type is also
+//! published as a standalone JSON Schema, for validating records in clients.
+//! Both are public: they describe the API, not anyone's data.
+
+use axum::{Json, Router, extract::Path, routing::get};
+use schemars::{JsonSchema, SchemaGenerator, generate::SchemaSettings};
+use serde_json::{Map, Value, json};
+use std::sync::{Arc, LazyLock};
+
+use crate::api::{AppState, EventsQuery};
+use crate::error::{ApiError, ErrorBody};
+use crate::models::{
+    DevicesResponse, List, ListPatch, ListsResponse, NewList, NewTag, NewTask, SearchResponse,
+    SyncRecord, SyncRequest, SyncResponse, Tag, TagPatch, TagsResponse, Task, TaskPatch,
+    TasksResponse,
+};
+use crate::query::{SearchQuery, TaskQuery};
+
+const OPENAPI_PATH: &str = "/api/v1/openapi.json";
+const SCHEMAS_PATH: &str = "/api/v1/schemas";
+
+/// Error responses: status, component name, description
+const ERRORS: &[(u16, &str, &str)] = &[
+    (
+        400,
+        "Validation",
+        "The request or records in it are malformed",
+    ),
+    (401, "Unauthorized", "Missing, invalid or expired token"),
+    (
+        403,
+        "Forbidden",
+        "The token's scope or lists don't allow the request",
+    ),
+    (404, "NotFound", "Unknown endpoint or record"),
+    (
+        409,
+        "Conflict",
+        "The request conflicts with the record's current state",
+    ),
+    (
+        413,
+        "PayloadTooLarge",
+        "The request exceeds a payload limit",
+    ),
+    (
+        415,
+        "UnsupportedMediaType",
+        "The request body isn't sent as `application/json`",
+    ),
+    (
+        429,
+        "RateLimited",
+        "Too many requests, or too many failed logins",
+    ),
+    (
+        500,
+        "Storage",
+        "The server failed to read or write its database",
+    ),
+];
+
+/// Errors any authenticated request can get
+const AUTH_ERRORS: &[u16] = &[401, 403, 429, 500];
+
+static DOCUMENT: LazyLock<Value> = LazyLock::new(document);
+static RECORD_SCHEMAS: LazyLock<Map<String, Value>> = LazyLock::new(record_schemas);
+
+pub fn routes() -> Router<Arc<AppState>> {
+    Router::new()
+        .route(OPENAPI_PATH, get(|| async { Json(DOCUMENT.clone()) }))
+        .route(SCHEMAS_PATH, get(schema_index))
+        .route(
+            &format!("{SCHEMAS_PATH}/{{record_type}}"),
+            get(record_schema),
+        )
+}
+
+/// Whether a path is served without authentication
+pub fn is_public(path: &str) -> bool {
+    path == OPENAPI_PATH
+        || path
+            .strip_prefix(SCHEMAS_PATH)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/// Where to find the schema of each record type
+async fn schema_index() -> Json<Value> {
+    let urls: Map<String, Value> = RECORD_SCHEMAS
+        .keys()
+        .map(|name| (name.clone(), format!("{SCHEMAS_PATH}/{name}").into()))
+        .collect();
+    Json(json!({ "record_types": urls }))
+}
+
+async fn record_schema(Path(record_type): Path<String>) -> Result<Json<Value>, ApiError> {
+    RECORD_SCHEMAS
+        .get(&record_type)
+        .map(|schema| Json(schema.clone()))
+        .ok_or_else(|| ApiError::NotFound(format!("No schema for record type '{record_type}'")))
+}
+
+/// JSON Schema of each record as it appears in a sync, keyed by its `type`
+fn record_schemas() -> Map<String, Value> {
+    let root = SchemaSettings::draft2020_12()
+        .into_generator()
+        .into_root_schema_for::<SyncRecord>()
+        .to_value();
+
+    let mut schemas = Map::new();
+    for variant in root["oneOf"].as_array().into_iter().flatten() {
+        let Some(name) = variant["properties"]["type"]["const"].as_str() else {
+            continue;
+        };
+        let mut schema = Map::new();
+        schema.insert("$schema".into(), root["$schema"].clone());
+        schema.insert("title".into(), format!("{name} record").into());
+        schema.extend(variant.as_object().cloned().unwrap_or_default());
+        schema.insert("$defs".into(), root["$defs"].clone());
+        schemas.insert(name.to_string(), schema.into());
+    }
+    schemas
+}
+
+/// The OpenAPI 3.1 document
+fn document() -> Value {
+    let mut g = SchemaSettings::draft2020_12()
+        .with(|settings| {
+            settings.definitions_path = "/components/schemas".into();
+            settings.meta_schema = None;
+        })
+        .into_generator();
+
+    let id = json!({
+        "name": "id",
+        "in": "path",
+        "required": true,
+        "schema": { "type": "string" }
+    });
+    let mut paths = Map::new();
+
+    paths.insert(
+        "/health".into(),
+        json!({ "get": {
+            "operationId": "health",
+            "summary": "Check that the server is running",
+            "security": [],
+            "responses": { "200": {
+                "description": "The server is running",
+                "content": { "application/json": { "schema": {
+                    "type": "object",
+                    "properties": {
+                        "status": { "const": "ok" },
+                        "service": { "type": "string" },
+                        "version": { "type": "string" }
+                    }
+                }}}
+            }}
+        }}),
+    );
+
+    paths.insert(
+        "/api/v1/sync".into(),
+        json!({ "post": {
+            "operationId": "sync",
+            "summary": "Send local changes and receive changes from other devices",
+            "description": "The batch is applied atomically: if any record is invalid or not \
+                allowed, nothing is applied. Records conflicting with newer server data are \
+                reported in `conflicts`.",
+            "requestBody": body::<SyncRequest>(&mut g),
+            "responses": responses(ok::<SyncResponse>(&mut g, "Changes since the cursor"), &[400, 413]),
+        }}),
+    );
+
+    paths.insert(
+        "/api/v1/events".into(),
+        json!({ "get": {
+            "operationId": "events",
+            "summary": "Stream a notification whenever another device syncs changes",
+            "parameters": query_parameters::<EventsQuery>(),
+            "responses": responses(
+                ("200", json!({
+                    "description": "Server-Sent Events named `changes`, with data \
+                        `{\"device_id\": ..., \"cursor\": ...}` (or `{}` after missed events)",
+                    "content": { "text/event-stream": { "schema": { "type": "string" } } }
+                })),
+                &[],
+            ),
+        }}),
+    );
+
+    paths.insert(
+        "/api/v1/devices".into(),
+        json!({ "get": {
+            "operationId": "listDevices",
+            "summary": "List the devices of the token's user account (admin scope)",
+            "responses": responses(ok::<DevicesResponse>(&mut g, "Registered devices"), &[]),
+        }}),
+    );
+
+    paths.insert(
+        "/api/v1/tasks".into(),
+        json!({
+            "get": {
+                "operationId": "listTasks",
+                "summary": "List tasks, filtered, sorted and paginated",
+                "parameters": query_parameters::<TaskQuery>(),
+                "responses": responses(ok::<TasksResponse>(&mut g, "A page of tasks"), &[400]),
+            },
+            "post": {
+                "operationId": "createTask",
+                "summary": "Create a task",
+                "requestBody": body::<NewTask>(&mut g),
+                "responses": responses(created::<Task>(&mut g), &[400, 409, 413, 415]),
+            }
+        }),
+    );
+    paths.insert(
+        "/api/v1/tasks/{id}".into(),
+        item::<Task, TaskPatch>(&mut g, "Task", &id),
+    );
+
+    paths.insert(
+        "/api/v1/lists".into(),
+        json!({
+            "get": {
+                "operationId": "listLists",
+                "summary": "List lists",
+                "responses": responses(ok::<ListsResponse>(&mut g, "All lists"), &[]),
+            },
+            "post": {
+                "operationId": "createList",
+                "summary": "Create a list",
+                "requestBody": body::<NewList>(&mut g),
+                "responses": responses(created::<List>(&mut g), &[400, 409, 413, 415]),
+            }
+        }),
+    );
+    paths.insert(
+        "/api/v1/lists/{id}".into(),
+        item::<List, ListPatch>(&mut g, "List", &id),
+    );
+
+    paths.insert(
+        "/api/v1/tags".into(),
+        json!({
+            "get": {
+                "operationId": "listTags",
+                "summary": "List tags",
+                "responses": responses(ok::<TagsResponse>(&mut g, "All tags"), &[]),
+            },
+            "post": {
+                "operationId": "createTag",
+                "summary": "Create a tag",
+                "requestBody": body::<NewTag>(&mut g),
+                "responses": responses(created::<Tag>(&mut g), &[400, 409, 413, 415]),
+            }
+        }),
+    );
+    paths.insert(
+        "/api/v1/tags/{id}".into(),
+        item::<Tag, TagPatch>(&mut g, "Tag", &id),
+    );
+
+    paths.insert(
+        "/api/v1/search".into(),
+        json!({ "get": {
+            "operationId": "searchTasks",
+            "summary": "Full-text search over task titles and descriptions",
+            "parameters": query_parameters::<SearchQuery>(),
+            "responses": responses(ok::<SearchResponse>(&mut g, "Matching tasks, best first"), &[400]),
+        }}),
+    );
+
+    paths.insert(
+        OPENAPI_PATH.into(),
+        json!({ "get": {
+            "operationId": "openapi",
+            "summary": "This document",
+            "security": [],
+            "responses": { "200": { "description": "OpenAPI 3.1 document" } }
+        }}),
+    );
+    paths.insert(
+        SCHEMAS_PATH.into(),
+        json!({ "get": {
+            "operationId": "recordSchemas",
+            "summary": "Where to find the JSON Schema of each sync record type",
+            "security": [],
+            "responses": { "200": {
+                "description": "URL of each record type's schema",
+                "content": { "application/json": { "schema": {
+                    "type": "object",
+                    "properties": { "record_types": {
+                        "type": "object",
+                        "additionalProperties": { "type": "string" }
+                    }}
+                }}}
+            }}
+        }}),
+    );
+    paths.insert(
+        format!("{SCHEMAS_PATH}/{{record_type}}"),
+        json!({ "get": {
+            "operationId": "recordSchema",
+            "summary": "JSON Schema of a sync record type",
+            "security": [],
+            "parameters": [{
+                "name": "record_type",
+                "in": "path",
+                "required": true,
+                "schema": { "enum": RECORD_SCHEMAS.keys().collect::<Vec<_>>() }
+            }],
+            "responses": {
+                "200": { "description": "JSON Schema (draft 2020-12)" },
+                "404": { "$ref": "#/components/responses/NotFound" }
+            }
+        }}),
+    );
+
+    let error = g.subschema_for::<ErrorBody<'static>>();
+    let error_responses: Map<String, Value> = ERRORS
+        .iter()
+        .map(|&(status, name, description)| {
+            let mut response = json!({
+                "description": description,
+                "content": { "application/json": { "schema": error } }
+            });
+            if status == 429 {
+                response["headers"] = json!({ "Retry-After": {
+                    "description": "Seconds until the client may try again",
+                    "schema": { "type": "integer" }
+                }});
+            }
+            (name.to_string(), response)
+        })
+        .collect();
+    // Every variant of a sync record, so clients can refer to them by name
+    g.subschema_for::<SyncRecord>();
+
+    json!({
+        "openapi": "3.1.0",
+        "info": {
+            "title": "tickit-sync",
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": "Sync server for Tickit task manager clients",
+            "license": {
+                "name": env!("CARGO_PKG_LICENSE"),
+                "identifier": env!("CARGO_PKG_LICENSE")
+            }
+        },
+        "security": [{ "bearerAuth": [] }],
+        "paths": paths,
+        "components": {
+            "securitySchemes": {
+                "bearerAuth": {
+                    "type": "http",
+                    "scheme": "bearer",
+                    "description": "API token from `tickit-sync token`"
+                }
+            },
+            "responses": error_responses,
+            "schemas": g.take_definitions(true),
+        }
+    })
+}
+
+/// Get, change and delete operations on one record
+fn item<T: JsonSchema, P: JsonSchema>(g: &mut SchemaGenerator, name: &str, id: &Value) -> Value {
+    json!({
+        "parameters": [id],
+        "get": {
+            "operationId": format!("get{name}"),
+            "summary": format!("Get a {}", name.to_lowercase()),
+            "responses": responses(ok::<T>(g, name), &[404]),
+        },
+        "patch": {
+            "operationId": format!("update{name}"),
+            "summary": format!(
+                "Change some fields of a {}; `null` clears an optional field",
+                name.to_lowercase()
+            ),
+            "requestBody": body::<P>(g),
+            "responses": responses(ok::<T>(g, &format!("The updated {}", name.to_lowercase())), &[400, 404, 409, 413, 415]),
+        },
+        "delete": {
+            "operationId": format!("delete{name}"),
+            "summary": format!("Delete a {}, leaving a tombstone for devices", name.to_lowercase()),
+            "responses": responses(("204", json!({ "description": "Deleted" })), &[404, 409]),
+        }
+    })
+}
+
+fn body<T: JsonSchema>(g: &mut SchemaGenerator) -> Value {
+    json!({
+        "required": true,
+        "content": { "application/json": { "schema": g.subschema_for::<T>() } }
+    })
+}
+
+fn ok<T: JsonSchema>(g: &mut SchemaGenerator, description: &str) -> (&'static str, Value) {
+    ("200", json_response::<T>(g, description))
+}
+
+fn created<T: JsonSchema>(g: &mut SchemaGenerator) -> (&'static str, Value) {
+    ("201", json_response::<T>(g, "Created"))
+}
+
+fn json_response<T: JsonSchema>(g: &mut SchemaGenerator, description: &str) -> Value {
+    json!({
+        "description": description,
+        "content": { "application/json": { "schema": g.subschema_for::<T>() } }
+    })
+}
+
+/// A success response plus the errors an operation can return
+fn responses(success: (&str, Value), errors: &[u16]) -> Value {
+    let mut responses = Map::new();
+    responses.insert(success.0.to_string(), success.1);
+    for &(status, name, _) in ERRORS {
+        if errors.contains(&status) || AUTH_ERRORS.contains(&status) {
+            responses.insert(
+                status.to_string(),
+                json!({ "$ref": format!("#/components/responses/{name}") }),
+            );
+        }
+    }
+    responses.into()
+}
+
+/// Query parameters described by the fields of a query string type
+fn query_parameters<T: JsonSchema>() -> Value {
+    let schema = SchemaSettings::draft2020_12()
+        .with(|settings| settings.inline_subschemas = true)
+        .into_generator()
+        .into_root_schema_for::<T>()
+        .to_value();
+    let required = schema["required"].as_array().cloned().unwrap_or_default();
+
+    let parameters: Vec<Value> = schema["properties"]
+        .as_object()
+        .into_iter()
+        .flatten()
+        .map(|(name, property)| {
+            let mut property = property.clone();
+            let description = property
+                .as_object_mut()
+                .and_then(|p| p.remove("description"));
+            let mut parameter = json!({
+                "name": name,
+                "in": "query",
+                "required": required.contains(&name.as_str().into()),
+                "schema": property,
+            });
+            if let Some(description) = description {
+                parameter["description"] = description;
+            }
+            parameter
+        })
+        .collect();
+    parameters.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::db::Database;
+    use axum::extract::ConnectInfo;
+    use axum::http::{Request, StatusCode};
+    use std::net::SocketAddr;
+    use tower::ServiceExt;
+
+    /// GET a path without credentials
+    async fn get(path: &str) -> (StatusCode, Value) {
+        let db = Database::open(std::path::Path::new(":memory:")).unwrap();
+        let state = AppState::new(db, Config::default());
+        let mut request = Request::get(path).body(axum::body::Body::empty()).unwrap();
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 0))));
+
+        let response = crate::api::create_router(state)
+            .oneshot(request)
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    /// Every `$ref` in a document
+    fn refs(value: &Value, found: &mut Vec<String>) {
+        match value {
+            Value::Object(map) => {
+                if let Some(Value::String(target)) = map.get("$ref") {
+                    found.push(target.clone());
+                }
+                map.values().for_each(|v| refs(v, found));
+            }
+            Value::Array(items) => items.iter().for_each(|v| refs(v, found)),
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn only_the_descriptions_are_public() {
+        assert!(is_public("/api/v1/openapi.json"));
+        assert!(is_public("/api/v1/schemas"));
+        assert!(is_public("/api/v1/schemas/task"));
+        assert!(!is_public("/api/v1/schemasx"));
+        assert!(!is_public("/api/v1/tasks"));
+    }
+
+    #[tokio::test]
+    async fn document_is_served_without_a_token() {
+        let (status, document) = get(OPENAPI_PATH).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(document["openapi"].as_str().unwrap().starts_with("3.1"));
+        for path in ["/api/v1/sync", "/api/v1/tasks", "/api/v1/search"] {
+            assert!(document["paths"].get(path).is_some(), "{path} missing");
+        }
+
+        let (status, _) = get("/api/v1/tasks").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn document_refs_resolve() {
+        let document = document();
+        let mut found = Vec::new();
+        refs(&document, &mut found);
+        assert!(!found.is_empty());
+        for target in found {
+            let pointer = target.strip_prefix('#').unwrap();
+            assert!(document.pointer(pointer).is_some(), "{target} is dangling");
+        }
+    }
+
+    #[tokio::test]
+    async fn record_schemas_are_keyed_by_type() {
+        let (status, index) = get(SCHEMAS_PATH).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(index["record_types"]["task"], "/api/v1/schemas/task");
+
+        let (status, schema) = get("/api/v1/schemas/task").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(schema["properties"]["type"]["const"], "task");
+        assert!(schema["$defs"].is_object());
+
+        let (status, body) = get("/api/v1/schemas/project").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["code"], "not_found");
+    }
+}
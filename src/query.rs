@@ -0,0 +1,649 @@
+//! Filtering, sorting and pagination of task listings, and task search
+//!
+//! `GET /api/v1/tasks` takes its filters from the query string. Pages are
+//! keyset-paginated: the cursor holds the sort key and ID of the last task
+//! sent, so pages stay consistent while tasks are added or removed.
+//! `GET /api/v1/search` ranks tasks with the full-text index instead.
+
+use rusqlite::types::Value;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::clock;
+use crate::models::{Priority, Task};
+
+/// Tasks per page unless the client asks for another size
+const DEFAULT_LIMIT: usize = 100;
+
+/// Largest page a client may ask for
+const MAX_LIMIT: usize = 1000;
+
+/// Search results unless the client asks for another number
+const DEFAULT_SEARCH_LIMIT: usize = 20;
+
+/// Most search results a client may ask for
+const MAX_SEARCH_LIMIT: usize = 100;
+
+/// Query string of a task listing. Fields that take several values are
+/// comma-separated.
+#[derive(Debug, Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct TaskQuery {
+    /// Tasks in any of these lists
+    pub list_id: Option<String>,
+    /// Tasks with all of these tags
+    pub tag_ids: Option<String>,
+    /// Tasks with any of these priorities
+    pub priority: Option<String>,
+    pub completed: Option<bool>,
+    /// Due on or after this date or timestamp
+    pub due_after: Option<String>,
+    /// Due before this date or timestamp
+    pub due_before: Option<String>,
+    /// Completed on or after this date or timestamp
+    pub completed_after: Option<String>,
+    /// Completed before this date or timestamp
+    pub completed_before: Option<String>,
+    /// Text in the title or description
+    pub q: Option<String>,
+    /// Field to sort by, `-` prefixed for descending order
+    pub sort: Option<String>,
+    pub limit: Option<usize>,
+    /// `next_cursor` of the previous page
+    pub cursor: Option<String>,
+}
+
+/// A checked task query
+#[derive(Debug)]
+pub struct TaskFilter {
+    list_ids: Vec<String>,
+    tag_ids: Vec<String>,
+    priorities: Vec<Priority>,
+    completed: Option<bool>,
+    due: Range,
+    completed_at: Range,
+    text: Option<String>,
+    sort: Sort,
+    limit: usize,
+    after: Option<Position>,
+}
+
+/// Bounds on a date column: `from` inclusive, `until` exclusive
+#[derive(Debug, Default)]
+struct Range {
+    from: Option<String>,
+    until: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum SortField {
+    #[default]
+    CreatedAt,
+    UpdatedAt,
+    DueDate,
+    CompletedAt,
+    Priority,
+    Title,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Sort {
+    field: SortField,
+    descending: bool,
+}
+
+/// Where a page ends, as carried by its cursor
+#[derive(Debug, Serialize, Deserialize)]
+struct Position {
+    sort: String,
+    key: serde_json::Value,
+    id: String,
+}
+
+impl TaskQuery {
+    /// Check the query; the error lists everything wrong with it
+    pub fn parse(self) -> Result<TaskFilter, String> {
+        let mut problems = Vec::new();
+
+        let priorities = split(self.priority.as_deref())
+            .into_iter()
+            .filter_map(|p| {
+                serde_json::from_value(serde_json::Value::String(p.clone()))
+                    .map_err(|_| problems.push(format!("Unknown priority '{p}'")))
+                    .ok()
+            })
+            .collect();
+
+        let sort = match self.sort.as_deref() {
+            None => Sort::default(),
+            Some(sort) => Sort::parse(sort).unwrap_or_else(|| {
+                problems.push(format!("Can't sort by '{sort}'"));
+                Sort::default()
+            }),
+        };
+
+        let limit = self.limit.unwrap_or(DEFAULT_LIMIT);
+        if !(1..=MAX_LIMIT).contains(&limit) {
+            problems.push(format!("`limit` must be between 1 and {MAX_LIMIT}"));
+        }
+
+        let after = self
+            .cursor
+            .as_deref()
+            .filter(|cursor| !cursor.is_empty())
+            .and_then(|cursor| match Position::decode(cursor) {
+                Some(position) if position.sort == sort.to_string() => Some(position),
+                Some(_) => {
+                    problems.push("`cursor` belongs to a different sort order".to_string());
+                    None
+                }
+                None => {
+                    problems.push("Invalid `cursor`".to_string());
+                    None
+                }
+            });
+
+        let mut bound = |name: &str, value: Option<String>| {
+            value.and_then(|value| {
+                let bound = parse_bound(&value);
+                if bound.is_none() {
+                    problems.push(format!(
+                        "`{name}` is not a date or RFC 3339 timestamp: '{value}'"
+                    ));
+                }
+                bound
+            })
+        };
+        let due = Range {
+            from: bound("due_after", self.due_after),
+            until: bound("due_before", self.due_before),
+        };
+        let completed_at = Range {
+            from: bound("completed_after", self.completed_after),
+            until: bound("completed_before", self.completed_before),
+        };
+
+        if !problems.is_empty() {
+            return Err(problems.join("; "));
+        }
+        Ok(TaskFilter {
+            list_ids: split(self.list_id.as_deref()),
+            tag_ids: split(self.tag_ids.as_deref()),
+            priorities,
+            completed: self.completed,
+            due,
+            completed_at,
+            text: self.q.filter(|q| !q.trim().is_empty()),
+            sort,
+            limit,
+            after,
+        })
+    }
+}
+
+/// Values of a comma-separated parameter
+fn split(value: Option<&str>) -> Vec<String> {
+    value
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// A date bound as it compares against stored values: plain dates as they
+/// are, timestamps in canonical form
+fn parse_bound(value: &str) -> Option<String> {
+    if chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d").is_ok() {
+        return Some(value.to_string());
+    }
+    clock::normalize(value)
+}
+
+impl Sort {
+    fn parse(value: &str) -> Option<Self> {
+        let (descending, name) = match value.strip_prefix('-') {
+            Some(name) => (true, name),
+            None => (false, value),
+        };
+        let field = match name {
+            "created_at" => SortField::CreatedAt,
+            "updated_at" => SortField::UpdatedAt,
+            "due_date" => SortField::DueDate,
+            "completed_at" => SortField::CompletedAt,
+            "priority" => SortField::Priority,
+            "title" => SortField::Title,
+            _ => return None,
+        };
+        Some(Self { field, descending })
+    }
+
+    /// SQL expression sorted on. Tasks without a due date or completion
+    /// time come last in either direction.
+    fn key_sql(&self) -> &'static str {
+        match (self.field, self.descending) {
+            (SortField::CreatedAt, _) => "created_at",
+            (SortField::UpdatedAt, _) => "updated_at",
+            (SortField::DueDate, false) => "COALESCE(due_date, '~')",
+            (SortField::DueDate, true) => "COALESCE(due_date, '')",
+            (SortField::CompletedAt, false) => "COALESCE(completed_at, '~')",
+            (SortField::CompletedAt, true) => "COALESCE(completed_at, '')",
+            (SortField::Priority, _) => {
+                "CASE priority WHEN 'low' THEN 0 WHEN 'high' THEN 2 WHEN 'urgent' THEN 3 ELSE 1 END"
+            }
+            (SortField::Title, _) => "title",
+        }
+    }
+
+    /// The sort key of a task, as `key_sql` computes it
+    fn key(&self, task: &Task) -> serde_json::Value {
+        let last = if self.descending { "" } else { "~" };
+        let text = |value: &Option<String>| value.clone().unwrap_or_else(|| last.to_string());
+        match self.field {
+            SortField::CreatedAt => task.created_at.clone().into(),
+            SortField::UpdatedAt => task.updated_at.clone().into(),
+            SortField::DueDate => text(&task.due_date).into(),
+            SortField::CompletedAt => text(&task.completed_at).into(),
+            SortField::Priority => (task.priority as i64).into(),
+            SortField::Title => task.title.clone().into(),
+        }
+    }
+}
+
+impl std::fmt::Display for Sort {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self.field {
+            SortField::CreatedAt => "created_at",
+            SortField::UpdatedAt => "updated_at",
+            SortField::DueDate => "due_date",
+            SortField::CompletedAt => "completed_at",
+            SortField::Priority => "priority",
+            SortField::Title => "title",
+        };
+        if self.descending {
+            write!(f, "-{name}")
+        } else {
+            f.write_str(name)
+        }
+    }
+}
+
+impl Position {
+    /// Cursors are hex-encoded JSON, opaque to clients
+    fn encode(&self) -> String {
+        serde_json::to_vec(self)
+            .unwrap_or_default()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect()
+    }
+
+    fn decode(cursor: &str) -> Option<Self> {
+        if !cursor.len().is_multiple_of(2) || !cursor.is_ascii() {
+            return None;
+        }
+        let bytes = (0..cursor.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&cursor[i..i + 2], 16).ok())
+            .collect::<Option<Vec<u8>>>()?;
+        serde_json::from_slice(&bytes).ok()
+    }
+}
+
+impl TaskFilter {
+    /// Conditions, ordering and limit to append to
+    /// `SELECT ... FROM tasks WHERE owner = ?1`, and the parameters they use
+    /// after the owner. `lists` restricts the result to a token's lists.
+    pub fn sql(&self, lists: Option<&[String]>) -> (String, Vec<Value>) {
+        let mut sql = String::new();
+        let mut params: Vec<Value> = Vec::new();
+
+        list_conditions(&mut sql, &mut params, &self.list_ids, lists);
+        for tag_id in &self.tag_ids {
+            let p = param(&mut params, tag_id.clone().into());
+            sql += &format!(
+                " AND id IN (SELECT task_id FROM task_tags WHERE owner = ?1 AND tag_id = {p})"
+            );
+        }
+        if !self.priorities.is_empty() {
+            let placeholders: Vec<String> = self
+                .priorities
+                .iter()
+                .map(|p| param(&mut params, format!("{p:?}").to_lowercase().into()))
+                .collect();
+            sql += &format!(" AND priority IN ({})", placeholders.join(", "));
+        }
+        completed_condition(&mut sql, self.completed);
+        for (column, range) in [
+            ("due_date", &self.due),
+            ("completed_at", &self.completed_at),
+        ] {
+            if let Some(from) = &range.from {
+                let p = param(&mut params, from.clone().into());
+                sql += &format!(" AND {column} >= {p}");
+            }
+            if let Some(until) = &range.until {
+                let p = param(&mut params, until.clone().into());
+                sql += &format!(" AND {column} < {p}");
+            }
+        }
+        if let Some(text) = &self.text {
+            let escaped = text
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_");
+            let p = param(&mut params, format!("%{escaped}%").into());
+            sql +=
+                &format!(" AND (title LIKE {p} ESCAPE '\\' OR description LIKE {p} ESCAPE '\\')");
+        }
+
+        let key = self.sort.key_sql();
+        let (op, direction) = if self.sort.descending {
+            ("<", "DESC")
+        } else {
+            (">", "ASC")
+        };
+        if let Some(after) = &self.after {
+            let value = match &after.key {
+                serde_json::Value::Number(n) => Value::Integer(n.as_i64().unwrap_or_default()),
+                other => Value::Text(other.as_str().unwrap_or_default().to_string()),
+            };
+            let k = param(&mut params, value);
+            let id = param(&mut params, after.id.clone().into());
+            sql += &format!(" AND ({key}, id) {op} ({k}, {id})");
+        }
+
+        // One extra row tells whether there is another page
+        sql += &format!(
+            " ORDER BY {key} {direction}, id {direction} LIMIT {}",
+            self.limit + 1
+        );
+        (sql, params)
+    }
+
+    /// Cut the rows fetched with `sql` down to one page, and make the cursor
+    /// of the next page if there is one
+    pub fn page(&self, mut tasks: Vec<Task>) -> (Vec<Task>, Option<String>) {
+        if tasks.len() <= self.limit {
+            return (tasks, None);
+        }
+        tasks.truncate(self.limit);
+        let cursor = tasks.last().map(|last| {
+            Position {
+                sort: self.sort.to_string(),
+                key: self.sort.key(last),
+                id: last.id.clone(),
+            }
+            .encode()
+        });
+        (tasks, cursor)
+    }
+}
+
+/// Query string of a task search
+#[derive(Debug, Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct SearchQuery {
+    /// Words to look for; each must appear in the title or description
+    pub q: Option<String>,
+    /// Tasks in any of these lists (comma-separated)
+    pub list_id: Option<String>,
+    pub completed: Option<bool>,
+    pub limit: Option<usize>,
+}
+
+/// A checked task search
+#[derive(Debug)]
+pub struct SearchFilter {
+    /// FTS5 query
+    matching: String,
+    list_ids: Vec<String>,
+    completed: Option<bool>,
+    limit: usize,
+}
+
+impl SearchQuery {
+    /// Check the query; the error lists everything wrong with it
+    pub fn parse(self) -> Result<SearchFilter, String> {
+        let mut problems = Vec::new();
+
+        let matching = match_expression(self.q.as_deref().unwrap_or_default());
+        if matching.is_empty() {
+            problems.push("`q` must contain a word to search for".to_string());
+        }
+        let limit = self.limit.unwrap_or(DEFAULT_SEARCH_LIMIT);
+        if !(1..=MAX_SEARCH_LIMIT).contains(&limit) {
+            problems.push(format!("`limit` must be between 1 and {MAX_SEARCH_LIMIT}"));
+        }
+
+        if !problems.is_empty() {
+            return Err(problems.join("; "));
+        }
+        Ok(SearchFilter {
+            matching,
+            list_ids: split(self.list_id.as_deref()),
+            completed: self.completed,
+            limit,
+        })
+    }
+}
+
+/// FTS5 query matching tasks that contain every word, or a word starting
+/// with it. Words are quoted, so FTS5 operators in the input are searched
+/// for as text rather than interpreted.
+fn match_expression(text: &str) -> String {
+    text.split_whitespace()
+        .map(|word| format!("\"{}\"*", word.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+impl SearchFilter {
+    /// Conditions to append to a search of `task_search` joined with
+    /// `tasks`, with `owner = ?1` already in place, and the parameters they
+    /// use after the owner. `lists` restricts the result to a token's lists.
+    pub fn sql(&self, lists: Option<&[String]>) -> (String, Vec<Value>) {
+        let mut params = Vec::new();
+        let p = param(&mut params, self.matching.clone().into());
+        let mut sql = format!(" AND task_search MATCH {p}");
+        list_conditions(&mut sql, &mut params, &self.list_ids, lists);
+        completed_condition(&mut sql, self.completed);
+        (sql, params)
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+}
+
+/// How matched words are marked in search results
+#[derive(Debug, Clone, Copy)]
+pub struct Highlight {
+    pub start: &'static str,
+    pub end: &'static str,
+    /// Escape the task text as HTML before marking it, so task content
+    /// can't inject markup
+    pub html: bool,
+}
+
+impl Highlight {
+    /// `<mark>` tags around matches in HTML-escaped text
+    pub const HTML: Self = Self {
+        start: "<mark>",
+        end: "</mark>",
+        html: true,
+    };
+
+    /// Where the full-text index marks a match, before `render` swaps in
+    /// the real marks
+    pub const MATCH_START: &str = "\u{2}";
+    pub const MATCH_END: &str = "\u{3}";
+
+    /// Text marked with `MATCH_START` and `MATCH_END`, escaped and marked
+    pub fn render(&self, marked: &str) -> String {
+        let text = if self.html {
+            escape_html(marked)
+        } else {
+            marked.to_string()
+        };
+        text.replace(Self::MATCH_START, self.start)
+            .replace(Self::MATCH_END, self.end)
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Add a parameter and return its placeholder, numbered after ?1 (the owner)
+pub fn param(params: &mut Vec<Value>, value: Value) -> String {
+    params.push(value);
+    format!("?{}", params.len() + 1)
+}
+
+/// Restrict tasks to the lists asked for and the lists a token may see
+fn list_conditions(
+    sql: &mut String,
+    params: &mut Vec<Value>,
+    list_ids: &[String],
+    lists: Option<&[String]>,
+) {
+    for ids in [Some(list_ids).filter(|ids| !ids.is_empty()), lists]
+        .into_iter()
+        .flatten()
+    {
+        let placeholders: Vec<String> = ids
+            .iter()
+            .map(|id| param(params, id.clone().into()))
+            .collect();
+        *sql += &format!(" AND list_id IN ({})", placeholders.join(", "));
+    }
+}
+
+fn completed_condition(sql: &mut String, completed: Option<bool>) {
+    if let Some(completed) = completed {
+        *sql += &format!(" AND completed = {}", i32::from(completed));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(query: TaskQuery) -> Result<TaskFilter, String> {
+        query.parse()
+    }
+
+    #[test]
+    fn sorts_parse_and_print_alike() {
+        for sort in ["created_at", "-due_date", "priority", "-title"] {
+            assert_eq!(Sort::parse(sort).unwrap().to_string(), sort);
+        }
+        assert!(Sort::parse("colour").is_none());
+        assert!(Sort::parse("--title").is_none());
+    }
+
+    #[test]
+    fn every_problem_is_reported() {
+        let error = parse(TaskQuery {
+            priority: Some("high,whenever".to_string()),
+            sort: Some("colour".to_string()),
+            limit: Some(0),
+            due_after: Some("soon".to_string()),
+            cursor: Some("zz".to_string()),
+            ..TaskQuery::default()
+        })
+        .unwrap_err();
+        for problem in [
+            "Unknown priority 'whenever'",
+            "Can't sort by 'colour'",
+            "`limit`",
+            "`due_after`",
+            "Invalid `cursor`",
+        ] {
+            assert!(error.contains(problem), "{problem} missing from {error}");
+        }
+    }
+
+    #[test]
+    fn cursors_are_tied_to_their_sort_order() {
+        let position = Position {
+            sort: "-title".to_string(),
+            key: "Buy milk".into(),
+            id: "t1".to_string(),
+        };
+        let cursor = position.encode();
+        assert!(cursor.bytes().all(|b| b.is_ascii_hexdigit()));
+
+        let same = parse(TaskQuery {
+            sort: Some("-title".to_string()),
+            cursor: Some(cursor.clone()),
+            ..TaskQuery::default()
+        })
+        .unwrap();
+        assert_eq!(same.after.unwrap().id, "t1");
+
+        let other = parse(TaskQuery {
+            sort: Some("title".to_string()),
+            cursor: Some(cursor),
+            ..TaskQuery::default()
+        });
+        assert!(other.unwrap_err().contains("different sort order"));
+    }
+
+    #[test]
+    fn bounds_accept_dates_and_timestamps() {
+        assert_eq!(parse_bound("2024-02-01").as_deref(), Some("2024-02-01"));
+        assert_eq!(
+            parse_bound("2024-02-01T02:00:00+02:00").as_deref(),
+            Some("2024-02-01T00:00:00.000000Z")
+        );
+        assert!(parse_bound("February").is_none());
+    }
+
+    #[test]
+    fn text_search_escapes_like_wildcards() {
+        let filter = parse(TaskQuery {
+            q: Some("100%_done".to_string()),
+            ..TaskQuery::default()
+        })
+        .unwrap();
+        let (_, params) = filter.sql(None);
+        assert_eq!(params, [Value::Text("%100\\%\\_done%".to_string())]);
+    }
+
+    #[test]
+    fn search_words_are_quoted_prefixes() {
+        assert_eq!(match_expression("buy  milk"), "\"buy\"* \"milk\"*");
+        assert_eq!(
+            match_expression("say \"hi\" OR"),
+            "\"say\"* \"\"\"hi\"\"\"* \"OR\"*"
+        );
+        assert_eq!(match_expression("  "), "");
+    }
+
+    #[test]
+    fn searches_need_a_word() {
+        let error = SearchQuery {
+            q: Some(" ".to_string()),
+            limit: Some(0),
+            ..SearchQuery::default()
+        }
+        .parse()
+        .unwrap_err();
+        assert!(error.contains("`q`"));
+        assert!(error.contains("`limit`"));
+    }
+}
@@ -0,0 +1,306 @@
+//! Request rate limiting and brute-force protection
+//!
+//! Requests are limited per client IP and across the whole server with token
+//! buckets. Repeated authentication failures from one IP get it banned, and
+//! every further failure doubles the ban. Bans are checked before the token is
+//! verified, so guessing tokens can't be used to burn Argon2 CPU time.
+
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::RateLimitConfig;
+
+/// Upper bound on tracked IPs before idle entries are dropped
+const MAX_TRACKED: usize = 10_000;
+
+#[derive(Default)]
+pub struct RateLimiter {
+    per_ip: Mutex<HashMap<String, Bucket>>,
+    global: Mutex<Option<Bucket>>,
+    failures: Mutex<HashMap<String, AuthFailures>>,
+}
+
+/// Recent authentication failures of one client IP
+#[derive(Debug, Clone)]
+pub struct AuthFailures {
+    pub failures: u32,
+    pub last_failure: DateTime<Utc>,
+    pub banned_until: Option<DateTime<Utc>>,
+}
+
+impl AuthFailures {
+    fn banned_for(&self, now: DateTime<Utc>) -> Option<Duration> {
+        self.banned_until
+            .and_then(|until| (until - now).to_std().ok())
+            .filter(|left| !left.is_zero())
+    }
+}
+
+/// Why a request was turned away, and when the client may try again
+#[derive(Debug)]
+pub struct Rejection {
+    pub reason: &'static str,
+    pub retry_after: Duration,
+}
+
+impl Rejection {
+    /// Retry-After value in whole seconds (at least 1)
+    pub fn retry_after_secs(&self) -> u64 {
+        self.retry_after.as_secs_f64().ceil().max(1.0) as u64
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn full(per_minute: u32) -> Self {
+        Self {
+            tokens: per_minute as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Take one request from a bucket refilled at `per_minute`; the error is
+    /// how long until one is available
+    fn take(&mut self, per_minute: u32) -> Result<(), Duration> {
+        let rate = per_minute as f64 / 60.0;
+        let now = Instant::now();
+        let refill = now.duration_since(self.updated).as_secs_f64() * rate;
+        self.tokens = (self.tokens + refill).min(per_minute as f64);
+        self.updated = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / rate))
+        }
+    }
+
+    /// Whether the bucket has refilled completely, so forgetting it changes nothing
+    fn is_idle(&self) -> bool {
+        self.updated.elapsed() > Duration::from_secs(60)
+    }
+}
+
+impl RateLimiter {
+    /// Admit or reject a request from `ip`
+    pub fn check(&self, ip: &str, config: &RateLimitConfig) -> Result<(), Rejection> {
+        if let Some(left) = self
+            .failures
+            .lock()
+            .unwrap()
+            .get(ip)
+            .and_then(|f| f.banned_for(Utc::now()))
+        {
+            return Err(Rejection {
+                reason: "Too many failed authentication attempts",
+                retry_after: left,
+            });
+        }
+
+        if config.requests_per_minute > 0 {
+            let mut per_ip = self.per_ip.lock().unwrap();
+            if per_ip.len() >= MAX_TRACKED {
+                per_ip.retain(|_, bucket| !bucket.is_idle());
+            }
+            per_ip
+                .entry(ip.to_string())
+                .or_insert_with(|| Bucket::full(config.requests_per_minute))
+                .take(config.requests_per_minute)
+                .map_err(|wait| Rejection {
+                    reason: "Too many requests",
+                    retry_after: wait,
+                })?;
+        }
+
+        if config.global_requests_per_minute > 0 {
+            self.global
+                .lock()
+                .unwrap()
+                .get_or_insert_with(|| Bucket::full(config.global_requests_per_minute))
+                .take(config.global_requests_per_minute)
+                .map_err(|wait| Rejection {
+                    reason: "Server is busy",
+                    retry_after: wait,
+                })?;
+        }
+
+        Ok(())
+    }
+
+    /// Count a failed authentication from `ip`, banning it once it has failed
+    /// too often. Returns the updated record.
+    pub fn record_failure(&self, ip: &str, config: &RateLimitConfig) -> AuthFailures {
+        let now = Utc::now();
+        let window = chrono::Duration::seconds(config.failure_window_secs as i64);
+        let mut failures = self.failures.lock().unwrap();
+        if failures.len() >= MAX_TRACKED {
+            failures.retain(|_, f| f.banned_for(now).is_some() || now - f.last_failure <= window);
+        }
+
+        let entry = failures.entry(ip.to_string()).or_insert(AuthFailures {
+            failures: 0,
+            last_failure: now,
+            banned_until: None,
+        });
+        // Failures are forgiven once the IP has been quiet (and unbanned) for a while
+        let quiet_since = entry
+            .banned_until
+            .map_or(entry.last_failure, |until| until.max(entry.last_failure));
+        if now - quiet_since > window {
+            entry.failures = 0;
+        }
+        entry.failures += 1;
+        entry.last_failure = now;
+
+        if config.max_auth_failures > 0 && entry.failures >= config.max_auth_failures {
+            let doublings = (entry.failures - config.max_auth_failures).min(32);
+            let ban = config
+                .ban_secs
+                .saturating_mul(1 << doublings)
+                .min(config.max_ban_secs.max(config.ban_secs));
+            entry.banned_until = Some(now + chrono::Duration::seconds(ban as i64));
+        }
+        entry.clone()
+    }
+
+    /// Forget the failures of an IP that authenticated successfully.
+    /// Returns whether there were any.
+    pub fn record_success(&self, ip: &str) -> bool {
+        self.failures.lock().unwrap().remove(ip).is_some()
+    }
+
+    /// Load failures saved by a previous run
+    pub fn restore(&self, saved: impl IntoIterator<Item = (String, AuthFailures)>) {
+        self.failures.lock().unwrap().extend(saved);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> RateLimitConfig {
+        RateLimitConfig {
+            requests_per_minute: 3,
+            global_requests_per_minute: 0,
+            max_auth_failures: 3,
+            failure_window_secs: 900,
+            ban_secs: 60,
+            max_ban_secs: 200,
+            persist_bans: false,
+        }
+    }
+
+    fn ban_secs(failures: &AuthFailures) -> i64 {
+        (failures.banned_until.unwrap() - failures.last_failure).num_seconds()
+    }
+
+    #[test]
+    fn each_ip_gets_its_own_bucket() {
+        let limiter = RateLimiter::default();
+        let config = config();
+        for _ in 0..3 {
+            assert!(limiter.check("192.0.2.1", &config).is_ok());
+        }
+        let rejection = limiter.check("192.0.2.1", &config).unwrap_err();
+        assert_eq!(rejection.reason, "Too many requests");
+        // Refilling one request takes 60 / 3 seconds
+        assert_eq!(rejection.retry_after_secs(), 20);
+        assert!(limiter.check("192.0.2.2", &config).is_ok());
+    }
+
+    #[test]
+    fn global_limit_covers_every_ip() {
+        let limiter = RateLimiter::default();
+        let config = RateLimitConfig {
+            requests_per_minute: 0,
+            global_requests_per_minute: 2,
+            ..config()
+        };
+        assert!(limiter.check("192.0.2.1", &config).is_ok());
+        assert!(limiter.check("192.0.2.2", &config).is_ok());
+        let rejection = limiter.check("192.0.2.3", &config).unwrap_err();
+        assert_eq!(rejection.reason, "Server is busy");
+    }
+
+    #[test]
+    fn repeated_failures_ban_with_doubling_length() {
+        let limiter = RateLimiter::default();
+        let config = config();
+        for _ in 0..2 {
+            assert!(
+                limiter
+                    .record_failure("192.0.2.1", &config)
+                    .banned_until
+                    .is_none()
+            );
+        }
+        assert!(limiter.check("192.0.2.1", &config).is_ok());
+
+        assert_eq!(ban_secs(&limiter.record_failure("192.0.2.1", &config)), 60);
+        let rejection = limiter.check("192.0.2.1", &config).unwrap_err();
+        assert_eq!(rejection.reason, "Too many failed authentication attempts");
+        assert!(rejection.retry_after_secs() <= 60);
+        assert!(limiter.check("192.0.2.2", &config).is_ok());
+
+        assert_eq!(ban_secs(&limiter.record_failure("192.0.2.1", &config)), 120);
+        // Capped at max_ban_secs
+        assert_eq!(ban_secs(&limiter.record_failure("192.0.2.1", &config)), 200);
+        assert_eq!(ban_secs(&limiter.record_failure("192.0.2.1", &config)), 200);
+    }
+
+    #[test]
+    fn success_forgets_failures() {
+        let limiter = RateLimiter::default();
+        let config = config();
+        limiter.record_failure("192.0.2.1", &config);
+        limiter.record_failure("192.0.2.1", &config);
+        assert!(limiter.record_success("192.0.2.1"));
+        assert!(!limiter.record_success("192.0.2.1"));
+
+        let failures = limiter.record_failure("192.0.2.1", &config);
+        assert_eq!(failures.failures, 1);
+        assert!(failures.banned_until.is_none());
+    }
+
+    #[test]
+    fn old_failures_are_forgiven() {
+        let limiter = RateLimiter::default();
+        let config = config();
+        let long_ago = Utc::now() - chrono::Duration::hours(1);
+        limiter.restore([(
+            "192.0.2.1".to_string(),
+            AuthFailures {
+                failures: 2,
+                last_failure: long_ago,
+                banned_until: None,
+            },
+        )]);
+
+        let failures = limiter.record_failure("192.0.2.1", &config);
+        assert_eq!(failures.failures, 1);
+        assert!(failures.banned_until.is_none());
+    }
+
+    #[test]
+    fn restored_bans_are_enforced() {
+        let limiter = RateLimiter::default();
+        let now = Utc::now();
+        limiter.restore([(
+            "192.0.2.1".to_string(),
+            AuthFailures {
+                failures: 3,
+                last_failure: now,
+                banned_until: Some(now + chrono::Duration::seconds(30)),
+            },
+        )]);
+        assert!(limiter.check("192.0.2.1", &config()).is_err());
+    }
+}
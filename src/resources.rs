@@ -0,0 +1,948 @@
+//! REST endpoints for individual tasks, lists and tags, and task search
+//!
+//! Writes go through the same path as a sync batch from a device: they are
+//! validated, checked against the token's lists, stamped by the server clock
+//! and given a change sequence number, so every device picks them up (and
+//! deletions become tombstones) on its next sync.
+
+use axum::{
+    Extension, Json, Router,
+    body::Body,
+    extract::{
+        DefaultBodyLimit, Path, Query, Request, State,
+        rejection::{JsonRejection, PathRejection, QueryRejection},
+    },
+    http::StatusCode,
+    middleware::{self, Next},
+    response::Response,
+    routing::get,
+};
+use http_body_util::Limited;
+use std::sync::Arc;
+
+use crate::api::{AppState, AuthUser, ChangeNotification, encode_cursor};
+use crate::error::ApiError;
+use crate::models::{
+    ConflictReason, List, ListPatch, ListsResponse, NewList, NewTag, NewTask, RecordError,
+    RecordType, SearchResponse, SyncRecord, Tag, TagPatch, TagsResponse, Task, TaskField,
+    TaskPatch, TasksResponse,
+};
+use crate::query::{Highlight, SearchQuery, TaskQuery};
+use crate::validation;
+
+/// Device ID reported to event subscribers for changes made through the API
+const API_DEVICE_ID: &str = "rest-api";
+
+pub fn routes(state: &Arc<AppState>) -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/api/v1/tasks", get(list_tasks).post(create_task))
+        .route(
+            "/api/v1/tasks/{id}",
+            get(get_task).patch(update_task).delete(delete_task),
+        )
+        .route("/api/v1/lists", get(list_lists).post(create_list))
+        .route(
+            "/api/v1/lists/{id}",
+            get(get_list).patch(update_list).delete(delete_list),
+        )
+        .route("/api/v1/tags", get(list_tags).post(create_tag))
+        .route(
+            "/api/v1/tags/{id}",
+            get(get_tag).patch(update_tag).delete(delete_tag),
+        )
+        .route("/api/v1/search", get(search))
+        .layer(DefaultBodyLimit::disable())
+        .layer(middleware::from_fn_with_state(state.clone(), body_limit))
+}
+
+/// Limit request bodies to `max_body_bytes` like syncs, reading the limit
+/// per request so config reloads apply
+async fn body_limit(State(state): State<Arc<AppState>>, request: Request, next: Next) -> Response {
+    let limit = state.config().limits.max_body_bytes;
+    next.run(request.map(|body| Body::new(Limited::new(body, limit))))
+        .await
+}
+
+// Tasks
+
+async fn list_tasks(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<AuthUser>,
+    query: Result<Query<TaskQuery>, QueryRejection>,
+) -> Result<Json<TasksResponse>, ApiError> {
+    let Query(query) = query?;
+    let filter = query.parse().map_err(|message| ApiError::Validation {
+        message,
+        errors: Vec::new(),
+    })?;
+    let (tasks, next_cursor) =
+        state
+            .db
+            .query_tasks(&user.user_id, &filter, user.lists.as_deref())?;
+    Ok(Json(TasksResponse { tasks, next_cursor }))
+}
+
+async fn get_task(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<AuthUser>,
+    id: Result<Path<String>, PathRejection>,
+) -> Result<Json<Task>, ApiError> {
+    let Path(id) = id?;
+    Ok(Json(find_task(&state, &user, &id)?))
+}
+
+async fn create_task(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<AuthUser>,
+    payload: Result<Json<NewTask>, JsonRejection>,
+) -> Result<(StatusCode, Json<Task>), ApiError> {
+    let Json(new) = payload?;
+    let id = new.id.unwrap_or_else(new_id);
+    if state.db.task(&user.user_id, &id)?.is_some() {
+        return Err(ApiError::Conflict(format!("Task {id} already exists")));
+    }
+    check_references(&state, &user, &id, Some(new.list_id.as_str()), &new.tag_ids)?;
+
+    let now = state.db.clock().now();
+    let task = Task {
+        id: id.clone(),
+        title: new.title,
+        description: new.description,
+        url: new.url,
+        priority: new.priority,
+        completed: new.completed,
+        list_id: new.list_id,
+        tag_ids: new.tag_ids,
+        created_at: now.clone(),
+        updated_at: now.clone(),
+        completed_at: new.completed.then(|| now.clone()),
+        due_date: new.due_date,
+        changed_fields: None,
+    };
+    write(&state, &user, SyncRecord::Task(task))?;
+
+    Ok((StatusCode::CREATED, Json(find_task(&state, &user, &id)?)))
+}
+
+async fn update_task(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<AuthUser>,
+    id: Result<Path<String>, PathRejection>,
+    payload: Result<Json<TaskPatch>, JsonRejection>,
+) -> Result<Json<Task>, ApiError> {
+    let Path(id) = id?;
+    let Json(patch) = payload?;
+    let mut task = find_task(&state, &user, &id)?;
+    let current = task.clone();
+    let now = state.db.clock().now();
+
+    let mut changed = Vec::new();
+    if let Some(title) = patch.title {
+        task.title = title;
+        changed.push(TaskField::Title);
+    }
+    if let Some(description) = patch.description {
+        task.description = description;
+        changed.push(TaskField::Description);
+    }
+    if let Some(url) = patch.url {
+        task.url = url;
+        changed.push(TaskField::Url);
+    }
+    if let Some(priority) = patch.priority {
+        task.priority = priority;
+        changed.push(TaskField::Priority);
+    }
+    if let Some(completed) = patch.completed
+        && completed != task.completed
+    {
+        task.completed = completed;
+        task.completed_at = completed.then(|| now.clone());
+        changed.push(TaskField::Completed);
+    }
+    if let Some(due_date) = patch.due_date {
+        task.due_date = due_date;
+        changed.push(TaskField::DueDate);
+    }
+    if let Some(list_id) = patch.list_id {
+        task.list_id = list_id;
+        changed.push(TaskField::ListId);
+    }
+    if let Some(tag_ids) = patch.tag_ids {
+        task.tag_ids = tag_ids;
+        changed.push(TaskField::TagIds);
+    }
+    if changed.is_empty() {
+        return Ok(Json(task));
+    }
+    let added_tags: Vec<String> = task
+        .tag_ids
+        .iter()
+        .filter(|tag_id| !current.tag_ids.contains(tag_id))
+        .cloned()
+        .collect();
+    let moved_to = Some(task.list_id.as_str()).filter(|list_id| *list_id != current.list_id);
+    check_references(&state, &user, &id, moved_to, &added_tags)?;
+
+    task.updated_at = now;
+    task.changed_fields = Some(changed);
+    write(&state, &user, SyncRecord::Task(task))?;
+
+    Ok(Json(find_task(&state, &user, &id)?))
+}
+
+async fn delete_task(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<AuthUser>,
+    id: Result<Path<String>, PathRejection>,
+) -> Result<StatusCode, ApiError> {
+    let Path(id) = id?;
+    find_task(&state, &user, &id)?;
+    delete(&state, &user, id, RecordType::Task)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// A task the token may see
+fn find_task(state: &AppState, user: &AuthUser, id: &str) -> Result<Task, ApiError> {
+    state
+        .db
+        .task(&user.user_id, id)?
+        .filter(|task| user.can_access_list(&task.list_id))
+        .ok_or_else(|| ApiError::NotFound(format!("Task {id} not found")))
+}
+
+/// Devices may sync a task before its list, but through the API a task can
+/// only be put in a list, or given tags, that exist. References the task
+/// already had are left alone.
+fn check_references(
+    state: &AppState,
+    user: &AuthUser,
+    id: &str,
+    list_id: Option<&str>,
+    tag_ids: &[String],
+) -> Result<(), ApiError> {
+    let mut problems = Vec::new();
+    if let Some(list_id) = list_id
+        && state.db.list(&user.user_id, list_id)?.is_none()
+    {
+        problems.push(format!("List {list_id} not found"));
+    }
+    for tag_id in tag_ids {
+        if state.db.tag(&user.user_id, tag_id)?.is_none() {
+            problems.push(format!("Tag {tag_id} not found"));
+        }
+    }
+    if problems.is_empty() {
+        return Ok(());
+    }
+    Err(invalid(id, RecordType::Task, problems))
+}
+
+// Lists
+
+async fn list_lists(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<AuthUser>,
+) -> Result<Json<ListsResponse>, ApiError> {
+    let lists = state.db.lists(&user.user_id, user.lists.as_deref())?;
+    Ok(Json(ListsResponse { lists }))
+}
+
+async fn get_list(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<AuthUser>,
+    id: Result<Path<String>, PathRejection>,
+) -> Result<Json<List>, ApiError> {
+    let Path(id) = id?;
+    Ok(Json(find_list(&state, &user, &id)?))
+}
+
+async fn create_list(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<AuthUser>,
+    payload: Result<Json<NewList>, JsonRejection>,
+) -> Result<(StatusCode, Json<List>), ApiError> {
+    let Json(new) = payload?;
+    let id = new.id.unwrap_or_else(new_id);
+    if state.db.list(&user.user_id, &id)?.is_some() {
+        return Err(ApiError::Conflict(format!("List {id} already exists")));
+    }
+
+    let now = state.db.clock().now();
+    let list = List {
+        id: id.clone(),
+        name: new.name,
+        description: new.description,
+        icon: new.icon,
+        color: new.color,
+        is_inbox: false,
+        created_at: now.clone(),
+        updated_at: now,
+        sort_order: new.sort_order,
+    };
+    write(&state, &user, SyncRecord::List(list))?;
+
+    Ok((StatusCode::CREATED, Json(find_list(&state, &user, &id)?)))
+}
+
+async fn update_list(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<AuthUser>,
+    id: Result<Path<String>, PathRejection>,
+    payload: Result<Json<ListPatch>, JsonRejection>,
+) -> Result<Json<List>, ApiError> {
+    let Path(id) = id?;
+    let Json(patch) = payload?;
+    let mut list = find_list(&state, &user, &id)?;
+
+    let mut changed = false;
+    if let Some(name) = patch.name {
+        list.name = name;
+        changed = true;
+    }
+    if let Some(description) = patch.description {
+        list.description = description;
+        changed = true;
+    }
+    if let Some(icon) = patch.icon {
+        list.icon = icon;
+        changed = true;
+    }
+    if let Some(color) = patch.color {
+        list.color = color;
+        changed = true;
+    }
+    if let Some(sort_order) = patch.sort_order {
+        list.sort_order = sort_order;
+        changed = true;
+    }
+    if !changed {
+        return Ok(Json(list));
+    }
+
+    list.updated_at = state.db.clock().now();
+    write(&state, &user, SyncRecord::List(list))?;
+
+    Ok(Json(find_list(&state, &user, &id)?))
+}
+
+async fn delete_list(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<AuthUser>,
+    id: Result<Path<String>, PathRejection>,
+) -> Result<StatusCode, ApiError> {
+    let Path(id) = id?;
+    let list = find_list(&state, &user, &id)?;
+    if list.is_inbox {
+        return Err(ApiError::Conflict(
+            "The inbox list can't be deleted".to_string(),
+        ));
+    }
+    let tasks = state.db.list_task_count(&user.user_id, &id)?;
+    if tasks > 0 {
+        return Err(ApiError::Conflict(format!(
+            "List {id} still has {tasks} task(s); move or delete them first"
+        )));
+    }
+
+    delete(&state, &user, id, RecordType::List)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// A list the token may see
+fn find_list(state: &AppState, user: &AuthUser, id: &str) -> Result<List, ApiError> {
+    state
+        .db
+        .list(&user.user_id, id)?
+        .filter(|list| user.can_access_list(&list.id))
+        .ok_or_else(|| ApiError::NotFound(format!("List {id} not found")))
+}
+
+// Tags
+
+async fn list_tags(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<AuthUser>,
+) -> Result<Json<TagsResponse>, ApiError> {
+    let tags = state.db.tags(&user.user_id)?;
+    Ok(Json(TagsResponse { tags }))
+}
+
+async fn get_tag(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<AuthUser>,
+    id: Result<Path<String>, PathRejection>,
+) -> Result<Json<Tag>, ApiError> {
+    let Path(id) = id?;
+    Ok(Json(find_tag(&state, &user, &id)?))
+}
+
+async fn create_tag(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<AuthUser>,
+    payload: Result<Json<NewTag>, JsonRejection>,
+) -> Result<(StatusCode, Json<Tag>), ApiError> {
+    let Json(new) = payload?;
+    let id = new.id.unwrap_or_else(new_id);
+    if state.db.tag(&user.user_id, &id)?.is_some() {
+        return Err(ApiError::Conflict(format!("Tag {id} already exists")));
+    }
+
+    let now = state.db.clock().now();
+    let tag = Tag {
+        id: id.clone(),
+        name: new.name,
+        color: new.color,
+        created_at: now.clone(),
+        updated_at: Some(now),
+    };
+    write(&state, &user, SyncRecord::Tag(tag))?;
+
+    Ok((StatusCode::CREATED, Json(find_tag(&state, &user, &id)?)))
+}
+
+async fn update_tag(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<AuthUser>,
+    id: Result<Path<String>, PathRejection>,
+    payload: Result<Json<TagPatch>, JsonRejection>,
+) -> Result<Json<Tag>, ApiError> {
+    let Path(id) = id?;
+    let Json(patch) = payload?;
+    let mut tag = find_tag(&state, &user, &id)?;
+    if patch.name.is_none() && patch.color.is_none() {
+        return Ok(Json(tag));
+    }
+
+    if let Some(name) = patch.name {
+        tag.name = name;
+    }
+    if let Some(color) = patch.color {
+        tag.color = color;
+    }
+    tag.updated_at = Some(state.db.clock().now());
+    write(&state, &user, SyncRecord::Tag(tag))?;
+
+    Ok(Json(find_tag(&state, &user, &id)?))
+}
+
+async fn delete_tag(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<AuthUser>,
+    id: Result<Path<String>, PathRejection>,
+) -> Result<StatusCode, ApiError> {
+    let Path(id) = id?;
+    find_tag(&state, &user, &id)?;
+    delete(&state, &user, id, RecordType::Tag)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn find_tag(state: &AppState, user: &AuthUser, id: &str) -> Result<Tag, ApiError> {
+    state
+        .db
+        .tag(&user.user_id, id)?
+        .ok_or_else(|| ApiError::NotFound(format!("Tag {id} not found")))
+}
+
+// Search
+
+async fn search(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<AuthUser>,
+    query: Result<Query<SearchQuery>, QueryRejection>,
+) -> Result<Json<SearchResponse>, ApiError> {
+    let Query(query) = query?;
+    let filter = query.parse().map_err(|message| ApiError::Validation {
+        message,
+        errors: Vec::new(),
+    })?;
+    let results = state.db.search_tasks(
+        &user.user_id,
+        &filter,
+        user.lists.as_deref(),
+        Highlight::HTML,
+    )?;
+    Ok(Json(SearchResponse { results }))
+}
+
+// Shared
+
+fn new_id() -> String {
+    uuid::Uuid::new_v4().to_string()
+}
+
+fn delete(
+    state: &AppState,
+    user: &AuthUser,
+    id: String,
+    record_type: RecordType,
+) -> Result<(), ApiError> {
+    let deleted_at = state.db.clock().now();
+    write(
+        state,
+        user,
+        SyncRecord::Deleted {
+            id,
+            record_type,
+            deleted_at,
+        },
+    )
+}
+
+/// Apply one record as if a device had synced it, and tell event
+/// subscribers about it
+fn write(state: &AppState, user: &AuthUser, record: SyncRecord) -> Result<(), ApiError> {
+    let config = state.config();
+
+    let problems = validation::check_record(&record, &config.limits);
+    if !problems.is_empty() {
+        return Err(invalid(record.id(), record.record_type(), problems));
+    }
+
+    let conflicts = state.db.apply_changes(
+        &user.user_id,
+        std::slice::from_ref(&record),
+        &config.sync,
+        user.lists.as_deref(),
+    )?;
+    // Server-stamped changes only conflict when something they refer to is
+    // gone. A field conflict was merged and saved in part, so the caller gets
+    // the merged record back like any other successful write.
+    if let Some(conflict) = conflicts
+        .iter()
+        .find(|c| c.reason != ConflictReason::FieldConflict)
+    {
+        let reason = match conflict.reason {
+            ConflictReason::Deleted => "it, or a record it refers to, was deleted",
+            ConflictReason::StaleUpdate | ConflictReason::FieldConflict => {
+                "the stored copy is newer"
+            }
+            ConflictReason::FutureTimestamp => "its timestamp is in the future",
+        };
+        return Err(ApiError::Conflict(format!(
+            "{} {} was not saved: {reason}",
+            record_type_name(conflict.record_type),
+            conflict.id
+        )));
+    }
+
+    // No subscribers is not an error
+    let _ = state.changes.send(ChangeNotification {
+        user_id: user.user_id.clone(),
+        device_id: API_DEVICE_ID.to_string(),
+        cursor: encode_cursor(state.db.current_seq()?),
+    });
+    Ok(())
+}
+
+/// Validation error listing the problems with one record
+fn invalid(id: &str, record_type: RecordType, problems: Vec<String>) -> ApiError {
+    ApiError::Validation {
+        message: format!("Invalid {}", record_type_name(record_type).to_lowercase()),
+        errors: problems
+            .into_iter()
+            .map(|message| RecordError {
+                index: 0,
+                id: id.to_string(),
+                record_type: Some(record_type),
+                message,
+            })
+            .collect(),
+    }
+}
+
+fn record_type_name(record_type: RecordType) -> &'static str {
+    match record_type {
+        RecordType::Task => "Task",
+        RecordType::List => "List",
+        RecordType::Tag => "Tag",
+        RecordType::TaskTag => "Task tag",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, TokenConfig, TokenScope};
+    use crate::db::Database;
+    use axum::extract::ConnectInfo;
+    use axum::http::{Method, Request, header};
+    use serde_json::{Value, json};
+    use std::net::SocketAddr;
+    use tower::ServiceExt;
+
+    /// A token for the default account, accepted as plain text
+    fn token(name: &str, lists: Option<Vec<String>>) -> TokenConfig {
+        TokenConfig {
+            name: name.to_string(),
+            token_hash: format!("{name}-secret"),
+            user: None,
+            scope: TokenScope::Write,
+            lists,
+            expires_at: None,
+        }
+    }
+
+    fn state(tokens: Vec<TokenConfig>) -> Arc<AppState> {
+        let db = Database::open(std::path::Path::new(":memory:")).unwrap();
+        AppState::new(
+            db,
+            Config {
+                tokens,
+                ..Config::default()
+            },
+        )
+    }
+
+    async fn call(
+        state: &Arc<AppState>,
+        token: &str,
+        method: Method,
+        path: &str,
+        body: Option<Value>,
+    ) -> (StatusCode, Value) {
+        let mut request = Request::builder()
+            .method(method)
+            .uri(path)
+            .header(header::AUTHORIZATION, format!("Bearer {token}-secret"));
+        if body.is_some() {
+            request = request.header(header::CONTENT_TYPE, "application/json");
+        }
+        let mut request = request
+            .body(axum::body::Body::from(
+                body.map(|b| b.to_string()).unwrap_or_default(),
+            ))
+            .unwrap();
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 0))));
+
+        let response = crate::api::create_router(state.clone())
+            .oneshot(request)
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    /// Create a list and return its ID
+    async fn create_list(state: &Arc<AppState>) -> String {
+        let (status, list) = call(
+            state,
+            "full",
+            Method::POST,
+            "/api/v1/lists",
+            Some(json!({ "name": "Groceries" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED, "{list}");
+        list["id"].as_str().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn tasks_can_be_created_read_updated_and_deleted() {
+        let state = state(vec![token("full", None)]);
+        let list_id = create_list(&state).await;
+
+        let (status, task) = call(
+            &state,
+            "full",
+            Method::POST,
+            "/api/v1/tasks",
+            Some(json!({ "title": "Buy milk", "list_id": list_id })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED, "{task}");
+        let path = format!("/api/v1/tasks/{}", task["id"].as_str().unwrap());
+
+        let (status, fetched) = call(&state, "full", Method::GET, &path, None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(fetched["title"], "Buy milk");
+
+        let (status, updated) = call(
+            &state,
+            "full",
+            Method::PATCH,
+            &path,
+            Some(json!({ "completed": true, "description": "Oat" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{updated}");
+        assert_eq!(updated["completed"], true);
+        assert!(updated["completed_at"].is_string());
+        assert_eq!(updated["description"], "Oat");
+        assert_eq!(updated["title"], "Buy milk");
+
+        let (status, cleared) = call(
+            &state,
+            "full",
+            Method::PATCH,
+            &path,
+            Some(json!({ "description": null })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(cleared["description"].is_null());
+
+        let (status, _) = call(&state, "full", Method::DELETE, &path, None).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, body) = call(&state, "full", Method::GET, &path, None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["code"], "not_found");
+        let (status, _) = call(&state, "full", Method::DELETE, &path, None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn undecodable_ids_are_typed_errors() {
+        let state = state(vec![token("full", None)]);
+        for path in ["/api/v1/tasks/%FF", "/api/v1/lists/%FF", "/api/v1/tags/%FF"] {
+            let (status, body) = call(&state, "full", Method::GET, path, None).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{path}");
+            assert_eq!(body["code"], "validation");
+        }
+    }
+
+    #[tokio::test]
+    async fn bad_task_writes_are_refused() {
+        let state = state(vec![token("full", None)]);
+        let list_id = create_list(&state).await;
+
+        // Unknown list and tag
+        let (status, body) = call(
+            &state,
+            "full",
+            Method::POST,
+            "/api/v1/tasks",
+            Some(json!({ "title": "Buy milk", "list_id": new_id(), "tag_ids": [new_id()] })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["errors"].as_array().unwrap().len(), 2);
+
+        // Malformed body, and a field tasks don't have
+        for body in [
+            json!("Buy milk"),
+            json!({ "title": "x", "list_id": list_id, "colour": "red" }),
+        ] {
+            let (status, body) =
+                call(&state, "full", Method::POST, "/api/v1/tasks", Some(body)).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            assert_eq!(body["code"], "validation");
+        }
+
+        // An ID that is already taken
+        let id = new_id();
+        let task = json!({ "id": id, "title": "Buy milk", "list_id": list_id });
+        let (status, _) = call(
+            &state,
+            "full",
+            Method::POST,
+            "/api/v1/tasks",
+            Some(task.clone()),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (status, body) = call(&state, "full", Method::POST, "/api/v1/tasks", Some(task)).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["code"], "conflict");
+    }
+
+    #[tokio::test]
+    async fn lists_with_tasks_cannot_be_deleted() {
+        let state = state(vec![token("full", None)]);
+        let list_id = create_list(&state).await;
+        let path = format!("/api/v1/lists/{list_id}");
+        let (status, task) = call(
+            &state,
+            "full",
+            Method::POST,
+            "/api/v1/tasks",
+            Some(json!({ "title": "Buy milk", "list_id": list_id })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        let (status, _) = call(&state, "full", Method::DELETE, &path, None).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        let task_path = format!("/api/v1/tasks/{}", task["id"].as_str().unwrap());
+        call(&state, "full", Method::DELETE, &task_path, None).await;
+        let (status, _) = call(&state, "full", Method::DELETE, &path, None).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (_, lists) = call(&state, "full", Method::GET, "/api/v1/lists", None).await;
+        assert!(lists["lists"].as_array().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn tags_can_be_created_renamed_and_deleted() {
+        let state = state(vec![token("full", None)]);
+        let (status, tag) = call(
+            &state,
+            "full",
+            Method::POST,
+            "/api/v1/tags",
+            Some(json!({ "name": "Urgent", "color": "red" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+        let path = format!("/api/v1/tags/{}", tag["id"].as_str().unwrap());
+
+        let (status, renamed) = call(
+            &state,
+            "full",
+            Method::PATCH,
+            &path,
+            Some(json!({ "name": "Soon" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(renamed["name"], "Soon");
+        assert_eq!(renamed["color"], "red");
+
+        let (status, _) = call(&state, "full", Method::DELETE, &path, None).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (_, tags) = call(&state, "full", Method::GET, "/api/v1/tags", None).await;
+        assert!(tags["tags"].as_array().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn list_tokens_only_reach_their_lists() {
+        let state = state(vec![token("full", None)]);
+        let (mine, other) = (create_list(&state).await, create_list(&state).await);
+        state.set_config(Config {
+            tokens: vec![
+                token("full", None),
+                token("limited", Some(vec![mine.clone()])),
+            ],
+            ..Config::default()
+        });
+        let (_, task) = call(
+            &state,
+            "full",
+            Method::POST,
+            "/api/v1/tasks",
+            Some(json!({ "title": "Buy milk", "list_id": other })),
+        )
+        .await;
+        let path = format!("/api/v1/tasks/{}", task["id"].as_str().unwrap());
+
+        let (status, _) = call(&state, "limited", Method::GET, &path, None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = call(&state, "limited", Method::DELETE, &path, None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = call(
+            &state,
+            "limited",
+            Method::POST,
+            "/api/v1/tasks",
+            Some(json!({ "title": "Sneaky", "list_id": other })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let (_, lists) = call(&state, "limited", Method::GET, "/api/v1/lists", None).await;
+        assert_eq!(lists["lists"].as_array().unwrap().len(), 1);
+        assert_eq!(lists["lists"][0]["id"], mine.as_str());
+    }
+
+    #[tokio::test]
+    async fn api_deletions_reach_syncing_devices() {
+        let state = state(vec![token("full", None)]);
+        let list_id = create_list(&state).await;
+        let (_, sync) = call(
+            &state,
+            "full",
+            Method::POST,
+            "/api/v1/sync",
+            Some(json!({ "device_id": "d1", "changes": [] })),
+        )
+        .await;
+
+        let path = format!("/api/v1/lists/{list_id}");
+        call(&state, "full", Method::DELETE, &path, None).await;
+        let (_, next) = call(
+            &state,
+            "full",
+            Method::POST,
+            "/api/v1/sync",
+            Some(json!({ "device_id": "d1", "cursor": sync["cursor"], "changes": [] })),
+        )
+        .await;
+        assert_eq!(next["changes"][0]["type"], "deleted");
+        assert_eq!(next["changes"][0]["id"], list_id.as_str());
+    }
+
+    #[tokio::test]
+    async fn task_listings_page_through_matching_tasks() {
+        let state = state(vec![token("full", None)]);
+        let list_id = create_list(&state).await;
+        for title in ["A", "B", "C"] {
+            call(
+                &state,
+                "full",
+                Method::POST,
+                "/api/v1/tasks",
+                Some(json!({ "title": title, "list_id": list_id, "priority": "high" })),
+            )
+            .await;
+        }
+
+        let mut titles = Vec::new();
+        let mut path = "/api/v1/tasks?priority=high&sort=-title&limit=2".to_string();
+        loop {
+            let (status, page) = call(&state, "full", Method::GET, &path, None).await;
+            assert_eq!(status, StatusCode::OK, "{page}");
+            titles.extend(
+                page["tasks"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|t| t["title"].clone()),
+            );
+            let Some(cursor) = page["next_cursor"].as_str() else {
+                break;
+            };
+            path = format!("/api/v1/tasks?priority=high&sort=-title&limit=2&cursor={cursor}");
+        }
+        assert_eq!(titles, [json!("C"), json!("B"), json!("A")]);
+
+        for query in ["sort=colour", "limit=many", "colour=red"] {
+            let (status, body) = call(
+                &state,
+                "full",
+                Method::GET,
+                &format!("/api/v1/tasks?{query}"),
+                None,
+            )
+            .await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{query}");
+            assert_eq!(body["code"], "validation");
+        }
+    }
+
+    #[tokio::test]
+    async fn search_returns_marked_matches() {
+        let state = state(vec![token("full", None)]);
+        let list_id = create_list(&state).await;
+        call(
+            &state,
+            "full",
+            Method::POST,
+            "/api/v1/tasks",
+            Some(json!({ "title": "Buy milk", "list_id": list_id })),
+        )
+        .await;
+
+        let (status, body) = call(&state, "full", Method::GET, "/api/v1/search?q=milk", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["results"][0]["title"], "Buy <mark>milk</mark>");
+        assert_eq!(body["results"][0]["task"]["title"], "Buy milk");
+
+        let (status, _) = call(&state, "full", Method::GET, "/api/v1/search?q=", None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+}
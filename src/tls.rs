@@ -0,0 +1,210 @@
+//! HTTPS support
+//!
+//! The server terminates TLS itself when `[server]` names a certificate and
+//! key, or when started with `--tls-self-signed`. Certificate files are
+//! watched, so a renewed certificate (e.g. from certbot) is picked up without
+//! a restart.
+
+use anyhow::{Context, Result, bail};
+use axum_server::tls_rustls::RustlsConfig;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::config::Config;
+
+/// How often the certificate files are checked for changes
+const WATCH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Certificate chain and private key served over HTTPS (PEM files)
+#[derive(Debug, Clone)]
+pub struct TlsFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// Work out which certificate to serve, generating a self-signed one if
+/// asked to and none exists yet. None means plain HTTP.
+pub fn prepare(
+    config: &Config,
+    self_signed: bool,
+    hostnames: &[String],
+) -> Result<Option<TlsFiles>> {
+    let files = match (&config.server.tls_cert, &config.server.tls_key) {
+        (Some(cert), Some(key)) => TlsFiles {
+            cert: cert.clone(),
+            key: key.clone(),
+        },
+        _ if self_signed => {
+            // Keep the generated certificate with the server's other data
+            let dir = config
+                .database
+                .path
+                .parent()
+                .unwrap_or_else(|| Path::new(""));
+            TlsFiles {
+                cert: dir.join("tickit-sync-cert.pem"),
+                key: dir.join("tickit-sync-key.pem"),
+            }
+        }
+        _ => return Ok(None),
+    };
+
+    match (files.cert.exists(), files.key.exists()) {
+        (true, true) => {}
+        (false, false) if self_signed => {
+            let mut names = vec!["localhost".to_string(), "127.0.0.1".to_string()];
+            let bind = (!["0.0.0.0", "::"].contains(&config.server.bind.as_str()))
+                .then_some(&config.server.bind);
+            for name in bind.into_iter().chain(hostnames) {
+                if !names.contains(name) {
+                    names.push(name.clone());
+                }
+            }
+
+            generate_self_signed(&files, names.clone())?;
+            tracing::info!(
+                cert = %files.cert.display(),
+                names = ?names,
+                "Generated a self-signed TLS certificate"
+            );
+        }
+        (false, _) => bail!("TLS certificate {} not found", files.cert.display()),
+        (_, false) => bail!("TLS key {} not found", files.key.display()),
+    }
+
+    Ok(Some(files))
+}
+
+/// Create and save a self-signed certificate valid for `names`
+fn generate_self_signed(files: &TlsFiles, names: Vec<String>) -> Result<()> {
+    let certified = rcgen::generate_simple_self_signed(names)
+        .context("Failed to generate self-signed certificate")?;
+
+    for path in [&files.cert, &files.key] {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+    }
+    std::fs::write(&files.cert, certified.cert.pem())
+        .with_context(|| format!("Failed to write {}", files.cert.display()))?;
+    write_private(&files.key, &certified.key_pair.serialize_pem())
+        .with_context(|| format!("Failed to write {}", files.key.display()))?;
+    Ok(())
+}
+
+/// Write a file only the current user can read
+fn write_private(path: &Path, contents: &str) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    std::io::Write::write_all(&mut options.open(path)?, contents.as_bytes())
+}
+
+impl TlsFiles {
+    /// Load the certificate and key for the listener
+    pub async fn load(&self) -> Result<RustlsConfig> {
+        RustlsConfig::from_pem_file(&self.cert, &self.key)
+            .await
+            .with_context(|| {
+                format!(
+                    "Failed to load TLS certificate {} and key {}",
+                    self.cert.display(),
+                    self.key.display()
+                )
+            })
+    }
+
+    fn modified(&self) -> Option<(SystemTime, SystemTime)> {
+        let modified = |path: &PathBuf| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        Some((modified(&self.cert)?, modified(&self.key)?))
+    }
+}
+
+/// Reload the certificate whenever its files change on disk. New connections
+/// use the new certificate; a broken pair is rejected and the old one kept.
+pub async fn watch(tls: RustlsConfig, files: TlsFiles) {
+    let mut modified = files.modified();
+    let mut poll = tokio::time::interval(WATCH_INTERVAL);
+
+    loop {
+        poll.tick().await;
+        let current = files.modified();
+        if current == modified {
+            continue;
+        }
+        modified = current;
+
+        match tls.reload_from_pem_file(&files.cert, &files.key).await {
+            Ok(()) => tracing::info!(cert = %files.cert.display(), "Reloaded TLS certificate"),
+            Err(e) => tracing::error!(
+                error = %e,
+                "Invalid TLS certificate or key, keeping the current one"
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Config keeping its data in a fresh temporary directory
+    fn config() -> (Config, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("tickit-sync-tls-{}", uuid::Uuid::new_v4()));
+        let mut config = Config::default();
+        config.database.path = dir.join("tickit-sync.sqlite");
+        (config, dir)
+    }
+
+    #[test]
+    fn plain_http_without_certificates() {
+        let (config, _) = config();
+        assert!(prepare(&config, false, &[]).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn self_signed_certificates_are_generated_once() {
+        let (config, dir) = config();
+        let files = prepare(&config, true, &["sync.example.com".to_string()])
+            .unwrap()
+            .unwrap();
+        assert_eq!(files.cert, dir.join("tickit-sync-cert.pem"));
+        assert!(files.cert.exists() && files.key.exists());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&files.key).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        files.load().await.unwrap();
+
+        // Later starts reuse the certificate
+        let cert = std::fs::read(&files.cert).unwrap();
+        let again = prepare(&config, true, &[]).unwrap().unwrap();
+        assert_eq!(std::fs::read(&again.cert).unwrap(), cert);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn configured_certificates_must_exist() {
+        let (mut config, dir) = config();
+        config.server.tls_cert = Some(dir.join("cert.pem"));
+        config.server.tls_key = Some(dir.join("key.pem"));
+        assert!(prepare(&config, false, &[]).is_err());
+
+        // Self-signed mode fills in the configured paths
+        let files = prepare(&config, true, &[]).unwrap().unwrap();
+        assert_eq!(files.cert, dir.join("cert.pem"));
+        assert!(files.key.exists());
+
+        // Half a pair is never replaced
+        std::fs::remove_file(&files.key).unwrap();
+        assert!(prepare(&config, true, &[]).is_err());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,67 @@
+//! Token usage counting
+//!
+//! Every authenticated request counts towards its token's usage. Writing each
+//! one to the database would take the database lock on every request, so uses
+//! are tallied in memory and written together by a background job every few
+//! seconds. Uses tallied since the last write are lost if the server stops.
+
+use std::collections::HashMap;
+use std::mem;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::db::TokenUsage;
+
+/// How often tallied uses are written to the database
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Default)]
+pub struct UsageTally {
+    pending: Mutex<HashMap<String, TokenUsage>>,
+}
+
+impl UsageTally {
+    /// Count a request made with a token at `timestamp`
+    pub fn record(&self, token_name: &str, timestamp: String) {
+        let mut pending = self.pending.lock().unwrap();
+        match pending.get_mut(token_name) {
+            Some(usage) => {
+                usage.request_count += 1;
+                usage.last_used = timestamp;
+            }
+            None => {
+                pending.insert(
+                    token_name.to_string(),
+                    TokenUsage {
+                        last_used: timestamp,
+                        request_count: 1,
+                    },
+                );
+            }
+        }
+    }
+
+    /// Uses tallied since the last call, by token name
+    pub fn take(&self) -> HashMap<String, TokenUsage> {
+        mem::take(&mut *self.pending.lock().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uses_are_tallied_per_token_until_taken() {
+        let tally = UsageTally::default();
+        tally.record("laptop", "2024-01-01T00:00:00.000000Z".to_string());
+        tally.record("laptop", "2024-01-02T00:00:00.000000Z".to_string());
+        tally.record("phone", "2024-01-01T00:00:00.000000Z".to_string());
+
+        let usage = tally.take();
+        assert_eq!(usage["laptop"].request_count, 2);
+        assert_eq!(usage["laptop"].last_used, "2024-01-02T00:00:00.000000Z");
+        assert_eq!(usage["phone"].request_count, 1);
+        assert!(tally.take().is_empty());
+    }
+}
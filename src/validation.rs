@@ -0,0 +1,409 @@
+//! Limits and checks on sync payloads
+//!
+//! A sync request is parsed record by record, so a batch with malformed
+//! records is rejected with an error for each of them rather than a single
+//! parse error for the whole body.
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::api::{decode_continuation, decode_cursor};
+use crate::clock;
+use crate::config::LimitsConfig;
+use crate::models::{RecordError, RecordType, SyncRecord, SyncRequest};
+
+/// Longest ID accepted when IDs don't have to be UUIDs
+const MAX_ID_LENGTH: usize = 256;
+
+/// Longest icon or color value
+const MAX_SHORT_FIELD_LENGTH: usize = 64;
+
+/// Why a sync payload was refused
+#[derive(Debug)]
+pub enum PayloadError {
+    /// The request exceeds a size limit
+    TooLarge(String),
+    /// The request, or some of its records, are malformed
+    Invalid {
+        error: String,
+        errors: Vec<RecordError>,
+    },
+}
+
+impl PayloadError {
+    fn invalid(error: impl Into<String>) -> Self {
+        Self::Invalid {
+            error: error.into(),
+            errors: Vec::new(),
+        }
+    }
+}
+
+/// Parse and check a sync request body
+pub fn parse_sync_request(body: &[u8], limits: &LimitsConfig) -> Result<SyncRequest, PayloadError> {
+    let mut value: Value = serde_json::from_slice(body)
+        .map_err(|e| PayloadError::invalid(format!("Invalid JSON: {e}")))?;
+
+    // Take the changes out so each record can be parsed on its own
+    let raw_changes = match value.get_mut("changes").map(Value::take) {
+        Some(Value::Array(changes)) => changes,
+        Some(_) => return Err(PayloadError::invalid("`changes` must be an array")),
+        None => return Err(PayloadError::invalid("Missing `changes`")),
+    };
+    if raw_changes.len() > limits.max_records_per_request {
+        return Err(PayloadError::TooLarge(format!(
+            "{} records in one request; the limit is {}",
+            raw_changes.len(),
+            limits.max_records_per_request
+        )));
+    }
+    value["changes"] = Value::Array(Vec::new());
+
+    let mut request: SyncRequest = serde_json::from_value(value)
+        .map_err(|e| PayloadError::invalid(format!("Invalid sync request: {e}")))?;
+    if request.device_id.trim().is_empty() {
+        return Err(PayloadError::invalid("`device_id` must not be empty"));
+    }
+    if request.device_id.chars().count() > MAX_ID_LENGTH {
+        return Err(PayloadError::invalid(format!(
+            "`device_id` is longer than {MAX_ID_LENGTH} characters"
+        )));
+    }
+    if let Some(cursor) = &request.cursor
+        && decode_cursor(cursor).is_none()
+    {
+        return Err(PayloadError::invalid(format!(
+            "`cursor` is not a cursor issued by this server: '{}'",
+            preview(cursor)
+        )));
+    }
+    if let Some(continuation) = &request.continuation
+        && decode_continuation(continuation).is_none()
+    {
+        return Err(PayloadError::invalid(format!(
+            "`continuation` is not a continuation token issued by this server: '{}'",
+            preview(continuation)
+        )));
+    }
+    if let Some(last_sync) = &mut request.last_sync {
+        *last_sync = clock::normalize(last_sync).ok_or_else(|| {
+            PayloadError::invalid(format!(
+                "`last_sync` is not an RFC 3339 timestamp: '{}'",
+                preview(last_sync)
+            ))
+        })?;
+    }
+
+    let mut errors = Vec::new();
+    for (index, raw) in raw_changes.into_iter().enumerate() {
+        let id = raw
+            .get("id")
+            .or_else(|| raw.get("task_id"))
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string();
+        let record_type = raw_record_type(&raw);
+
+        match SyncRecord::deserialize(raw) {
+            Ok(record) => {
+                errors.extend(check_record(&record, limits).into_iter().map(|message| {
+                    RecordError {
+                        index,
+                        id: record.id().to_string(),
+                        record_type: Some(record.record_type()),
+                        message,
+                    }
+                }));
+                request.changes.push(record);
+            }
+            Err(e) => errors.push(RecordError {
+                index,
+                id,
+                record_type,
+                message: e.to_string(),
+            }),
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(PayloadError::Invalid {
+            error: "Sync batch contains invalid records; no changes were applied".to_string(),
+            errors,
+        });
+    }
+    Ok(request)
+}
+
+/// Best guess at the type of a record that failed to parse
+fn raw_record_type(raw: &Value) -> Option<RecordType> {
+    let tag = match raw.get("type").and_then(Value::as_str) {
+        Some("deleted") => raw.get("record_type")?,
+        _ => raw.get("type")?,
+    };
+    RecordType::deserialize(tag).ok()
+}
+
+/// Problems with a parsed record: malformed IDs or timestamps, and fields
+/// over the configured lengths
+pub fn check_record(record: &SyncRecord, limits: &LimitsConfig) -> Vec<String> {
+    let mut check = Checker {
+        limits,
+        problems: Vec::new(),
+    };
+
+    match record {
+        SyncRecord::Task(task) => {
+            check.id("id", &task.id);
+            check.id("list_id", &task.list_id);
+            check.text("title", &task.title, limits.max_title_length);
+            if let Some(description) = &task.description {
+                check.text("description", description, limits.max_description_length);
+            }
+            if let Some(url) = &task.url {
+                check.text("url", url, limits.max_url_length);
+            }
+            if task.tag_ids.len() > limits.max_tags_per_task {
+                check.problems.push(format!(
+                    "{} tags on one task; the limit is {}",
+                    task.tag_ids.len(),
+                    limits.max_tags_per_task
+                ));
+            }
+            for tag_id in &task.tag_ids {
+                check.id("tag_ids", tag_id);
+            }
+            check.timestamp("created_at", &task.created_at);
+            check.timestamp("updated_at", &task.updated_at);
+            if let Some(completed_at) = &task.completed_at {
+                check.timestamp("completed_at", completed_at);
+            }
+            if let Some(due_date) = &task.due_date {
+                check.date("due_date", due_date);
+            }
+        }
+        SyncRecord::List(list) => {
+            check.id("id", &list.id);
+            check.text("name", &list.name, limits.max_title_length);
+            if let Some(description) = &list.description {
+                check.text("description", description, limits.max_description_length);
+            }
+            check.text("icon", &list.icon, MAX_SHORT_FIELD_LENGTH);
+            if let Some(color) = &list.color {
+                check.text("color", color, MAX_SHORT_FIELD_LENGTH);
+            }
+            check.timestamp("created_at", &list.created_at);
+            check.timestamp("updated_at", &list.updated_at);
+        }
+        SyncRecord::Tag(tag) => {
+            check.id("id", &tag.id);
+            check.text("name", &tag.name, limits.max_title_length);
+            check.text("color", &tag.color, MAX_SHORT_FIELD_LENGTH);
+            check.timestamp("created_at", &tag.created_at);
+            if let Some(updated_at) = &tag.updated_at {
+                check.timestamp("updated_at", updated_at);
+            }
+        }
+        SyncRecord::TaskTag(link) => {
+            check.id("task_id", &link.task_id);
+            check.id("tag_id", &link.tag_id);
+            check.timestamp("created_at", &link.created_at);
+        }
+        SyncRecord::Deleted { id, deleted_at, .. } => {
+            check.id("id", id);
+            check.timestamp("deleted_at", deleted_at);
+        }
+    }
+
+    check.problems
+}
+
+struct Checker<'a> {
+    limits: &'a LimitsConfig,
+    problems: Vec<String>,
+}
+
+impl Checker<'_> {
+    fn id(&mut self, field: &str, value: &str) {
+        if value.trim().is_empty() {
+            self.problems.push(format!("`{field}` must not be empty"));
+        } else if self.limits.require_uuid_ids && uuid::Uuid::try_parse(value).is_err() {
+            self.problems
+                .push(format!("`{field}` is not a UUID: '{}'", preview(value)));
+        } else if value.chars().count() > MAX_ID_LENGTH {
+            self.problems.push(format!(
+                "`{field}` is longer than {MAX_ID_LENGTH} characters"
+            ));
+        }
+    }
+
+    fn text(&mut self, field: &str, value: &str, max: usize) {
+        let length = value.chars().count();
+        if length > max {
+            self.problems.push(format!(
+                "`{field}` is {length} characters long; the limit is {max}"
+            ));
+        }
+    }
+
+    fn timestamp(&mut self, field: &str, value: &str) {
+        if clock::parse(value).is_none() {
+            self.problems.push(format!(
+                "`{field}` is not an RFC 3339 timestamp: '{}'",
+                preview(value)
+            ));
+        }
+    }
+
+    /// A timestamp, or a plain YYYY-MM-DD date
+    fn date(&mut self, field: &str, value: &str) {
+        if clock::parse(value).is_none()
+            && chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d").is_err()
+        {
+            self.problems.push(format!(
+                "`{field}` is not a date or RFC 3339 timestamp: '{}'",
+                preview(value)
+            ));
+        }
+    }
+}
+
+/// Start of a value, for error messages about values that may be huge
+fn preview(value: &str) -> String {
+    const PREVIEW_LENGTH: usize = 40;
+    match value.char_indices().nth(PREVIEW_LENGTH) {
+        Some((end, _)) => format!("{}...", &value[..end]),
+        None => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const ID: &str = "00000000-0000-4000-8000-000000000001";
+    const LIST_ID: &str = "00000000-0000-4000-8000-000000000000";
+
+    fn task(id: &str) -> Value {
+        json!({
+            "type": "task",
+            "id": id,
+            "title": "Buy milk",
+            "priority": "medium",
+            "completed": false,
+            "list_id": LIST_ID,
+            "created_at": "2024-01-01T00:00:00Z",
+            "updated_at": "2024-01-01T00:00:00Z"
+        })
+    }
+
+    fn parse(changes: Value, limits: &LimitsConfig) -> Result<SyncRequest, PayloadError> {
+        let body = json!({ "device_id": "d1", "changes": changes });
+        parse_sync_request(body.to_string().as_bytes(), limits)
+    }
+
+    /// Messages of each invalid record, by index
+    fn record_errors(result: Result<SyncRequest, PayloadError>) -> Vec<(usize, String)> {
+        match result {
+            Err(PayloadError::Invalid { errors, .. }) => {
+                errors.into_iter().map(|e| (e.index, e.message)).collect()
+            }
+            other => panic!("expected invalid records, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn valid_requests_parse() {
+        let mut with_date = task(ID);
+        with_date["due_date"] = json!("2024-02-01");
+        let request = parse(json!([with_date]), &LimitsConfig::default()).unwrap();
+        assert_eq!(request.device_id, "d1");
+        assert_eq!(request.changes.len(), 1);
+    }
+
+    #[test]
+    fn too_many_records_are_refused() {
+        let limits = LimitsConfig {
+            max_records_per_request: 1,
+            ..LimitsConfig::default()
+        };
+        let result = parse(json!([task(ID), task(ID)]), &limits);
+        assert!(matches!(result, Err(PayloadError::TooLarge(_))));
+    }
+
+    #[test]
+    fn oversized_fields_are_reported_per_record() {
+        let limits = LimitsConfig {
+            max_title_length: 5,
+            max_tags_per_task: 1,
+            ..LimitsConfig::default()
+        };
+        let mut tagged = task(ID);
+        tagged["title"] = json!("Milk");
+        tagged["tag_ids"] = json!([ID, ID]);
+
+        let errors = record_errors(parse(json!([task(ID), tagged]), &limits));
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].0, 0);
+        assert!(errors[0].1.contains("`title`"), "{}", errors[0].1);
+        assert_eq!(errors[1].0, 1);
+        assert!(errors[1].1.contains("2 tags"), "{}", errors[1].1);
+    }
+
+    #[test]
+    fn malformed_records_are_reported_with_their_index() {
+        let mut bad_time = task(ID);
+        bad_time["created_at"] = json!("yesterday");
+        let mut bad_priority = task(ID);
+        bad_priority["priority"] = json!("whenever");
+
+        let result = parse(
+            json!([task("not-a-uuid"), bad_time, task(ID), bad_priority]),
+            &LimitsConfig::default(),
+        );
+        let Err(PayloadError::Invalid { errors, .. }) = result else {
+            panic!("batch was accepted");
+        };
+        let indexes: Vec<usize> = errors.iter().map(|e| e.index).collect();
+        assert_eq!(indexes, [0, 1, 3]);
+        assert!(errors[0].message.contains("not a UUID"));
+        assert!(errors[1].message.contains("`created_at`"));
+        // Records that don't parse still get their ID and type reported
+        assert_eq!(errors[2].id, ID);
+        assert_eq!(errors[2].record_type, Some(RecordType::Task));
+    }
+
+    #[test]
+    fn ids_need_not_be_uuids_when_allowed() {
+        let limits = LimitsConfig {
+            require_uuid_ids: false,
+            ..LimitsConfig::default()
+        };
+        assert!(parse(json!([task("t1")]), &limits).is_ok());
+        let errors = record_errors(parse(json!([task(&"x".repeat(300))]), &limits));
+        assert!(errors[0].1.contains("longer than"));
+    }
+
+    #[test]
+    fn malformed_requests_are_refused() {
+        let limits = LimitsConfig::default();
+        for body in [
+            "not json".to_string(),
+            json!({ "device_id": "d1" }).to_string(),
+            json!({ "device_id": "d1", "changes": {} }).to_string(),
+            json!({ "device_id": " ", "changes": [] }).to_string(),
+        ] {
+            let result = parse_sync_request(body.as_bytes(), &limits);
+            assert!(
+                matches!(result, Err(PayloadError::Invalid { ref errors, .. }) if errors.is_empty()),
+                "{body}"
+            );
+        }
+    }
+
+    #[test]
+    fn previews_truncate_long_values() {
+        assert_eq!(preview("short"), "short");
+        assert_eq!(preview(&"é".repeat(50)), format!("{}...", "é".repeat(40)));
+    }
+}